@@ -12,6 +12,8 @@ use std::str::FromStr;
 
 use super::Error;
 
+mod render;
+
 /// Our custom HTML-lite grammar.
 mod grammar {
     use std::result;
@@ -0,0 +1,367 @@
+//! Rendering HTML fragments to formats other than HTML, so that they can be
+//! displayed without an HTML engine.
+
+use super::{Attributes, Fragment, Node};
+
+/// The formatting which we know how to render in at least some formats.
+#[derive(Clone, Debug, PartialEq)]
+enum Style {
+    Bold,
+    Italic,
+    Underline,
+    Color(String),
+    /// An element we don't know how to render, whose children should be
+    /// rendered without any extra formatting.
+    Unstyled,
+}
+
+impl Style {
+    /// Figure out what style an element represents.
+    fn for_element(name: &str, attributes: &Attributes) -> Style {
+        match name.to_lowercase().as_str() {
+            "b" | "strong" => Style::Bold,
+            "i" | "em" => Style::Italic,
+            "u" => Style::Underline,
+            "font" => match attributes.get("color") {
+                Some(color) => Style::Color(color.trim().to_lowercase()),
+                None => Style::Unstyled,
+            },
+            _ => Style::Unstyled,
+        }
+    }
+}
+
+/// An output format which can render a limited set of styles.
+trait Renderer {
+    /// Render text.
+    fn text(&mut self, text: &str);
+    /// Render a line break.
+    fn line_break(&mut self);
+    /// Start an element with the specified style.
+    fn open(&mut self, style: &Style);
+    /// End an element with the specified style.
+    fn close(&mut self, style: &Style);
+}
+
+/// Walk `nodes`, passing them to `renderer`.
+fn render<R: Renderer>(nodes: &[Node], renderer: &mut R) {
+    for node in nodes {
+        match *node {
+            Node::Text { ref text } => renderer.text(text),
+            Node::Element { ref name, ref attributes, ref children } => {
+                match name.to_lowercase().as_str() {
+                    "br" => renderer.line_break(),
+                    "img" => {
+                        if let Some(alt) = attributes.get("alt") {
+                            renderer.text(alt);
+                        }
+                    }
+                    _ => {
+                        let style = Style::for_element(name, attributes);
+                        renderer.open(&style);
+                        render(children, renderer);
+                        renderer.close(&style);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Renders plain text, without any formatting.
+struct PlainText(String);
+
+impl Renderer for PlainText {
+    fn text(&mut self, text: &str) {
+        self.0.push_str(text);
+    }
+
+    fn line_break(&mut self) {
+        self.0.push('\n');
+    }
+
+    fn open(&mut self, _style: &Style) {}
+    fn close(&mut self, _style: &Style) {}
+}
+
+/// Renders the HTML-like markup supported by most SRT players.
+struct Srt(String);
+
+impl Renderer for Srt {
+    fn text(&mut self, text: &str) {
+        // SRT has no way to escape anything, so just hope for the best.
+        self.0.push_str(text);
+    }
+
+    fn line_break(&mut self) {
+        self.0.push('\n');
+    }
+
+    fn open(&mut self, style: &Style) {
+        match *style {
+            Style::Bold => self.0.push_str("<b>"),
+            Style::Italic => self.0.push_str("<i>"),
+            Style::Underline => self.0.push_str("<u>"),
+            Style::Color(ref color) => {
+                self.0.push_str(&format!("<font color=\"{}\">", color))
+            }
+            Style::Unstyled => {}
+        }
+    }
+
+    fn close(&mut self, style: &Style) {
+        match *style {
+            Style::Bold => self.0.push_str("</b>"),
+            Style::Italic => self.0.push_str("</i>"),
+            Style::Underline => self.0.push_str("</u>"),
+            Style::Color(_) => self.0.push_str("</font>"),
+            Style::Unstyled => {}
+        }
+    }
+}
+
+/// The color classes which WebVTT players are required to support.
+const VTT_COLORS: &[&str] = &[
+    "white", "lime", "cyan", "red", "yellow", "magenta", "blue", "black",
+];
+
+/// Renders WebVTT cue text.
+struct Vtt(String);
+
+impl Vtt {
+    /// Can we render `color` using a standard WebVTT class?
+    fn is_supported_color(color: &str) -> bool {
+        VTT_COLORS.contains(&color)
+    }
+}
+
+impl Renderer for Vtt {
+    fn text(&mut self, text: &str) {
+        let escaped = text.replace("&", "&amp;")
+            .replace("<", "&lt;")
+            .replace(">", "&gt;");
+        self.0.push_str(&escaped);
+    }
+
+    fn line_break(&mut self) {
+        self.0.push('\n');
+    }
+
+    fn open(&mut self, style: &Style) {
+        match *style {
+            Style::Bold => self.0.push_str("<b>"),
+            Style::Italic => self.0.push_str("<i>"),
+            Style::Underline => self.0.push_str("<u>"),
+            Style::Color(ref color) if Vtt::is_supported_color(color) => {
+                self.0.push_str(&format!("<c.{}>", color))
+            }
+            Style::Color(_) | Style::Unstyled => {}
+        }
+    }
+
+    fn close(&mut self, style: &Style) {
+        match *style {
+            Style::Bold => self.0.push_str("</b>"),
+            Style::Italic => self.0.push_str("</i>"),
+            Style::Underline => self.0.push_str("</u>"),
+            Style::Color(ref color) if Vtt::is_supported_color(color) => {
+                self.0.push_str("</c>")
+            }
+            Style::Color(_) | Style::Unstyled => {}
+        }
+    }
+}
+
+/// Convert an HTML color to an ANSI foreground color escape sequence.
+fn ansi_color(color: &str) -> Option<String> {
+    let code = match color {
+        "black" => 30,
+        "red" | "maroon" => 31,
+        "green" | "lime" => 32,
+        "yellow" | "olive" => 33,
+        "blue" | "navy" => 34,
+        "magenta" | "fuchsia" | "purple" => 35,
+        "cyan" | "aqua" | "teal" => 36,
+        "white" | "silver" | "gray" | "grey" => 37,
+        _ => {
+            // Try to parse "#rgb" or "#rrggbb" as a 24-bit color.
+            if !color.starts_with('#') || !color[1..].chars().all(|c| c.is_digit(16)) {
+                return None;
+            }
+            let hex = &color[1..];
+            let parse = |s: &str| u8::from_str_radix(s, 16).ok();
+            let rgb = match hex.len() {
+                3 => (
+                    parse(&hex[0..1]).map(|v| v * 17),
+                    parse(&hex[1..2]).map(|v| v * 17),
+                    parse(&hex[2..3]).map(|v| v * 17),
+                ),
+                6 => (parse(&hex[0..2]), parse(&hex[2..4]), parse(&hex[4..6])),
+                _ => return None,
+            };
+            return match rgb {
+                (Some(r), Some(g), Some(b)) => {
+                    Some(format!("\x1b[38;2;{};{};{}m", r, g, b))
+                }
+                _ => None,
+            };
+        }
+    };
+    Some(format!("\x1b[{}m", code))
+}
+
+/// Renders text with ANSI escape sequences, for display on a terminal.
+#[derive(Default)]
+struct Ansi {
+    out: String,
+    bold: usize,
+    italic: usize,
+    underline: usize,
+    /// The colors we're currently inside, so we can restore the outer color
+    /// when an inner one ends.
+    colors: Vec<Option<String>>,
+}
+
+impl Ansi {
+    /// Start a style which may be nested, tracking how deeply nested it is.
+    fn push(out: &mut String, depth: &mut usize, on: &str) {
+        if *depth == 0 {
+            out.push_str(on);
+        }
+        *depth += 1;
+    }
+
+    /// End a style which may be nested.
+    fn pop(out: &mut String, depth: &mut usize, off: &str) {
+        *depth -= 1;
+        if *depth == 0 {
+            out.push_str(off);
+        }
+    }
+}
+
+impl Renderer for Ansi {
+    fn text(&mut self, text: &str) {
+        self.out.push_str(text);
+    }
+
+    fn line_break(&mut self) {
+        self.out.push('\n');
+    }
+
+    fn open(&mut self, style: &Style) {
+        match *style {
+            Style::Bold => Ansi::push(&mut self.out, &mut self.bold, "\x1b[1m"),
+            Style::Italic => Ansi::push(&mut self.out, &mut self.italic, "\x1b[3m"),
+            Style::Underline => {
+                Ansi::push(&mut self.out, &mut self.underline, "\x1b[4m")
+            }
+            Style::Color(ref color) => {
+                let escape = ansi_color(color);
+                if let Some(ref escape) = escape {
+                    self.out.push_str(escape);
+                }
+                self.colors.push(escape);
+            }
+            Style::Unstyled => {}
+        }
+    }
+
+    fn close(&mut self, style: &Style) {
+        match *style {
+            Style::Bold => Ansi::pop(&mut self.out, &mut self.bold, "\x1b[22m"),
+            Style::Italic => Ansi::pop(&mut self.out, &mut self.italic, "\x1b[23m"),
+            Style::Underline => {
+                Ansi::pop(&mut self.out, &mut self.underline, "\x1b[24m")
+            }
+            Style::Color(_) => {
+                if let Some(Some(_)) = self.colors.pop() {
+                    // Restore the closest enclosing color we could render.
+                    let outer = self.colors.iter().rev().filter_map(|c| c.as_ref()).next();
+                    match outer {
+                        Some(escape) => self.out.push_str(escape),
+                        None => self.out.push_str("\x1b[39m"),
+                    }
+                }
+            }
+            Style::Unstyled => {}
+        }
+    }
+}
+
+impl Fragment {
+    /// Render this fragment as plain text, with `<br>` converted to newlines
+    /// and all formatting removed.
+    ///
+    /// ```
+    /// use aligned_media::html::Fragment;
+    ///
+    /// let html: Fragment = "<i>Jean &amp; Luc:</i><br>On y va !".parse().unwrap();
+    /// assert_eq!(html.to_plain_text(), "Jean & Luc:\nOn y va !");
+    /// ```
+    pub fn to_plain_text(&self) -> String {
+        let mut renderer = PlainText(String::new());
+        render(&self.nodes, &mut renderer);
+        renderer.0
+    }
+
+    /// Render this fragment using the `<b>`, `<i>`, `<u>` and `<font
+    /// color>` markup supported by SRT subtitles.
+    pub fn to_srt(&self) -> String {
+        let mut renderer = Srt(String::new());
+        render(&self.nodes, &mut renderer);
+        renderer.0
+    }
+
+    /// Render this fragment as WebVTT cue text, escaping special characters
+    /// and converting standard colors to WebVTT color classes.
+    pub fn to_vtt(&self) -> String {
+        let mut renderer = Vtt(String::new());
+        render(&self.nodes, &mut renderer);
+        renderer.0
+    }
+
+    /// Render this fragment with ANSI escape sequences, for display on a
+    /// terminal.
+    pub fn to_ansi(&self) -> String {
+        let mut renderer = Ansi::default();
+        render(&self.nodes, &mut renderer);
+        renderer.out
+    }
+}
+
+#[test]
+fn render_fragments() {
+    let examples: &[(&str, &str, &str, &str, &str)] = &[
+        ("a &lt; b", "a < b", "a < b", "a &lt; b", "a < b"),
+        (
+            "<i>Jean &amp; Luc:</i><br>On y va !",
+            "Jean & Luc:\nOn y va !",
+            "<i>Jean & Luc:</i>\nOn y va !",
+            "<i>Jean &amp; Luc:</i>\nOn y va !",
+            "\x1b[3mJean & Luc:\x1b[23m\nOn y va !",
+        ),
+        (
+            "<b>1<b>2</b>3</b>",
+            "123",
+            "<b>1<b>2</b>3</b>",
+            "<b>1<b>2</b>3</b>",
+            "\x1b[1m123\x1b[22m",
+        ),
+        (
+            "<font color=\"Yellow\">a<font color=\"#f00\">b</font>c</font>",
+            "abc",
+            "<font color=\"yellow\">a<font color=\"#f00\">b</font>c</font>",
+            "<c.yellow>abc</c>",
+            "\x1b[33ma\x1b[38;2;255;0;0mb\x1b[33mc\x1b[39m",
+        ),
+        ("<span>x</span><img alt=\"[y]\">", "x[y]", "x[y]", "x[y]", "x[y]"),
+    ];
+    for &(html, plain, srt, vtt, ansi) in examples {
+        let fragment: Fragment = html.parse().expect("could not parse HTML");
+        assert_eq!(fragment.to_plain_text(), plain);
+        assert_eq!(fragment.to_srt(), srt);
+        assert_eq!(fragment.to_vtt(), vtt);
+        assert_eq!(fragment.to_ansi(), ansi);
+    }
+}
@@ -0,0 +1,323 @@
+//! Fast time-based lookups of alignments, for use by media players and
+//! similar tools.
+
+use std::cmp::Ordering;
+
+use super::{Alignment, Metadata};
+
+/// An index over the timed alignments in a `Metadata` object, which allows
+/// finding the alignments active at a given time without scanning them all.
+///
+/// Alignments without a time span are ignored. All results are returned as
+/// `(index, alignment)` pairs, where `index` is the position of the alignment
+/// in `Metadata.alignments`, and are sorted by start time.
+///
+/// ```
+/// use aligned_media::Metadata;
+/// use aligned_media::index::AlignmentIndex;
+///
+/// let metadata = Metadata::from_str(r#"{
+///   "baseTrack": { "type": "media", "file": "episode1.mp4" },
+///   "alignments": [
+///     { "timeSpan": [1, 2] },
+///     { "timeSpan": [3, 5] },
+///     { "timeSpan": [4, 6] }
+///   ]
+/// }"#).unwrap();
+/// let index = AlignmentIndex::new(&metadata.alignments);
+/// let at = |t| index.at(t).iter().map(|&(i, _)| i).collect::<Vec<_>>();
+/// assert_eq!(at(1.5), vec![0]);
+/// assert_eq!(at(2.5), Vec::<usize>::new());
+/// assert_eq!(at(4.5), vec![1, 2]);
+/// assert_eq!(index.nearest(2.4).map(|(i, _)| i), Some(0));
+/// ```
+#[derive(Debug)]
+pub struct AlignmentIndex<'a> {
+    /// The alignments we're indexing.
+    alignments: &'a [Alignment],
+    /// Entries for each timed alignment, sorted by `begin`.
+    entries: Vec<Entry>,
+    /// We treat `entries` as an implicit balanced binary tree, where the
+    /// root of `entries[lo..hi]` is at `mid = lo + (hi - lo) / 2`. Then
+    /// `max_end[mid]` is the latest `end` in the subtree rooted at `mid`,
+    /// which allows us to skip whole subtrees which end too early.
+    max_end: Vec<f32>,
+    /// The same entries as `entries`, but sorted by `end`.
+    by_end: Vec<Entry>,
+}
+
+/// A timed alignment.
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    begin: f32,
+    end: f32,
+    index: usize,
+}
+
+/// Fill in `max_end` for the subtree of `entries[lo..hi]`, returning the
+/// latest `end` in that subtree.
+fn build_max_end(entries: &[Entry], max_end: &mut [f32], lo: usize, hi: usize) -> f32 {
+    if lo >= hi {
+        return ::std::f32::NEG_INFINITY;
+    }
+    let mid = lo + (hi - lo) / 2;
+    let latest = entries[mid]
+        .end
+        .max(build_max_end(entries, max_end, lo, mid))
+        .max(build_max_end(entries, max_end, mid + 1, hi));
+    max_end[mid] = latest;
+    latest
+}
+
+/// Return the first index in `items` for which `pred` is false, assuming
+/// that `pred` is true for some prefix of `items` and false afterwards.
+fn partition_point<T, F>(items: &[T], pred: F) -> usize
+where
+    F: Fn(&T) -> bool,
+{
+    let (mut lo, mut hi) = (0, items.len());
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if pred(&items[mid]) {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    lo
+}
+
+impl<'a> AlignmentIndex<'a> {
+    /// Build an index over `alignments`.
+    pub fn new(alignments: &'a [Alignment]) -> AlignmentIndex<'a> {
+        let mut entries: Vec<Entry> = alignments
+            .iter()
+            .enumerate()
+            .filter_map(|(i, a)| {
+                a.time_span().map(|span| Entry {
+                    begin: span.begin(),
+                    end: span.end(),
+                    index: i,
+                })
+            })
+            .collect();
+        // `TimeSpan` never contains NaN, so this comparison always succeeds.
+        entries.sort_by(|a, b| {
+            a.begin
+                .partial_cmp(&b.begin)
+                .unwrap_or(Ordering::Equal)
+                .then(a.index.cmp(&b.index))
+        });
+
+        let mut max_end = vec![::std::f32::NEG_INFINITY; entries.len()];
+        build_max_end(&entries, &mut max_end, 0, entries.len());
+
+        let mut by_end = entries.clone();
+        by_end.sort_by(|a, b| a.end.partial_cmp(&b.end).unwrap_or(Ordering::Equal));
+
+        AlignmentIndex {
+            alignments: alignments,
+            entries: entries,
+            max_end: max_end,
+            by_end: by_end,
+        }
+    }
+
+    /// The number of timed alignments in this index.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Does this index contain no timed alignments?
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Convert an entry to a result.
+    fn result(&self, entry: &Entry) -> (usize, &'a Alignment) {
+        (entry.index, &self.alignments[entry.index])
+    }
+
+    /// All the alignments which overlap the half-open interval `[begin,
+    /// end)`, or which contain `begin` if `begin == end`.
+    pub fn range(&self, begin: f32, end: f32) -> Vec<(usize, &'a Alignment)> {
+        // Ignore alignments which begin too late, and search the rest for
+        // ones which end late enough.
+        let last = if begin == end {
+            partition_point(&self.entries, |e| e.begin <= begin)
+        } else {
+            partition_point(&self.entries, |e| e.begin < end)
+        };
+        let mut results = vec![];
+        self.collect_ending_after(begin, last, 0, self.entries.len(), &mut results);
+        results
+    }
+
+    /// Append the entries in the subtree of `entries[lo..hi]` which end
+    /// after `time`, and which come before `entries[last]`, to `results` in
+    /// order.
+    fn collect_ending_after(
+        &self,
+        time: f32,
+        last: usize,
+        lo: usize,
+        hi: usize,
+        results: &mut Vec<(usize, &'a Alignment)>,
+    ) {
+        if lo >= hi.min(last) {
+            return;
+        }
+        let mid = lo + (hi - lo) / 2;
+        if self.max_end[mid] <= time {
+            return;
+        }
+        self.collect_ending_after(time, last, lo, mid, results);
+        if mid < last {
+            if self.entries[mid].end > time {
+                results.push(self.result(&self.entries[mid]));
+            }
+            self.collect_ending_after(time, last, mid + 1, hi, results);
+        }
+    }
+
+    /// All the alignments active at `time`.
+    pub fn at(&self, time: f32) -> Vec<(usize, &'a Alignment)> {
+        self.range(time, time)
+    }
+
+    /// The last alignment which ends at or before `time`.
+    pub fn previous(&self, time: f32) -> Option<(usize, &'a Alignment)> {
+        match partition_point(&self.by_end, |e| e.end <= time) {
+            0 => None,
+            count => Some(self.result(&self.by_end[count - 1])),
+        }
+    }
+
+    /// The first alignment which begins after `time`.
+    pub fn next(&self, time: f32) -> Option<(usize, &'a Alignment)> {
+        let first = partition_point(&self.entries, |e| e.begin <= time);
+        self.entries.get(first).map(|e| self.result(e))
+    }
+
+    /// The alignment active at `time`, or if there isn't one, the closest
+    /// alignment before or after `time`. If several alignments are active, we
+    /// return the one which started most recently.
+    pub fn nearest(&self, time: f32) -> Option<(usize, &'a Alignment)> {
+        if let Some(&current) = self.at(time).last() {
+            return Some(current);
+        }
+        let prev = self.previous(time);
+        let next = self.next(time);
+        let end = |i: usize| self.alignments[i].time_span().map_or(0.0, |s| s.end());
+        let begin = |i: usize| self.alignments[i].time_span().map_or(0.0, |s| s.begin());
+        match (prev, next) {
+            (Some(p), Some(n)) => {
+                if time - end(p.0) <= begin(n.0) - time {
+                    Some(p)
+                } else {
+                    Some(n)
+                }
+            }
+            (p, None) => p,
+            (None, n) => n,
+        }
+    }
+}
+
+impl Metadata {
+    /// Build an index for looking up alignments by time.
+    pub fn index(&self) -> AlignmentIndex {
+        AlignmentIndex::new(&self.alignments)
+    }
+}
+
+#[cfg(test)]
+fn test_alignments(spans: &[(f32, f32)]) -> Vec<Alignment> {
+    use super::TimeSpan;
+    spans
+        .iter()
+        .map(|&(b, e)| Alignment::new(TimeSpan::new(b, e).unwrap()))
+        .collect()
+}
+
+#[test]
+fn index_queries() {
+    // Deliberately out of order, with an untimed alignment and an alignment
+    // that spans several others.
+    let mut alignments = test_alignments(&[(5.0, 6.0), (1.0, 2.0), (0.5, 10.0), (3.0, 4.0)]);
+    alignments.insert(2, Alignment::default());
+    let index = AlignmentIndex::new(&alignments);
+    assert_eq!(index.len(), 4);
+
+    let ids = |results: Vec<(usize, &Alignment)>| -> Vec<usize> {
+        results.into_iter().map(|(i, _)| i).collect()
+    };
+    assert_eq!(ids(index.at(0.0)), Vec::<usize>::new());
+    assert_eq!(ids(index.at(1.0)), vec![3, 1]);
+    assert_eq!(ids(index.at(2.0)), vec![3]);
+    assert_eq!(ids(index.at(10.0)), Vec::<usize>::new());
+    assert_eq!(ids(index.range(1.5, 3.5)), vec![3, 1, 4]);
+    assert_eq!(ids(index.range(4.0, 5.0)), vec![3]);
+
+    assert_eq!(index.previous(4.5).map(|(i, _)| i), Some(4));
+    assert_eq!(index.previous(0.7), None);
+    assert_eq!(index.next(4.5).map(|(i, _)| i), Some(0));
+    assert_eq!(index.next(5.0), None);
+
+    assert_eq!(index.nearest(4.5).map(|(i, _)| i), Some(3));
+    assert_eq!(index.nearest(0.0).map(|(i, _)| i), Some(3));
+    assert_eq!(index.nearest(12.0).map(|(i, _)| i), Some(3));
+}
+
+#[test]
+fn nearest_without_overlaps() {
+    let alignments = test_alignments(&[(1.0, 2.0), (3.0, 4.0), (7.0, 8.0)]);
+    let index = AlignmentIndex::new(&alignments);
+    let nearest = |t| index.nearest(t).map(|(i, _)| i);
+    assert_eq!(nearest(0.0), Some(0));
+    assert_eq!(nearest(2.4), Some(0));
+    assert_eq!(nearest(2.6), Some(1));
+    assert_eq!(nearest(5.0), Some(1));
+    assert_eq!(nearest(6.0), Some(2));
+    assert_eq!(nearest(100.0), Some(2));
+    assert!(AlignmentIndex::new(&[]).nearest(1.0).is_none());
+}
+
+#[test]
+fn index_queries_with_many_alignments() {
+    // Lots of short alignments, plus a few long ones near the beginning,
+    // which would defeat a simple "latest end so far" index.
+    let mut spans = vec![(0.0, 900.0), (0.5, 1.0), (2.0, 450.0)];
+    for i in 0..1000 {
+        let begin = i as f32;
+        spans.push((begin + 0.25, begin + 0.75 + (i % 3) as f32));
+    }
+    let alignments = test_alignments(&spans);
+    let index = AlignmentIndex::new(&alignments);
+    assert_eq!(index.len(), spans.len());
+
+    // Compare against a linear scan.
+    let active = |t: f32| -> Vec<usize> {
+        let mut found: Vec<(f32, usize)> = spans
+            .iter()
+            .enumerate()
+            .filter(|&(_, &(b, e))| b <= t && t < e)
+            .map(|(i, &(b, _))| (b, i))
+            .collect();
+        found.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        found.into_iter().map(|(_, i)| i).collect()
+    };
+    let previous = |t: f32| -> Option<f32> {
+        spans
+            .iter()
+            .map(|&(_, e)| e)
+            .filter(|&e| e <= t)
+            .fold(None, |best: Option<f32>, e| Some(best.map_or(e, |b| b.max(e))))
+    };
+    for &t in &[0.1, 0.3, 1.0, 10.6, 449.9, 450.0, 500.5, 899.9, 999.3, 2000.0] {
+        let ids: Vec<usize> = index.at(t).into_iter().map(|(i, _)| i).collect();
+        assert_eq!(ids, active(t), "at({})", t);
+        let end = index.previous(t).map(|(_, a)| a.time_span().unwrap().end());
+        assert_eq!(end, previous(t), "previous({})", t);
+    }
+}
@@ -19,9 +19,13 @@ use failure::ResultExt;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde::de::Error as DeError;
 use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::result;
 
 pub mod html;
+pub mod index;
+pub mod validate;
 
 /// Errors which can be returned by this crate.
 #[derive(Debug, Fail)]
@@ -60,6 +64,18 @@ pub enum Error {
         path: String,
     },
 
+    /// We have alignments with time spans, but no base track for them to
+    /// refer to.
+    #[fail(display = "alignments with time spans require a base track")]
+    MissingBaseTrack,
+
+    /// A track didn't have the right mix of `file` and `html` fields.
+    #[fail(display = "{} track must have exactly one of \"file\" or \"html\"", track_type)]
+    InvalidTrackContent {
+        /// The type of the invalid track.
+        track_type: String,
+    },
+
     /// We encountered an invalid span.
     #[fail(display = "beginning of time span {},{} is greater than end", begin, end)]
     InvalidSpan {
@@ -145,6 +161,130 @@ impl Metadata {
     pub fn from_str(data: &str) -> result::Result<Metadata, failure::Error> {
         Self::from_bytes(data.as_bytes())
     }
+
+    /// Create a `MetadataBuilder`, which can be used to construct valid
+    /// `Metadata`.
+    ///
+    /// ```
+    /// # extern crate aligned_media;
+    /// # extern crate isolang;
+    /// use aligned_media::{Alignment, Metadata, TimeSpan, Track};
+    /// use isolang::Language;
+    ///
+    /// # fn main() {
+    /// let mut alignment = Alignment::new(TimeSpan::new(10.0, 15.5).unwrap());
+    /// alignment.add_track(Track::text(Language::Fra, "On y va !"));
+    ///
+    /// let metadata = Metadata::builder()
+    ///     .title("Episode 1")
+    ///     .base_track(Track::media(Some(Language::Fra), "episode1.mp4").unwrap())
+    ///     .alignment(alignment)
+    ///     .build()
+    ///     .unwrap();
+    /// assert_eq!(metadata.alignments.len(), 1);
+    ///
+    /// // Time spans must refer to a base track.
+    /// let mut alignment = Alignment::new(TimeSpan::new(10.0, 15.5).unwrap());
+    /// alignment.add_track(Track::text(Language::Fra, "On y va !"));
+    /// assert!(Metadata::builder().alignment(alignment).build().is_err());
+    /// # }
+    /// ```
+    pub fn builder() -> MetadataBuilder {
+        MetadataBuilder::default()
+    }
+
+    /// Check that this metadata follows the rules which can't be enforced
+    /// by the type system alone.
+    pub fn validate(&self) -> Result<()> {
+        if self.base_track.is_none()
+            && self.alignments.iter().any(|a| a.time_span.is_some())
+        {
+            return Err(Error::MissingBaseTrack);
+        }
+        let alignment_tracks = self.alignments.iter().flat_map(|a| &a.tracks);
+        for track in self.base_track.iter().chain(&self.tracks).chain(alignment_tracks) {
+            track.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// Used to build `Metadata`, checking that it's valid before returning it.
+#[derive(Debug, Default)]
+pub struct MetadataBuilder {
+    metadata: Metadata,
+}
+
+impl MetadataBuilder {
+    /// Set the title of the book, TV series, album, etc.
+    pub fn title<S: Into<String>>(&mut self, title: S) -> &mut Self {
+        self.metadata.title = Some(title.into());
+        self
+    }
+
+    /// Set the episode, track or chapter number.
+    pub fn section_number(&mut self, section_number: u32) -> &mut Self {
+        self.metadata.section_number = Some(section_number);
+        self
+    }
+
+    /// Set the title of this particular section.
+    pub fn section_title<S: Into<String>>(&mut self, section_title: S) -> &mut Self {
+        self.metadata.section_title = Some(section_title.into());
+        self
+    }
+
+    /// Add an author, etc., of this work.
+    pub fn creator<S: Into<String>>(&mut self, creator: S) -> &mut Self {
+        self.metadata.creators.push(creator.into());
+        self
+    }
+
+    /// Set the year in which this work was published.
+    pub fn year(&mut self, year: i32) -> &mut Self {
+        self.metadata.year = Some(year);
+        self
+    }
+
+    /// Set the primary media track, which is the time base for all
+    /// alignments.
+    pub fn base_track(&mut self, track: Track) -> &mut Self {
+        self.metadata.base_track = Some(track);
+        self
+    }
+
+    /// Add another track associated with the entire file.
+    pub fn track(&mut self, track: Track) -> &mut Self {
+        self.metadata.tracks.push(track);
+        self
+    }
+
+    /// Add an alignment.
+    pub fn alignment(&mut self, alignment: Alignment) -> &mut Self {
+        self.metadata.alignments.push(alignment);
+        self
+    }
+
+    /// Add several alignments.
+    pub fn alignments<I>(&mut self, alignments: I) -> &mut Self
+    where
+        I: IntoIterator<Item = Alignment>,
+    {
+        self.metadata.alignments.extend(alignments);
+        self
+    }
+
+    /// Add application-specific extension data.
+    pub fn ext<S: Into<String>>(&mut self, key: S, value: serde_json::Value) -> &mut Self {
+        self.metadata.ext.insert(key.into(), value);
+        self
+    }
+
+    /// Check our metadata and return it.
+    pub fn build(&self) -> Result<Metadata> {
+        self.metadata.validate()?;
+        Ok(self.metadata.clone())
+    }
 }
 
 #[test]
@@ -156,7 +296,9 @@ fn parse_metadata() {
     ];
     for example in examples {
         Metadata::from_str(example)
-            .expect("failed to parse example metadata");
+            .expect("failed to parse example metadata")
+            .validate()
+            .expect("example metadata should be valid");
     }
 }
 
@@ -180,6 +322,7 @@ pub struct Track {
 
     // The actual underlying file on disk, if any. Either this or `html` should
     // be present, but not both.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     file: Option<FilePath>,
 
     // TODO: Do we want a `fileSpan: Span` element, to select only a portion of
@@ -213,6 +356,63 @@ impl Track {
         }
     }
 
+    /// Create a new track with the specified type, pointing to a file on disk.
+    /// Typically used with `TrackType::Media` or `TrackType::Image`.
+    pub fn with_file(track_type: TrackType, file: FilePath) -> Track {
+        Track {
+            file: Some(file),
+            ..Track::with_type(track_type)
+        }
+    }
+
+    /// Create a new audio or video track from the file at `path`, which must
+    /// be a valid `FilePath`.
+    pub fn media<S>(lang: Option<isolang::Language>, path: S) -> Result<Track>
+    where
+        S: Into<String>,
+    {
+        let mut track = Track::with_file(TrackType::Media, FilePath::new(path)?);
+        track.lang = lang;
+        Ok(track)
+    }
+
+    /// Create a new image track from the file at `path`, which must be a
+    /// valid `FilePath`.
+    pub fn image<S: Into<String>>(path: S) -> Result<Track> {
+        Ok(Track::with_file(TrackType::Image, FilePath::new(path)?))
+    }
+
+    /// The file containing this track's data, if any.
+    pub fn file(&self) -> Option<&FilePath> {
+        self.file.as_ref()
+    }
+
+    /// Set or clear the file containing this track's data.
+    pub fn set_file(&mut self, file: Option<FilePath>) {
+        self.file = file;
+    }
+
+    /// Check that this track has exactly one of `file` or `html`, and that
+    /// `html` is only used for HTML tracks.
+    pub fn validate(&self) -> Result<()> {
+        let valid = match self.track_type {
+            TrackType::Html => self.file.is_none() && self.html.is_some(),
+            TrackType::Media | TrackType::Image => {
+                self.file.is_some() && self.html.is_none()
+            }
+            // We don't know what extension tracks contain, so just apply
+            // the general rule.
+            TrackType::Ext(_) => self.file.is_some() != self.html.is_some(),
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(Error::InvalidTrackContent {
+                track_type: self.track_type.to_string(),
+            })
+        }
+    }
+
     /// Create a new HTML track with specified language and content.
     pub fn html<F>(lang: isolang::Language, html: F) -> Track
     where
@@ -258,6 +458,17 @@ pub enum TrackType {
     Ext(String),
 }
 
+impl fmt::Display for TrackType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TrackType::Html => write!(f, "html"),
+            TrackType::Media => write!(f, "media"),
+            TrackType::Image => write!(f, "image"),
+            TrackType::Ext(ref name) => write!(f, "x-{}", name),
+        }
+    }
+}
+
 impl<'de> Deserialize<'de> for TrackType {
     fn deserialize<D: Deserializer<'de>>(d: D) -> result::Result<Self, D::Error> {
         let value: &str = Deserialize::deserialize(d)?;
@@ -286,9 +497,7 @@ impl Serialize for TrackType {
             TrackType::Html => "html".serialize(serializer),
             TrackType::Media => "media".serialize(serializer),
             TrackType::Image => "image".serialize(serializer),
-            TrackType::Ext(ref name) => {
-                format!("x-{}", name).serialize(serializer)
-            }
+            TrackType::Ext(_) => self.to_string().serialize(serializer),
         }
     }
 }
@@ -325,6 +534,74 @@ pub struct Alignment {
     _placeholder: (),
 }
 
+impl Alignment {
+    /// Create a new alignment covering the specified time span, which is
+    /// relative to `Metadata.base_track`.
+    pub fn new(time_span: TimeSpan) -> Alignment {
+        Alignment {
+            time_span: Some(time_span),
+            ..Alignment::default()
+        }
+    }
+
+    /// The time span associated with this alignment, if any.
+    pub fn time_span(&self) -> Option<&TimeSpan> {
+        self.time_span.as_ref()
+    }
+
+    /// Set or clear the time span associated with this alignment.
+    pub fn set_time_span(&mut self, time_span: Option<TimeSpan>) {
+        self.time_span = time_span;
+    }
+
+    /// Add a track to this alignment.
+    pub fn add_track(&mut self, track: Track) -> &mut Self {
+        self.tracks.push(track);
+        self
+    }
+}
+
+#[test]
+fn serialize_constructed_alignment() {
+    let span = TimeSpan::new(10.0, 15.5).unwrap();
+    let mut alignment = Alignment::new(span);
+    alignment.tracks.push(Track::text(isolang::Language::Fra, "On y va !"));
+    alignment.tracks.push(Track::with_file(
+        TrackType::Image,
+        FilePath::new("episode1_12_75.jpg").unwrap(),
+    ));
+    let json = serde_json::to_string(&alignment).unwrap();
+    assert_eq!(
+        json,
+        r#"{"timeSpan":[10.0,15.5],"tracks":[{"type":"html","lang":"fr","html":"On y va !"},{"type":"image","file":"episode1_12_75.jpg"}]}"#,
+    );
+}
+
+#[test]
+fn builder_validates_tracks() {
+    assert!(Track::image("../escape.jpg").is_err());
+
+    let mut track = Track::image("still.jpg").unwrap();
+    track.html = Some(html::Fragment::from_text("Not allowed"));
+    assert!(Metadata::builder().track(track.clone()).build().is_err());
+    track.set_file(None);
+    assert!(Metadata::builder().track(track).build().is_err());
+
+    let mut alignment = Alignment::default();
+    alignment.add_track(Track::text(isolang::Language::Eng, "Hello"));
+    let metadata = Metadata::builder()
+        .alignment(alignment.clone())
+        .build()
+        .expect("untimed alignments don't need a base track");
+
+    alignment.set_time_span(Some(TimeSpan::new(1.0, 2.0).unwrap()));
+    assert!(Metadata::builder().alignment(alignment.clone()).build().is_err());
+    let mut timed = metadata.clone();
+    timed.alignments = vec![alignment];
+    timed.base_track = Some(Track::media(None, "movie.mp4").unwrap());
+    timed.validate().expect("timed alignments with a base track are valid");
+}
+
 /// A span of time, measured in floating-point seconds.
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct TimeSpan {
@@ -398,6 +675,20 @@ impl FilePath {
         }
         Ok(FilePath { path })
     }
+
+    /// Return this path as a string, in Unix notation.
+    pub fn as_str(&self) -> &str {
+        &self.path
+    }
+
+    /// Convert this path to a native path relative to `base`.
+    pub fn to_path(&self, base: &Path) -> PathBuf {
+        let mut path = base.to_owned();
+        for component in self.path.split("/") {
+            path.push(component);
+        }
+        path
+    }
 }
 
 #[test]
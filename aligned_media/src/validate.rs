@@ -0,0 +1,383 @@
+//! Semantic validation of aligned media, going beyond what we can check while
+//! parsing.
+//!
+//! Each problem we find is reported as a `Diagnostic` containing a JSON path
+//! like `$.alignments[3].tracks[1].lang`, so that authors can find and fix
+//! problems in bulk.
+
+use failure::{self, ResultExt};
+use isolang::Language;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use super::{Metadata, Track, TrackType};
+
+/// Overlaps shorter than this are probably just rounding errors.
+const OVERLAP_TOLERANCE: f32 = 0.001;
+
+/// How serious is a problem?
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Severity {
+    /// Something which is legal, but probably a mistake.
+    Warning,
+    /// Something which violates the specification.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A problem found while validating metadata.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    /// How serious is this problem?
+    pub severity: Severity,
+    /// The JSON path of the value with the problem, such as
+    /// `$.alignments[3].timeSpan`.
+    pub path: String,
+    /// A human-readable description of the problem.
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.severity, self.path, self.message)
+    }
+}
+
+/// Extra information used during validation.
+#[derive(Clone, Debug, Default)]
+pub struct ValidationOptions {
+    /// The directory containing `metadata.json`. If specified, we check that
+    /// every file referred to by the metadata exists in this directory.
+    pub bundle_dir: Option<PathBuf>,
+
+    /// The duration of the base track, in seconds. If specified, we check
+    /// that all alignments lie within it.
+    pub base_track_duration: Option<f32>,
+}
+
+/// The shortest code for `lang`, for use in messages.
+fn lang_code(lang: Language) -> &'static str {
+    lang.to_639_1().unwrap_or_else(|| lang.to_639_3())
+}
+
+/// Accumulates diagnostics.
+struct Validator<'a> {
+    options: &'a ValidationOptions,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Validator<'a> {
+    /// Record a problem.
+    fn report<S: Into<String>>(&mut self, severity: Severity, path: String, message: S) {
+        self.diagnostics.push(Diagnostic {
+            severity: severity,
+            path: path,
+            message: message.into(),
+        });
+    }
+
+    /// Check a single track.
+    fn track(&mut self, track: &Track, path: &str) {
+        if let Err(err) = track.validate() {
+            self.report(Severity::Error, path.to_owned(), err.to_string());
+        }
+        if track.track_type == TrackType::Html && track.lang.is_none() {
+            self.report(
+                Severity::Error,
+                format!("{}.lang", path),
+                "html track has no language",
+            );
+        }
+        if let (Some(file), Some(dir)) = (track.file(), self.options.bundle_dir.as_ref()) {
+            if !file.to_path(dir).is_file() {
+                self.report(
+                    Severity::Error,
+                    format!("{}.file", path),
+                    format!("file {:?} does not exist", file.as_str()),
+                );
+            }
+        }
+    }
+
+    /// Check the metadata as a whole.
+    fn metadata(&mut self, metadata: &Metadata) {
+        match metadata.base_track {
+            Some(ref track) => {
+                self.track(track, "$.baseTrack");
+                if track.track_type != TrackType::Media {
+                    self.report(
+                        Severity::Error,
+                        "$.baseTrack.type".to_owned(),
+                        "base track must be a media track",
+                    );
+                }
+            }
+            None => {
+                if metadata.alignments.iter().any(|a| a.time_span().is_some()) {
+                    self.report(
+                        Severity::Error,
+                        "$.baseTrack".to_owned(),
+                        "alignments with time spans require a base track",
+                    );
+                }
+            }
+        }
+        for (i, track) in metadata.tracks.iter().enumerate() {
+            self.track(track, &format!("$.tracks[{}]", i));
+        }
+
+        let timed = metadata
+            .alignments
+            .iter()
+            .filter(|a| a.time_span().is_some())
+            .count();
+        if timed > 0 && timed < metadata.alignments.len() {
+            self.report(
+                Severity::Warning,
+                "$.alignments".to_owned(),
+                format!(
+                    "only {} of {} alignments have time spans",
+                    timed,
+                    metadata.alignments.len()
+                ),
+            );
+        }
+
+        // Every alignment should normally have text in each language used
+        // anywhere in the file.
+        let mut all_langs = vec![];
+        for alignment in &metadata.alignments {
+            for track in &alignment.tracks {
+                if track.track_type != TrackType::Html {
+                    continue;
+                }
+                if let Some(lang) = track.lang {
+                    if !all_langs.contains(&lang) {
+                        all_langs.push(lang);
+                    }
+                }
+            }
+        }
+
+        let mut prev_span = None;
+        for (i, alignment) in metadata.alignments.iter().enumerate() {
+            let path = format!("$.alignments[{}]", i);
+
+            if let Some(span) = alignment.time_span() {
+                let span_path = format!("{}.timeSpan", path);
+                if let Some(prev) = prev_span {
+                    let (prev_i, prev_begin, prev_end): (usize, f32, f32) = prev;
+                    if span.begin() < prev_begin {
+                        self.report(
+                            Severity::Error,
+                            span_path.clone(),
+                            format!("begins before alignments[{}]", prev_i),
+                        );
+                    } else if span.begin() + OVERLAP_TOLERANCE < prev_end {
+                        self.report(
+                            Severity::Error,
+                            span_path.clone(),
+                            format!(
+                                "overlaps alignments[{}] by {:.3}s",
+                                prev_i,
+                                prev_end - span.begin()
+                            ),
+                        );
+                    }
+                }
+                if let Some(duration) = self.options.base_track_duration {
+                    if span.end() > duration + OVERLAP_TOLERANCE {
+                        self.report(
+                            Severity::Error,
+                            span_path,
+                            format!(
+                                "ends at {:.3}s, after the end of the base track at {:.3}s",
+                                span.end(),
+                                duration
+                            ),
+                        );
+                    }
+                }
+                prev_span = Some((i, span.begin(), span.end()));
+            }
+
+            if alignment.tracks.is_empty() {
+                self.report(Severity::Warning, path.clone(), "alignment has no tracks");
+            }
+            let mut langs = vec![];
+            for (j, track) in alignment.tracks.iter().enumerate() {
+                let track_path = format!("{}.tracks[{}]", path, j);
+                self.track(track, &track_path);
+                if track.track_type == TrackType::Html {
+                    if let Some(lang) = track.lang {
+                        if langs.contains(&lang) {
+                            self.report(
+                                Severity::Error,
+                                format!("{}.lang", track_path),
+                                format!("duplicate html track for language {:?}", lang_code(lang)),
+                            );
+                        } else {
+                            langs.push(lang);
+                        }
+                    }
+                }
+            }
+            if !alignment.tracks.is_empty() {
+                for &lang in all_langs.iter().filter(|l| !langs.contains(l)) {
+                    self.report(
+                        Severity::Warning,
+                        format!("{}.tracks", path),
+                        format!("missing html track for language {:?}", lang_code(lang)),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Check `metadata` for problems, returning a list of diagnostics. If the
+/// list contains no `Severity::Error` diagnostics, the metadata is valid.
+///
+/// ```
+/// use aligned_media::Metadata;
+/// use aligned_media::validate::{validate, ValidationOptions};
+///
+/// let metadata = Metadata::from_str(r#"{
+///   "baseTrack": { "type": "media", "file": "episode1.mp4" },
+///   "alignments": [
+///     { "timeSpan": [10, 15.5], "tracks": [{ "type": "html", "html": "On y va !" }] }
+///   ]
+/// }"#).unwrap();
+/// let diagnostics = validate(&metadata, &ValidationOptions::default());
+/// assert_eq!(
+///     diagnostics[0].to_string(),
+///     "error: $.alignments[0].tracks[0].lang: html track has no language",
+/// );
+/// ```
+pub fn validate(metadata: &Metadata, options: &ValidationOptions) -> Vec<Diagnostic> {
+    let mut validator = Validator {
+        options: options,
+        diagnostics: vec![],
+    };
+    validator.metadata(metadata);
+    validator.diagnostics
+}
+
+/// Does `diagnostics` contain any errors?
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+/// Load and validate the bundle at `path`, which may be either a directory
+/// containing `metadata.json`, or the `metadata.json` file itself. Returns an
+/// error if the metadata can't be read or parsed.
+pub fn validate_bundle(
+    path: &Path,
+    base_track_duration: Option<f32>,
+) -> Result<Vec<Diagnostic>, failure::Error> {
+    let metadata_path = if path.is_dir() {
+        path.join("metadata.json")
+    } else {
+        path.to_owned()
+    };
+    let mut data = vec![];
+    File::open(&metadata_path)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .with_context(|_| format!("could not read {}", metadata_path.display()))?;
+    let metadata = Metadata::from_bytes(&data)
+        .with_context(|_| format!("could not parse {}", metadata_path.display()))?;
+    let options = ValidationOptions {
+        bundle_dir: metadata_path.parent().map(|p| p.to_owned()),
+        base_track_duration: base_track_duration,
+    };
+    Ok(validate(&metadata, &options))
+}
+
+#[test]
+fn validate_examples() {
+    let examples = &[
+        "fixtures/examples/book_example.aligned",
+        "fixtures/examples/subtitle_example.aligned",
+    ];
+    for example in examples {
+        let diagnostics = validate_bundle(Path::new(example), None).unwrap();
+        // The referenced media files aren't included in our fixtures.
+        for d in &diagnostics {
+            assert!(d.path.ends_with(".file"), "unexpected: {}", d);
+        }
+    }
+}
+
+#[test]
+fn validate_reports_paths() {
+    let metadata = Metadata::from_str(r#"{
+      "baseTrack": { "type": "media", "lang": "fr", "file": "episode1.mp4" },
+      "alignments": [
+        { "timeSpan": [10, 15.5], "tracks": [
+          { "type": "html", "lang": "fr", "html": "On y va !" },
+          { "type": "html", "lang": "fr", "html": "Allons-y !" }
+        ] },
+        { "timeSpan": [15, 20], "tracks": [
+          { "type": "html", "lang": "fr", "html": "Oui." }
+        ] },
+        { "timeSpan": [12, 13], "tracks": [
+          { "type": "html", "lang": "fr", "html": "Non." }
+        ] }
+      ]
+    }"#).unwrap();
+    let options = ValidationOptions {
+        bundle_dir: None,
+        base_track_duration: Some(18.0),
+    };
+    let found: Vec<(Severity, String)> = validate(&metadata, &options)
+        .into_iter()
+        .map(|d| (d.severity, d.path))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            (Severity::Error, "$.alignments[0].tracks[1].lang".to_owned()),
+            (Severity::Error, "$.alignments[1].timeSpan".to_owned()),
+            (Severity::Error, "$.alignments[1].timeSpan".to_owned()),
+            (Severity::Error, "$.alignments[2].timeSpan".to_owned()),
+        ],
+    );
+}
+
+#[test]
+fn validate_reports_missing_languages() {
+    let metadata = Metadata::from_str(r#"{
+      "alignments": [
+        { "tracks": [
+          { "type": "html", "lang": "fr", "html": "On y va !" },
+          { "type": "html", "lang": "en", "html": "Let's go!" }
+        ] },
+        { "tracks": [
+          { "type": "html", "lang": "fr", "html": "Oui." }
+        ] },
+        { "tracks": [
+          { "type": "html", "lang": "en", "html": "No." }
+        ] }
+      ]
+    }"#).unwrap();
+    let diagnostics = validate(&metadata, &ValidationOptions::default());
+    let found: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+    assert_eq!(
+        found,
+        vec![
+            "warning: $.alignments[1].tracks: missing html track for language \"en\"",
+            "warning: $.alignments[2].tracks: missing html track for language \"fr\"",
+        ],
+    );
+    assert!(!has_errors(&diagnostics));
+}
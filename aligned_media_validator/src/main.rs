@@ -4,14 +4,28 @@ extern crate failure;
 #[macro_use]
 extern crate stdweb;
 
+use aligned_media::validate::{has_errors, validate, ValidationOptions};
 use common_failures::prelude::*;
 
 /// Called from JavaScript to parse and validate metadata. Returns `null` if the
-/// data is valid, or a string containing an error message if the validation
-/// fails.
+/// data is valid, or a string containing one diagnostic per line (including
+/// any warnings) if the validation fails.
 pub fn validate_metadata(json: String) -> Option<String> {
     match aligned_media::Metadata::from_str(&json) {
-        Ok(_) => None,
+        Ok(metadata) => {
+            // We can't see any media files from here, so we don't check them.
+            let options = ValidationOptions::default();
+            let diagnostics = validate(&metadata, &options);
+            // Warnings are worth showing, but they don't make the data
+            // invalid.
+            if !has_errors(&diagnostics) {
+                None
+            } else {
+                let lines: Vec<String> =
+                    diagnostics.iter().map(|d| d.to_string()).collect();
+                Some(lines.join("\n"))
+            }
+        }
         Err(err) => {
             Some(format!("{}", err.display_causes_and_backtrace()))
         }
@@ -1,152 +1,563 @@
 //! Align two subtitle files.
 
-use std::cmp::Ordering;
-use time::MIN_SPACING;
+use common_failures::prelude::*;
+use std::fmt;
+use std::sync::Arc;
+use time::{Period, MIN_SPACING};
 
+use dictionary::{words, Dictionary};
 use srt::{Subtitle, SubtitleFile};
 use merge::merge_subtitles;
 use clean::{clean_subtitle_file, strip_formatting};
 
-use self::MatchQuality::{Nearby, NoMatch, Overlap};
-
-// How well do two subtitles match each other, going solely by the time?
-#[derive(PartialEq, Clone, Copy, Debug)]
-enum MatchQuality {
-    NoMatch,      // More than two seconds away.
-    Nearby(f32),  // 0.0 <= seconds <= 2.0
-    Overlap(f32), // 0.0 < seconds
-}
-
-impl PartialOrd for MatchQuality {
-    fn partial_cmp(&self, other: &MatchQuality) -> Option<Ordering> {
-        match (self, other) {
-            (&Overlap(v1), &Overlap(v2)) => v1.partial_cmp(&v2),
-            (&Nearby(v1), &Nearby(v2)) => v1.partial_cmp(&v2).map(|c| c.reverse()),
-            (&NoMatch, &NoMatch) => Some(Ordering::Equal),
-            (&Overlap(_), _) => Some(Ordering::Greater),
-            (_, &Overlap(_)) => Some(Ordering::Less),
-            (&Nearby(_), _) => Some(Ordering::Greater),
-            (_, &Nearby(_)) => Some(Ordering::Less),
+/// Alignment specification, showing how to match up the specified indices
+/// in two subtitle files.
+type Alignment = Vec<(Vec<usize>, Vec<usize>)>;
+
+/// Why we matched up a group of subtitles.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MatchReason {
+    /// A single subtitle from each file, which overlap in time.
+    Overlap,
+    /// A single subtitle from each file, which are close together in time.
+    Nearby,
+    /// A single subtitle from each file, matched using only their text.
+    Text,
+    /// Several subtitles merged together, with the number of subtitles
+    /// taken from each file.
+    Merged(usize, usize),
+    /// A subtitle from one file which doesn't match anything in the other.
+    Unmatched,
+}
+
+impl fmt::Display for MatchReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MatchReason::Overlap => write!(f, "overlap"),
+            MatchReason::Nearby => write!(f, "nearby"),
+            MatchReason::Text => write!(f, "text"),
+            MatchReason::Merged(n1, n2) => write!(f, "merged {}:{}", n1, n2),
+            MatchReason::Unmatched => write!(f, "unmatched"),
         }
     }
 }
 
-// Calculate the match quality of two subtitles.
-fn match_quality(sub1: &Subtitle, sub2: &Subtitle) -> MatchQuality {
-    match sub1.period.distance(sub2.period) {
-        Some(distance) if distance > 2.0 => NoMatch,
-        Some(distance) => Nearby(distance),
-        None => Overlap(sub1.period.overlap(sub2.period)),
+/// A group of aligned subtitles, merged into at most one subtitle from
+/// each file.
+#[derive(Clone, Debug)]
+pub struct AlignedGroup {
+    /// The merged subtitles from each file.
+    pub pair: (Option<Subtitle>, Option<Subtitle>),
+    /// How confident we are in this match, from 0.0 to 1.0.
+    pub confidence: f32,
+    /// Why we made this match.
+    pub reason: MatchReason,
+}
+
+impl AlignedGroup {
+    /// The time period covered by this group.
+    pub fn period(&self) -> Period {
+        Period::from_union_opt(
+            self.pair.0.as_ref().map(|s| s.period),
+            self.pair.1.as_ref().map(|s| s.period),
+        ).expect("aligned group should not be empty")
     }
 }
 
-// Find the index of the best match for `sub` in `candidates`.
-fn best_match(sub: &Subtitle, candidates: &[Subtitle]) -> Option<usize> {
-    let mut best: Option<(usize, MatchQuality)> = None;
-    for (i, candidate) in candidates.iter().enumerate() {
-        let mq = match_quality(sub, candidate);
-        if mq == NoMatch {
-            continue;
+/// The shapes of the "beads" we may use to align subtitles, specified as
+/// the number of subtitles taken from each file.
+const BEADS: &[(usize, usize)] = &[(1, 1), (1, 2), (2, 1), (1, 0), (0, 1)];
+
+/// The costs used to choose between possible alignments. We look for the
+/// alignment with the lowest total cost, in the style of the Gale–Church
+/// sentence aligner.
+#[derive(Clone, Debug)]
+pub struct CostModel {
+    /// The cost of leaving a subtitle unmatched.
+    pub skip: f32,
+    /// The extra cost of merging two subtitles in one file to match a
+    /// single subtitle in the other.
+    pub merge: f32,
+    /// How much weight to give to the time cost, which is 0.0 when every
+    /// subtitle in a group is completely overlapped by the other side, 1.0
+    /// when some subtitle isn't overlapped at all, and more than 1.0 when
+    /// the two sides are separated by a gap.
+    pub time_weight: f32,
+    /// How much weight to give to differences in text length, measured as
+    /// the absolute value of the log of the length ratio.
+    pub length_weight: f32,
+    /// The expected ratio of text lengths in the second file to text
+    /// lengths in the first.
+    pub length_ratio: f32,
+    /// The offset of the second file relative to the first, in seconds.
+    /// This is subtracted from times in the second file before comparing
+    /// them. If this is `None`, we estimate it using `estimate_offset`.
+    pub offset: Option<f32>,
+    /// A dictionary of words in the first file and their translations in
+    /// the second, used as hints about which subtitles match.
+    pub dictionary: Option<Arc<Dictionary>>,
+    /// How much weight to give to dictionary hints. The cost is the fraction
+    /// of known words in the first file whose translations don't appear in
+    /// the second.
+    pub dictionary_weight: f32,
+}
+
+impl Default for CostModel {
+    fn default() -> CostModel {
+        CostModel {
+            skip: 1.0,
+            merge: 0.5,
+            time_weight: 1.0,
+            length_weight: 0.5,
+            length_ratio: 1.0,
+            offset: None,
+            dictionary: None,
+            dictionary_weight: 1.0,
         }
-        match best {
-            None => {
-                best = Some((i, mq));
-            }
-            Some((_, old_mq)) if mq > old_mq => {
-                best = Some((i, mq));
-            }
-            _ => {}
+    }
+}
+
+impl CostModel {
+    /// A cost model for aligning two files using only their text, for use
+    /// when one of them has missing or broken timing. We estimate the
+    /// ratio of text lengths from the files themselves.
+    pub fn text_only(
+        file1: &SubtitleFile,
+        file2: &SubtitleFile,
+        dictionary: Option<Arc<Dictionary>>,
+    ) -> CostModel {
+        let len1 = text_len(&file1.subtitles) as f32 + 1.0;
+        let len2 = text_len(&file2.subtitles) as f32 + 1.0;
+        CostModel {
+            time_weight: 0.0,
+            length_weight: 1.0,
+            length_ratio: len2 / len1,
+            offset: Some(0.0),
+            dictionary: dictionary,
+            ..CostModel::default()
+        }
+    }
+
+    /// The cost of aligning `subs1` with `subs2`, either of which may be
+    /// empty.
+    fn cost(&self, subs1: &[Features], subs2: &[Features]) -> f32 {
+        if subs1.is_empty() || subs2.is_empty() {
+            return self.skip * (subs1.len() + subs2.len()) as f32;
         }
+
+        let time = if self.time_weight > 0.0 {
+            self.time_weight * time_cost(subs1, subs2)
+        } else {
+            0.0
+        };
+
+        let len1 = subs1.iter().map(|f| f.len).sum::<usize>() as f32 + 1.0;
+        let len2 = subs2.iter().map(|f| f.len).sum::<usize>() as f32 + 1.0;
+        let length = self.length_weight * (len2 / (len1 * self.length_ratio)).ln().abs();
+
+        let hints = match self.dictionary {
+            Some(ref dictionary) => dictionary
+                .match_fraction(&bead_words(subs1), &bead_words(subs2))
+                .map_or(0.0, |fraction| self.dictionary_weight * (1.0 - fraction)),
+            None => 0.0,
+        };
+
+        let merge = if subs1.len() > 1 || subs2.len() > 1 {
+            self.merge
+        } else {
+            0.0
+        };
+        time + length + hints + merge
     }
-    best.map(|(i, _)| i)
 }
 
-// Find the index of the best match each subtitle in `subs` in `candidates`.
-fn best_matches(subs: &[Subtitle], candidates: &[Subtitle]) -> Vec<Option<usize>> {
-    // We could be a lot more efficient about this if we wanted.
-    subs.iter().map(|s| best_match(s, candidates)).collect()
+// Precomputed information about a subtitle, used to calculate costs.
+struct Features {
+    begin: f32,
+    end: f32,
+    len: usize,
+    words: Vec<String>,
+}
+
+// Compute features for each subtitle, shifting times back by `offset`. We
+// only split text into words if we have a dictionary.
+fn features(subs: &[Subtitle], offset: f32, model: &CostModel) -> Vec<Features> {
+    subs.iter()
+        .map(|s| {
+            let text = s.plain_text();
+            Features {
+                begin: s.period.begin() - offset,
+                end: s.period.end() - offset,
+                len: text.chars().count(),
+                words: if model.dictionary.is_some() { words(&text) } else { vec![] },
+            }
+        })
+        .collect()
 }
 
-// Make sure the subtitles are in-order and have sensible begin/end times.
-//fn clean_subs(file: &SubtitleFile) -> Vec<Subtitle> {
-//    // Remove subtitles with bogus begin/end times.
-//    let result = file.subtitles.iter().filter(|s| s.begin < s.end).collect();
-//    // Sort subtitles by begin time.
-//    result.sort_by(|a, b| a.begin.cmp(b.begin));
-//    result
-//}
+// The words of each subtitle in one side of a bead, which never has more
+// than two subtitles. We use an array so that we don't need to allocate
+// anything.
+fn bead_words(subs: &[Features]) -> [&[String]; 2] {
+    debug_assert!(subs.len() <= 2);
+    let words = |i: usize| subs.get(i).map_or(&[][..], |f| &f.words[..]);
+    [words(0), words(1)]
+}
 
-/// Alignment specification, showing how to match up the specified indices
-/// in two subtitle files.
-type Alignment = Vec<(Vec<usize>, Vec<usize>)>;
+// The begin and end times of each subtitle.
+fn times(subs: &[Subtitle]) -> Vec<(f32, f32)> {
+    subs.iter()
+        .map(|s| (s.period.begin(), s.period.end()))
+        .collect()
+}
+
+// The smallest span of time containing all of `subs`.
+fn span(subs: &[Features]) -> (f32, f32) {
+    subs.iter().fold((subs[0].begin, subs[0].end), |(b, e), f| {
+        (b.min(f.begin), e.max(f.end))
+    })
+}
 
-// Returns true if `items[i].is_some()` and the value is found in `group`.
-// Returns false if `i` is out of bounds.
-fn group_contains(group: &[usize], items: &[Option<usize>], i: usize) -> bool {
-    if !(i < items.len()) {
-        return false;
+// How much do two spans of time overlap?
+fn overlap(t1: (f32, f32), t2: (f32, f32)) -> f32 {
+    (t1.1.min(t2.1) - t1.0.max(t2.0)).max(0.0)
+}
+
+// Compare the times of two groups of subtitles, looking at how well the
+// worst-covered subtitle on either side is overlapped by the other side.
+fn time_cost(subs1: &[Features], subs2: &[Features]) -> f32 {
+    let (span1, span2) = (span(subs1), span(subs2));
+    if span1.1 <= span2.0 || span2.1 <= span1.0 {
+        // No overlap, so penalize the gap between the two groups.
+        let gap = (span2.0 - span1.1).max(span1.0 - span2.1);
+        return 1.0 + gap / 2.0;
     }
-    match items[i] {
-        None => false,
-        Some(v) => group.iter().position(|e| *e == v).is_some(),
+    let coverage = |f: &Features, other: (f32, f32)| -> f32 {
+        overlap((f.begin, f.end), other) / (f.end - f.begin).max(MIN_SPACING)
+    };
+    let worst = subs1
+        .iter()
+        .map(|f| coverage(f, span2))
+        .chain(subs2.iter().map(|f| coverage(f, span1)))
+        .fold(1.0, f32::min);
+    1.0 - worst
+}
+
+// The total length of the text in `subs`.
+fn text_len(subs: &[Subtitle]) -> usize {
+    subs.iter().map(|s| s.plain_text().chars().count()).sum()
+}
+
+/// The largest offset between two subtitle files that `estimate_offset`
+/// will look for, in seconds.
+const MAX_OFFSET: f32 = 120.0;
+
+// Merge the periods of `subs` into a sorted list of non-overlapping
+// intervals, showing when subtitles are on screen.
+fn on_screen(subs: &[Subtitle]) -> Vec<(f32, f32)> {
+    let mut times = times(subs);
+    times.sort_by(|a, b| a.partial_cmp(b).expect("subtitle times should not be NaN"));
+    let mut merged: Vec<(f32, f32)> = vec![];
+    for t in times {
+        let extends_last = merged.last().map_or(false, |last| t.0 <= last.1);
+        if extends_last {
+            let last = merged.last_mut().expect("merged should not be empty");
+            last.1 = last.1.max(t.1);
+        } else {
+            merged.push(t);
+        }
     }
+    merged
 }
 
-/// Find a good way to align two subtitle files.
-fn alignment(file1: &SubtitleFile, file2: &SubtitleFile) -> Alignment {
-    let (subs1, subs2) = (&file1.subtitles, &file2.subtitles);
-    // assert!(subs1 && subs2 contain valid subs in ascending order)
-    let matches1 = best_matches(subs1, subs2);
-    let matches2 = best_matches(subs2, subs1);
-    let mut alignment: Alignment = vec![];
-    let mut i1 = 0;
-    let mut i2 = 0;
-    while i1 < subs1.len() && i2 < subs2.len() {
-        debug!(
-            "subs1: {} matches {:?}, subs2: {} matches {:?}",
-            i1,
-            matches1[i1],
-            i2,
-            matches2[i2]
-        );
-        if subs1[i1].period.begin() < subs2[i2].period.begin()
-            && matches1[i1] != Some(i2)
-        {
-            // Subs1 has an item which doesn't match subs2.
-            debug!("unmatched: [{}], []", i1);
-            alignment.push((vec![i1], vec![]));
+// The total time during which subtitles are on screen in both `on1` and
+// `on2`, after shifting `on2` back by `offset`.
+fn total_overlap(on1: &[(f32, f32)], on2: &[(f32, f32)], offset: f32) -> f32 {
+    let (mut i1, mut i2, mut total) = (0, 0, 0.0);
+    while i1 < on1.len() && i2 < on2.len() {
+        let t1 = on1[i1];
+        let t2 = (on2[i2].0 - offset, on2[i2].1 - offset);
+        total += overlap(t1, t2);
+        if t1.1 < t2.1 {
             i1 += 1;
-        } else if subs2[i2].period.begin() < subs1[i1].period.begin()
-            && matches2[i2] != Some(i1)
-        {
-            // Subs2 has an item which doesn't match subs1.
-            debug!("unmatched: [], [{}]", i2);
-            alignment.push((vec![], vec![i2]));
-            i2 += 1;
         } else {
-            // We have some matches, so let's gather them all together.
-            assert!(matches1[i1] == Some(i2) || matches2[i2] == Some(i1));
-            let mut matched1 = vec![i1];
-            i1 += 1;
-            let mut matched2 = vec![i2];
             i2 += 1;
-            while group_contains(&matched1, &matches2, i2)
-                || group_contains(&matched2, &matches1, i1)
-            {
-                if group_contains(&matched1, &matches2, i2) {
-                    // i2 matches something in matched1, so add to matched2.
-                    matched2.push(i2);
-                    i2 += 1;
-                } else if group_contains(&matched2, &matches1, i1) {
-                    // i1 matches something in matched2, so add to matched1.
-                    matched1.push(i1);
-                    i1 += 1;
+        }
+    }
+    total
+}
+
+/// Estimate how many seconds later subtitles in `file2` appear than the
+/// corresponding subtitles in `file1`, by finding the shift which maximizes
+/// the time that both files have subtitles on screen. This is useful when
+/// subtitles come from different releases of the same video.
+///
+/// ```
+/// use substudy::align::estimate_offset;
+/// use substudy::srt::SubtitleFile;
+/// use substudy::time::Retiming;
+/// use std::path::Path;
+///
+/// let es = SubtitleFile::from_path(Path::new("fixtures/sample.es.srt")).unwrap();
+/// let en = SubtitleFile::from_path(Path::new("fixtures/sample.en.srt")).unwrap();
+/// assert_eq!(0.0, estimate_offset(&es, &en));
+///
+/// let late = en.retime(Retiming::offset(4.2)).unwrap();
+/// assert!((estimate_offset(&es, &late) - 4.2).abs() < 0.02);
+/// ```
+pub fn estimate_offset(file1: &SubtitleFile, file2: &SubtitleFile) -> f32 {
+    let (on1, on2) = (on_screen(&file1.subtitles), on_screen(&file2.subtitles));
+
+    // Search outwards from 0.0, so that we prefer smaller offsets when
+    // several offsets work equally well.
+    let search = |center: f32, step: f32, steps: usize| -> f32 {
+        let mut best = (center, total_overlap(&on1, &on2, center));
+        for i in 1..steps + 1 {
+            for &sign in &[1.0, -1.0] {
+                let offset = center + sign * step * i as f32;
+                let score = total_overlap(&on1, &on2, offset);
+                if score > best.1 + MIN_SPACING {
+                    best = (offset, score);
+                }
+            }
+        }
+        best.0
+    };
+    let coarse = search(0.0, 0.1, (MAX_OFFSET / 0.1) as usize);
+    let offset = search(coarse, 0.01, 10);
+    // Round away floating point noise from our search steps.
+    (offset * 1000.0).round() / 1000.0
+}
+
+// Shift the subtitles in `file` back by `offset` seconds, clamping any
+// subtitles that would start before 0.
+fn shift_file(file: &SubtitleFile, offset: f32) -> SubtitleFile {
+    let subtitles = file.subtitles
+        .iter()
+        .map(|s| {
+            let begin = (s.period.begin() - offset).max(0.0);
+            let end = (s.period.end() - offset).max(begin + MIN_SPACING);
+            Subtitle {
+                index: s.index,
+                period: Period::new(begin, end).expect("shifted period should be valid"),
+                lines: s.lines.clone(),
+            }
+        })
+        .collect();
+    SubtitleFile { subtitles: subtitles }
+}
+
+/// The minimum number of positions on either side of the expected diagonal
+/// that `Band` includes.
+const BAND_WIDTH: usize = 50;
+
+// Count the items of `sorted` which are less than `time`.
+fn count_before(sorted: &[f32], time: f32) -> usize {
+    let (mut lo, mut hi) = (0, sorted.len());
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if sorted[mid] < time {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    lo
+}
+
+/// The part of our dynamic programming table which we actually fill in.
+/// For each count `i1` of subtitles from the first file, we only look at
+/// counts `lo[i1]..hi[i1] + 1` from the second file, near where we expect
+/// the matching subtitles to be. Subtitles are rarely dropped or merged in
+/// long runs, so the best alignment almost always stays near this
+/// diagonal, and this keeps the table small for long files.
+struct Band {
+    lo: Vec<usize>,
+    hi: Vec<usize>,
+    /// The position of the first cell of each row in our table.
+    start: Vec<usize>,
+}
+
+impl Band {
+    /// Choose a band for aligning `subs1` with `subs2`. If `use_times` is
+    /// true, we expect subtitles which start at the same time to match
+    /// (after correcting for any offset). Otherwise, we assume that both
+    /// files are spread out in the same way.
+    fn new(subs1: &[Features], subs2: &[Features], use_times: bool) -> Band {
+        let (n1, n2) = (subs1.len(), subs2.len());
+        let width = BAND_WIDTH.max(n1.max(n2) / 10);
+        let mut begins2: Vec<f32> = subs2.iter().map(|f| f.begin).collect();
+        begins2.sort_by(|a, b| a.partial_cmp(b).expect("subtitle times should not be NaN"));
+
+        let (mut lo, mut hi) = (vec![], vec![]);
+        let mut center = 0;
+        for i1 in 0..n1 + 1 {
+            let expected = if i1 == n1 {
+                n2
+            } else if use_times {
+                count_before(&begins2, subs1[i1].begin)
+            } else {
+                i1 * n2 / n1
+            };
+            center = center.max(expected);
+            lo.push(if i1 == 0 { 0 } else { center.saturating_sub(width) });
+            hi.push(if i1 == n1 { n2 } else { (center + width).min(n2) });
+        }
+        // Make sure each row overlaps the next, so that there's always a
+        // path through the table.
+        for i1 in (0..n1).rev() {
+            hi[i1] = hi[i1].max(lo[i1 + 1]);
+        }
+
+        let mut start = Vec::with_capacity(n1 + 1);
+        let mut len = 0;
+        for i1 in 0..n1 + 1 {
+            start.push(len);
+            len += hi[i1] - lo[i1] + 1;
+        }
+        Band {
+            lo: lo,
+            hi: hi,
+            start: start,
+        }
+    }
+
+    /// The total number of cells in our band.
+    fn len(&self) -> usize {
+        let last = self.start.len() - 1;
+        self.start[last] + self.hi[last] - self.lo[last] + 1
+    }
+
+    /// The position of the cell for `(i1, i2)` in our table, or `None` if
+    /// it lies outside our band.
+    fn cell(&self, i1: usize, i2: usize) -> Option<usize> {
+        if self.lo[i1] <= i2 && i2 <= self.hi[i1] {
+            Some(self.start[i1] + i2 - self.lo[i1])
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn band_connects_corners() {
+    let features = |begins: &[f32]| -> Vec<Features> {
+        begins
+            .iter()
+            .map(|&b| Features {
+                begin: b,
+                end: b + 1.0,
+                len: 10,
+                words: vec![],
+            })
+            .collect()
+    };
+    // A long run of subtitles in the second file with nothing matching in
+    // the first.
+    let subs1 = features(&(0..300).map(|i| i as f32).collect::<Vec<_>>());
+    let mut begins2: Vec<f32> = (0..150).map(|i| i as f32).collect();
+    begins2.extend((0..200).map(|i| 150.0 + i as f32 / 1000.0));
+    begins2.extend((150..300).map(|i| i as f32 + 1.0));
+    let subs2 = features(&begins2);
+    for &use_times in &[true, false] {
+        let band = Band::new(&subs1, &subs2, use_times);
+        assert_eq!(band.cell(0, 0), Some(0));
+        assert_eq!(band.cell(300, 500), Some(band.len() - 1));
+        assert!(band.cell(0, 400).is_none());
+        for i1 in 0..300 {
+            assert!(band.lo[i1] <= band.lo[i1 + 1]);
+            assert!(band.lo[i1 + 1] <= band.hi[i1]);
+        }
+    }
+}
+
+/// Find a good way to align two subtitle files, using the default cost
+/// model.
+fn alignment(file1: &SubtitleFile, file2: &SubtitleFile) -> Alignment {
+    alignment_with(file1, file2, &CostModel::default())
+}
+
+/// Find the lowest-cost way to align two subtitle files.
+fn alignment_with(
+    file1: &SubtitleFile,
+    file2: &SubtitleFile,
+    model: &CostModel,
+) -> Alignment {
+    scored_alignment_with(file1, file2, model)
+        .into_iter()
+        .map(|(indices1, indices2, _, _)| (indices1, indices2))
+        .collect()
+}
+
+/// Find the lowest-cost way to align two subtitle files using dynamic
+/// programming, returning the cost of each group and why it matched.
+fn scored_alignment_with(
+    file1: &SubtitleFile,
+    file2: &SubtitleFile,
+    model: &CostModel,
+) -> Vec<(Vec<usize>, Vec<usize>, f32, MatchReason)> {
+    let model = CostModel {
+        offset: Some(model.offset.unwrap_or_else(|| estimate_offset(file1, file2))),
+        ..model.clone()
+    };
+    let subs1 = features(&file1.subtitles, 0.0, &model);
+    let subs2 = features(&file2.subtitles, model.offset.unwrap_or(0.0), &model);
+    let (n1, n2) = (subs1.len(), subs2.len());
+    let band = Band::new(&subs1, &subs2, model.time_weight > 0.0);
+    let cell = |i1: usize, i2: usize| -> usize {
+        band.cell(i1, i2).expect("cell should be inside alignment band")
+    };
+
+    // `costs[cell(i1, i2)]` is the lowest cost of aligning the first `i1`
+    // subtitles of `subs1` with the first `i2` of `subs2`, and `beads` holds
+    // the bead used to get there.
+    let mut costs = vec![::std::f32::INFINITY; band.len()];
+    let mut beads: Vec<Option<(usize, usize)>> = vec![None; band.len()];
+    costs[0] = 0.0;
+    for i1 in 0..n1 + 1 {
+        for i2 in band.lo[i1]..band.hi[i1] + 1 {
+            let here = cell(i1, i2);
+            for &(d1, d2) in BEADS {
+                if d1 > i1 || d2 > i2 {
+                    continue;
+                }
+                let prev = match band.cell(i1 - d1, i2 - d2) {
+                    Some(prev) if costs[prev].is_finite() => costs[prev],
+                    _ => continue,
+                };
+                let cost = prev + model.cost(&subs1[i1 - d1..i1], &subs2[i2 - d2..i2]);
+                if cost < costs[here] {
+                    costs[here] = cost;
+                    beads[here] = Some((d1, d2));
                 }
-                debug!("grouping: {:?}, {:?}", matched1, matched2);
             }
-            alignment.push((matched1, matched2));
         }
     }
+
+    // Trace back through our table to find the best alignment.
+    let mut alignment = vec![];
+    let (mut i1, mut i2) = (n1, n2);
+    while i1 > 0 || i2 > 0 {
+        let (d1, d2) = beads[cell(i1, i2)].expect("alignment table should be complete");
+        let cost = costs[cell(i1, i2)] - costs[cell(i1 - d1, i2 - d2)];
+        let reason = if d1 == 0 || d2 == 0 {
+            MatchReason::Unmatched
+        } else if d1 > 1 || d2 > 1 {
+            MatchReason::Merged(d1, d2)
+        } else if model.time_weight == 0.0 {
+            MatchReason::Text
+        } else if time_cost(&subs1[i1 - 1..i1], &subs2[i2 - 1..i2]) < 1.0 {
+            MatchReason::Overlap
+        } else {
+            MatchReason::Nearby
+        };
+        let group = ((i1 - d1..i1).collect(), (i2 - d2..i2).collect(), cost, reason);
+        debug!("aligned: {:?}", group);
+        alignment.push(group);
+        i1 -= d1;
+        i2 -= d2;
+    }
+    alignment.reverse();
     alignment
 }
 
@@ -171,11 +582,74 @@ fn test_alignment() {
     assert_eq!(expected, alignment(&srt_es, &srt_en));
 }
 
+#[test]
+fn test_alignment_with_cost_model() {
+    use std::path::Path;
+    use time::Retiming;
+
+    let path_es = Path::new("fixtures/sample.es.srt");
+    let srt_es = SubtitleFile::from_path(&path_es).unwrap();
+    let path_en = Path::new("fixtures/sample.en.srt");
+    let srt_en = SubtitleFile::from_path(&path_en).unwrap();
+
+    // Swapping the files should produce 2:1 beads instead of 1:2 beads.
+    let swapped: Alignment = alignment(&srt_es, &srt_en)
+        .into_iter()
+        .map(|(g1, g2)| (g2, g1))
+        .collect();
+    assert_eq!(swapped, alignment(&srt_en, &srt_es));
+
+    // A known offset should let us align shifted subtitles.
+    let shifted = srt_en.retime(Retiming::offset(10.0)).unwrap();
+    let model = CostModel {
+        offset: Some(10.0),
+        ..CostModel::default()
+    };
+    assert_eq!(
+        alignment(&srt_es, &srt_en),
+        alignment_with(&srt_es, &shifted, &model)
+    );
+}
+
 /// Align two subtitle files, merging subtitles as necessary.
 pub fn align_files(
     file1: &SubtitleFile,
     file2: &SubtitleFile,
 ) -> Vec<(Option<Subtitle>, Option<Subtitle>)> {
+    align_files_with(file1, file2, &CostModel::default())
+}
+
+/// Align two subtitle files using the specified cost model, merging
+/// subtitles as necessary.
+pub fn align_files_with(
+    file1: &SubtitleFile,
+    file2: &SubtitleFile,
+    model: &CostModel,
+) -> Vec<(Option<Subtitle>, Option<Subtitle>)> {
+    align_files_scored(file1, file2, model)
+        .into_iter()
+        .map(|group| group.pair)
+        .collect()
+}
+
+/// Align two subtitle files using the specified cost model, and report how
+/// confident we are in each aligned group. Confidence is calculated from
+/// the cost of each group as `exp(-cost)`.
+pub fn align_files_scored(
+    file1: &SubtitleFile,
+    file2: &SubtitleFile,
+    model: &CostModel,
+) -> Vec<AlignedGroup> {
+    // Move `file2` into the same timebase as `file1` before aligning, so that
+    // merged subtitles have sensible times.
+    let offset = model.offset.unwrap_or_else(|| estimate_offset(file1, file2));
+    info!("offset between subtitle files: {:.3}s", offset);
+    let file2 = &shift_file(file2, offset);
+    let model = CostModel {
+        offset: Some(0.0),
+        ..model.clone()
+    };
+
     fn merge(file: &SubtitleFile, indices: &[usize]) -> Option<Subtitle> {
         let mut subs = vec![];
         for &i in indices.iter() {
@@ -184,22 +658,54 @@ pub fn align_files(
         merge_subtitles(&subs)
     }
 
-    alignment(file1, file2)
-        .iter()
-        .map(|&(ref indices1, ref indices2)| {
-            (merge(file1, &indices1), merge(file2, &indices2))
+    scored_alignment_with(file1, file2, &model)
+        .into_iter()
+        .map(|(indices1, indices2, cost, reason)| AlignedGroup {
+            pair: (merge(file1, &indices1), merge(file2, &indices2)),
+            confidence: (-cost).exp(),
+            reason: reason,
         })
         .collect()
 }
 
+/// Find runs of consecutive groups with a confidence below `threshold`,
+/// which should probably be checked by hand.
+pub fn low_confidence_regions(groups: &[AlignedGroup], threshold: f32) -> Vec<&[AlignedGroup]> {
+    let mut regions = vec![];
+    let mut start = None;
+    for (i, group) in groups.iter().enumerate() {
+        match (start, group.confidence < threshold) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                regions.push(&groups[s..i]);
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        regions.push(&groups[s..]);
+    }
+    regions
+}
+
 /// If we have two files, align them.  If one is missing, just return its
 /// subtitles by themselves.
 pub fn align_available_files(
     file1: &SubtitleFile,
     file2_opt: Option<&SubtitleFile>,
+) -> Vec<(Option<Subtitle>, Option<Subtitle>)> {
+    align_available_files_with(file1, file2_opt, &CostModel::default())
+}
+
+/// Like `align_available_files`, but using the specified cost model.
+pub fn align_available_files_with(
+    file1: &SubtitleFile,
+    file2_opt: Option<&SubtitleFile>,
+    model: &CostModel,
 ) -> Vec<(Option<Subtitle>, Option<Subtitle>)> {
     match file2_opt {
-        Some(ref file2) => align_files(file1, file2),
+        Some(ref file2) => align_files_with(file1, file2, model),
         None => file1
             .subtitles
             .iter()
@@ -209,6 +715,131 @@ pub fn align_available_files(
     }
 }
 
+/// Align `untimed` with `timed` using `model`, and return a copy of
+/// `untimed` using the timing of the matching subtitles in `timed`. This is
+/// normally used with `CostModel::text_only`, when `untimed` has missing or
+/// broken timestamps. When several subtitles in `untimed` match a single
+/// timed period, we divide up the period according to text length.
+/// Unmatched subtitles in `untimed` are attached to the group before them.
+pub fn copy_timing(
+    timed: &SubtitleFile,
+    untimed: &SubtitleFile,
+    model: &CostModel,
+) -> Result<SubtitleFile> {
+    // Attach unmatched untimed subtitles to a neighbouring group.
+    let mut groups: Vec<(Vec<usize>, Vec<usize>)> = vec![];
+    let mut pending: Vec<usize> = vec![];
+    for (indices1, indices2) in alignment_with(timed, untimed, model) {
+        if indices1.is_empty() {
+            match groups.last_mut() {
+                Some(group) => group.1.extend(indices2),
+                None => pending.extend(indices2),
+            }
+        } else {
+            pending.extend(indices2);
+            groups.push((indices1, pending));
+            pending = vec![];
+        }
+    }
+    if !pending.is_empty() {
+        return Err(format_err!("cannot copy timing from an empty subtitle file"));
+    }
+
+    let mut subtitles = vec![];
+    for (indices1, indices2) in groups {
+        let period = indices1
+            .iter()
+            .map(|&i| timed.subtitles[i].period)
+            .fold(timed.subtitles[indices1[0]].period, |p1, p2| p1.union(p2));
+        let subs: Vec<&Subtitle> = indices2.iter().map(|&i| &untimed.subtitles[i]).collect();
+        let lens: Vec<f32> = subs
+            .iter()
+            .map(|s| s.plain_text().chars().count() as f32 + 1.0)
+            .collect();
+        let total: f32 = lens.iter().sum();
+        let mut begin = period.begin();
+        for (sub, len) in subs.into_iter().zip(lens) {
+            let end = (begin + period.duration() * len / total).min(period.end());
+            subtitles.push(Subtitle {
+                index: sub.index,
+                period: Period::new(begin, end.max(begin + MIN_SPACING))?,
+                lines: sub.lines.clone(),
+            });
+            begin = end;
+        }
+    }
+    Ok(SubtitleFile { subtitles: subtitles })
+}
+
+#[test]
+fn test_align_files_scored() {
+    use std::path::Path;
+
+    let path_es = Path::new("fixtures/sample.es.srt");
+    let srt_es = SubtitleFile::from_path(&path_es).unwrap();
+    let path_en = Path::new("fixtures/sample.en.srt");
+    let srt_en = SubtitleFile::from_path(&path_en).unwrap();
+
+    let groups = align_files_scored(&srt_es, &srt_en, &CostModel::default());
+    let reasons: Vec<MatchReason> = groups.iter().map(|g| g.reason).collect();
+    assert_eq!(
+        vec![
+            MatchReason::Merged(1, 2),
+            MatchReason::Unmatched,
+            MatchReason::Overlap,
+            MatchReason::Overlap,
+            MatchReason::Merged(1, 2),
+            MatchReason::Overlap,
+        ],
+        reasons
+    );
+    assert!(groups[2].confidence > 0.9);
+    assert!(groups[1].confidence < 0.5);
+
+    let regions = low_confidence_regions(&groups, 0.5);
+    assert_eq!(MatchReason::Unmatched, regions[0][0].reason);
+    let flagged: usize = regions.iter().map(|r| r.len()).sum();
+    assert!(flagged < groups.len());
+    assert!(low_confidence_regions(&groups, 0.0).is_empty());
+}
+
+#[test]
+fn test_copy_timing() {
+    use std::path::Path;
+
+    let path_es = Path::new("fixtures/sample.es.srt");
+    let srt_es = SubtitleFile::from_path(&path_es).unwrap();
+    let path_en = Path::new("fixtures/sample.en.srt");
+    let srt_en = SubtitleFile::from_path(&path_en).unwrap();
+
+    // Throw away the timing information in our English subtitles.
+    let untimed = SubtitleFile {
+        subtitles: srt_en
+            .subtitles
+            .iter()
+            .enumerate()
+            .map(|(i, s)| Subtitle {
+                index: s.index,
+                period: Period::new(i as f32, i as f32 + 0.5).unwrap(),
+                lines: s.lines.clone(),
+            })
+            .collect(),
+    };
+
+    let dictionary = Dictionary::from_str("sabía\tknew\nseñal\tsignaled\nnosotros\tus\n")
+        .unwrap();
+    let model = CostModel::text_only(&srt_es, &untimed, Some(Arc::new(dictionary)));
+    let retimed = copy_timing(&srt_es, &untimed, &model).unwrap();
+    assert_eq!(untimed.subtitles.len(), retimed.subtitles.len());
+    let find = |index: usize| retimed.find(index).unwrap().period;
+    assert_eq!(srt_es.subtitles[0].period.begin(), find(18).begin());
+    assert_eq!(srt_es.subtitles[1].period, find(21));
+    assert_eq!(srt_es.subtitles[4].period.end(), find(25).end());
+    for pair in retimed.subtitles.windows(2) {
+        assert!(pair[0].period.end() <= pair[1].period.begin());
+    }
+}
+
 // Clone a subtitle and wrap its lines with formatting.
 fn clone_as(sub: &Subtitle, before: &str, after: &str) -> Subtitle {
     let lines = sub.lines
@@ -234,7 +865,17 @@ static STYLE2E: &'static str = "</i>";
 
 /// Combine two subtitle files into an aligned file.
 pub fn combine_files(file1: &SubtitleFile, file2: &SubtitleFile) -> SubtitleFile {
-    let mut subs: Vec<Subtitle> = align_files(file1, file2)
+    combine_files_with(file1, file2, &CostModel::default())
+}
+
+/// Combine two subtitle files into an aligned file, using the specified
+/// cost model to align them.
+pub fn combine_files_with(
+    file1: &SubtitleFile,
+    file2: &SubtitleFile,
+    model: &CostModel,
+) -> SubtitleFile {
+    let mut subs: Vec<Subtitle> = align_files_with(file1, file2, model)
         .iter()
         .map(|pair| match pair {
             &(None, None) => panic!("Shouldn't have empty alignment pair!"),
@@ -0,0 +1,497 @@
+//! Advanced SubStation Alpha (`*.ass`) and SubStation Alpha (`*.ssa`)
+//! subtitle support.
+//!
+//! We read the `[Script Info]`, `[V4+ Styles]` (or `[V4 Styles]`) and
+//! `[Events]` sections, and convert each `Dialogue:` line into a regular
+//! `Subtitle`, keeping the style, layer and actor alongside it. Override
+//! tags like `{\i1}` are left in the text, where `strip_formatting` can
+//! find them.
+
+use common_failures::prelude::*;
+use regex::Regex;
+use std::str::FromStr;
+
+use align::{align_files_with, CostModel};
+use srt::{Subtitle, SubtitleFile};
+use time::Period;
+
+/// The field order used for `[Events]` when no `Format:` line is present.
+const DEFAULT_EVENT_FORMAT: &[&str] = &[
+    "Layer", "Start", "End", "Style", "Name", "MarginL", "MarginR", "MarginV",
+    "Effect", "Text",
+];
+
+/// The field order we use when writing `[V4+ Styles]`.
+const STYLE_FORMAT: &[&str] = &[
+    "Name", "Fontname", "Fontsize", "PrimaryColour", "SecondaryColour",
+    "OutlineColour", "BackColour", "Bold", "Italic", "Underline", "StrikeOut",
+    "ScaleX", "ScaleY", "Spacing", "Angle", "BorderStyle", "Outline", "Shadow",
+    "Alignment", "MarginL", "MarginR", "MarginV", "Encoding",
+];
+
+// Numpad-style alignment values used by `[V4+ Styles]`.
+const ALIGN_BOTTOM: u8 = 2;
+const ALIGN_TOP: u8 = 8;
+
+/// Parse an ASS timestamp of the form `H:MM:SS.cc`.
+fn parse_time(time: &str) -> Result<f32> {
+    let mkerr = || format_err!("invalid ASS timestamp {:?}", time);
+    let parts: Vec<&str> = time.trim().split(':').collect();
+    if parts.len() != 3 {
+        return Err(mkerr());
+    }
+    let h = u32::from_str(parts[0]).with_context(|_| mkerr())?;
+    let m = u32::from_str(parts[1]).with_context(|_| mkerr())?;
+    let s = f32::from_str(parts[2]).with_context(|_| mkerr())?;
+    Ok((h as f32) * 3600.0 + (m as f32) * 60.0 + s)
+}
+
+/// Format seconds using the ASS time format, which only has centisecond
+/// precision.
+pub fn format_time(time: f32) -> String {
+    let cs = (time * 100.0).round() as u64;
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        cs / 360_000,
+        (cs / 6000) % 60,
+        (cs / 100) % 60,
+        cs % 100
+    )
+}
+
+/// Convert ASS event text into subtitle lines.
+fn text_to_lines(text: &str) -> Vec<String> {
+    text.replace("\\n", "\\N")
+        .replace("\\h", "\u{a0}")
+        .split("\\N")
+        .map(|l| l.trim().to_owned())
+        .filter(|l| !l.is_empty())
+        .collect()
+}
+
+/// Convert subtitle lines into ASS event text, translating the basic SRT
+/// formatting tags into override tags.
+fn lines_to_text(lines: &[String]) -> String {
+    lazy_static! {
+        static ref OTHER_TAG: Regex = Regex::new(r"<[a-z/][^>]*>").unwrap();
+    }
+    let text = lines.join("\\N")
+        .replace("<i>", "{\\i1}")
+        .replace("</i>", "{\\i0}")
+        .replace("<b>", "{\\b1}")
+        .replace("</b>", "{\\b0}")
+        .replace("<u>", "{\\u1}")
+        .replace("</u>", "{\\u0}");
+    OTHER_TAG.replace_all(&text, "").into_owned()
+}
+
+/// A named style from the `[V4+ Styles]` section.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Style {
+    /// The fields of this style, as `(name, value)` pairs, in the order
+    /// they appear in the file.
+    pub fields: Vec<(String, String)>,
+}
+
+impl Style {
+    /// The name of this style.
+    pub fn name(&self) -> &str {
+        self.get("Name").unwrap_or("Default")
+    }
+
+    /// Look up a field by name.
+    pub fn get(&self, field: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|&&(ref k, _)| k == field)
+            .map(|&(_, ref v)| &v[..])
+    }
+
+    /// Create a simple style with the specified name, font size, text color
+    /// (in ASS `&HAABBGGRR` notation) and screen alignment.
+    fn simple(name: &str, size: u32, color: &str, alignment: u8) -> Style {
+        let values = vec![
+            name.to_owned(),
+            "Arial".to_owned(),
+            size.to_string(),
+            color.to_owned(),
+            "&H000000FF".to_owned(),
+            "&H00000000".to_owned(),
+            "&H80000000".to_owned(),
+            "0".to_owned(),
+            "0".to_owned(),
+            "0".to_owned(),
+            "0".to_owned(),
+            "100".to_owned(),
+            "100".to_owned(),
+            "0".to_owned(),
+            "0".to_owned(),
+            "1".to_owned(),
+            "2".to_owned(),
+            "1".to_owned(),
+            alignment.to_string(),
+            "10".to_owned(),
+            "10".to_owned(),
+            "10".to_owned(),
+            "1".to_owned(),
+        ];
+        Style {
+            fields: STYLE_FORMAT
+                .iter()
+                .map(|s| s.to_string())
+                .zip(values)
+                .collect(),
+        }
+    }
+}
+
+/// A single `Dialogue:` event.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    /// The layer on which this event is drawn. Higher layers are drawn on
+    /// top of lower ones.
+    pub layer: i32,
+    /// The name of the style used by this event.
+    pub style: String,
+    /// The name of the character speaking, if specified.
+    pub actor: String,
+    /// The subtitle itself.
+    pub subtitle: Subtitle,
+}
+
+/// The contents of an `*.ass` or `*.ssa` file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssFile {
+    /// `(key, value)` pairs from the `[Script Info]` section.
+    pub script_info: Vec<(String, String)>,
+    /// Styles from the `[V4+ Styles]` section.
+    pub styles: Vec<Style>,
+    /// Dialogue events, in the order they appear in the file.
+    pub events: Vec<Event>,
+}
+
+impl AssFile {
+    /// Parse the text of an `*.ass` or `*.ssa` file.
+    pub fn from_str(data: &str) -> Result<AssFile> {
+        let data = data.trim_left_matches("\u{FEFF}");
+        let mut file = AssFile {
+            script_info: vec![],
+            styles: vec![],
+            events: vec![],
+        };
+
+        let mut section = String::new();
+        let mut style_format: Vec<String> = vec![];
+        let mut event_format: Vec<String> = DEFAULT_EVENT_FORMAT
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        for (line_no, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') || line.starts_with("!:") {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..line.len() - 1].to_lowercase();
+                continue;
+            }
+            let colon = match line.find(':') {
+                Some(colon) => colon,
+                None => {
+                    trace!("ignoring ASS line without key: {:?}", line);
+                    continue;
+                }
+            };
+            let (key, value) = (line[..colon].trim(), line[colon + 1..].trim());
+            let mkerr = || format_err!("error parsing line {} of ASS file", line_no + 1);
+            match (&section[..], key) {
+                ("script info", _) => {
+                    file.script_info.push((key.to_owned(), value.to_owned()));
+                }
+                ("v4+ styles", "Format") | ("v4 styles", "Format") => {
+                    style_format = value.split(',').map(|f| f.trim().to_owned()).collect();
+                }
+                ("v4+ styles", "Style") | ("v4 styles", "Style") => {
+                    let values = value.splitn(style_format.len().max(1), ',');
+                    let fields = style_format
+                        .iter()
+                        .cloned()
+                        .zip(values.map(|v| v.trim().to_owned()))
+                        .collect();
+                    file.styles.push(Style { fields: fields });
+                }
+                ("events", "Format") => {
+                    event_format = value.split(',').map(|f| f.trim().to_owned()).collect();
+                }
+                ("events", "Dialogue") => {
+                    let event = parse_dialogue(&event_format, value)
+                        .with_context(|_| mkerr())?;
+                    if let Some(event) = event {
+                        file.events.push(event);
+                    }
+                }
+                _ => trace!("ignoring ASS line in [{}]: {:?}", section, line),
+            }
+        }
+
+        // Number our subtitles in the order they appear.
+        for (i, event) in file.events.iter_mut().enumerate() {
+            event.subtitle.index = i + 1;
+        }
+        Ok(file)
+    }
+
+    /// Create an `AssFile` containing the subtitles in `file`, using a single
+    /// default style.
+    pub fn from_subtitle_file(file: &SubtitleFile) -> AssFile {
+        AssFile {
+            script_info: default_script_info(),
+            styles: vec![Style::simple("Default", 20, "&H00FFFFFF", ALIGN_BOTTOM)],
+            events: file.subtitles
+                .iter()
+                .map(|sub| Event {
+                    layer: 0,
+                    style: "Default".to_owned(),
+                    actor: "".to_owned(),
+                    subtitle: sub.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Align two subtitle files using `model`, and create a bilingual
+    /// `AssFile` showing the foreign text at the top of the screen, and the
+    /// native text at the bottom.
+    pub fn bilingual(
+        foreign: &SubtitleFile,
+        native: &SubtitleFile,
+        model: &CostModel,
+    ) -> AssFile {
+        let mut events = vec![];
+        for pair in align_files_with(foreign, native, model) {
+            let period = Period::from_union_opt(
+                pair.0.as_ref().map(|s| s.period),
+                pair.1.as_ref().map(|s| s.period),
+            ).expect("subtitle pair must not be empty");
+            let styled = [(pair.0, "Foreign"), (pair.1, "Native")];
+            for &(ref sub, style) in styled.iter() {
+                if let Some(ref sub) = *sub {
+                    let index = events.len() + 1;
+                    events.push(Event {
+                        layer: 0,
+                        style: style.to_owned(),
+                        actor: "".to_owned(),
+                        subtitle: Subtitle {
+                            index: index,
+                            period: period,
+                            lines: sub.lines.clone(),
+                        },
+                    });
+                }
+            }
+        }
+        AssFile {
+            script_info: default_script_info(),
+            styles: vec![
+                Style::simple("Foreign", 20, "&H0000FFFF", ALIGN_TOP),
+                Style::simple("Native", 18, "&H00FFFFFF", ALIGN_BOTTOM),
+            ],
+            events: events,
+        }
+    }
+
+    /// Convert this file to a regular `SubtitleFile`, discarding all styling
+    /// information except for override tags in the text.
+    pub fn to_subtitle_file(&self) -> SubtitleFile {
+        SubtitleFile {
+            subtitles: self.events.iter().map(|e| e.subtitle.clone()).collect(),
+        }
+    }
+
+    /// Convert this file to a string in `*.ass` format.
+    pub fn to_string(&self) -> String {
+        let mut out = String::from("[Script Info]\n");
+        for &(ref key, ref value) in &self.script_info {
+            out.push_str(&format!("{}: {}\n", key, value));
+        }
+
+        out.push_str("\n[V4+ Styles]\n");
+        out.push_str(&format!("Format: {}\n", STYLE_FORMAT.join(", ")));
+        for style in &self.styles {
+            let values: Vec<&str> = STYLE_FORMAT
+                .iter()
+                .map(|f| style.get(f).unwrap_or("0"))
+                .collect();
+            out.push_str(&format!("Style: {}\n", values.join(",")));
+        }
+
+        out.push_str("\n[Events]\n");
+        out.push_str(&format!("Format: {}\n", DEFAULT_EVENT_FORMAT.join(", ")));
+        for event in &self.events {
+            out.push_str(&format!(
+                "Dialogue: {},{},{},{},{},0,0,0,,{}\n",
+                event.layer,
+                format_time(event.subtitle.period.begin()),
+                format_time(event.subtitle.period.end()),
+                event.style,
+                event.actor,
+                lines_to_text(&event.subtitle.lines)
+            ));
+        }
+        out
+    }
+}
+
+/// The `[Script Info]` we use for files we generate.
+fn default_script_info() -> Vec<(String, String)> {
+    vec![
+        ("ScriptType".to_owned(), "v4.00+".to_owned()),
+        ("WrapStyle".to_owned(), "0".to_owned()),
+        ("ScaledBorderAndShadow".to_owned(), "yes".to_owned()),
+        ("PlayResX".to_owned(), "384".to_owned()),
+        ("PlayResY".to_owned(), "288".to_owned()),
+    ]
+}
+
+/// Look up the value of the field `name` in `values`, using the field names
+/// in `format`.
+fn field_value<'a>(format: &[String], values: &[&'a str], name: &str) -> &'a str {
+    format
+        .iter()
+        .position(|f| f == name)
+        .map(|i| values[i].trim())
+        .unwrap_or("")
+}
+
+/// Parse the value of a `Dialogue:` line using the field names in `format`.
+/// Returns `None` if the event has no text.
+fn parse_dialogue(format: &[String], value: &str) -> Result<Option<Event>> {
+    // The `Text` field is always last, and it may contain commas.
+    let values: Vec<&str> = value.splitn(format.len(), ',').collect();
+    if values.len() != format.len() {
+        return Err(format_err!("expected {} fields in {:?}", format.len(), value));
+    }
+    let field = |name: &str| field_value(format, &values, name);
+
+    let mut end = parse_time(field("End"))?;
+    let begin = parse_time(field("Start"))?;
+    if begin == end {
+        end += 0.001;
+    }
+    let lines = text_to_lines(field("Text"));
+    if lines.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Event {
+        // `*.ssa` files have a "Marked" field instead of "Layer".
+        layer: i32::from_str(field("Layer")).unwrap_or(0),
+        style: field("Style").to_owned(),
+        actor: field("Name").to_owned(),
+        subtitle: Subtitle {
+            index: 0,
+            period: Period::new(begin, end)?,
+            lines: lines,
+        },
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use align::CostModel;
+    use ass::{format_time, AssFile};
+    use clean::strip_formatting;
+    use srt::SubtitleFile;
+    use time::Period;
+
+    const EXAMPLE: &str = "\u{FEFF}[Script Info]
+; A comment.
+Title: Example
+ScriptType: v4.00+
+
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, Alignment
+Style: Default,Arial,20,&H00FFFFFF,2
+Style: Signs,Arial,16,&H0000FFFF,8
+
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+Comment: 0,0:00:00.00,0:00:01.00,Default,,0,0,0,,Not shown
+Dialogue: 0,0:00:01.50,0:00:03.00,Default,Sokka,0,0,0,,{\\i1}Hey,{\\i0} wait!\\NFor me!
+Dialogue: 1,0:01:02.33,0:01:04.66,Signs,,0,0,0,,{\\pos(10,10)}Ba Sing Se
+";
+
+    #[test]
+    fn parse_ass_file() {
+        let ass = AssFile::from_str(EXAMPLE).unwrap();
+        assert_eq!(
+            Some(&("Title".to_owned(), "Example".to_owned())),
+            ass.script_info.get(0)
+        );
+        assert_eq!(2, ass.styles.len());
+        assert_eq!("Signs", ass.styles[1].name());
+        assert_eq!(Some("8"), ass.styles[1].get("Alignment"));
+
+        assert_eq!(2, ass.events.len());
+        let event = &ass.events[0];
+        assert_eq!(0, event.layer);
+        assert_eq!("Default", event.style);
+        assert_eq!("Sokka", event.actor);
+        assert_eq!(1, event.subtitle.index);
+        assert_eq!(Period::new(1.5, 3.0).unwrap(), event.subtitle.period);
+        assert_eq!(
+            vec!["{\\i1}Hey,{\\i0} wait!".to_owned(), "For me!".to_owned()],
+            event.subtitle.lines
+        );
+        assert_eq!("Hey, wait! For me!", event.subtitle.plain_text());
+
+        let event2 = &ass.events[1];
+        assert_eq!(1, event2.layer);
+        assert_eq!("Signs", event2.style);
+        assert_eq!("Ba Sing Se", strip_formatting(&event2.subtitle.lines[0]));
+    }
+
+    #[test]
+    fn format_ass_time() {
+        assert_eq!("0:00:01.50", format_time(1.5));
+        assert_eq!("1:02:03.46", format_time(3723.456));
+    }
+
+    #[test]
+    fn write_ass_file() {
+        let srt = SubtitleFile::from_str(
+            "1
+00:00:01,500 --> 00:00:03,000
+<i>Hey,</i> wait!
+For me!
+",
+        ).unwrap();
+        let ass = AssFile::from_subtitle_file(&srt);
+        let text = ass.to_string();
+        assert!(text.starts_with("[Script Info]\nScriptType: v4.00+\n"));
+        assert!(text.contains("\nStyle: Default,Arial,20,&H00FFFFFF,"));
+        assert!(text.contains(
+            "\nDialogue: 0,0:00:01.50,0:00:03.00,Default,,0,0,0,,{\\i1}Hey,{\\i0} wait!\\NFor me!\n"
+        ));
+
+        // Make sure we can read back what we wrote.
+        let reparsed = AssFile::from_str(&text).unwrap().to_subtitle_file();
+        assert_eq!(1, reparsed.subtitles.len());
+        assert_eq!(srt.subtitles[0].period, reparsed.subtitles[0].period);
+        assert_eq!(srt.subtitles[0].plain_text(), reparsed.subtitles[0].plain_text());
+    }
+
+    #[test]
+    fn bilingual_ass_file() {
+        use std::path::Path;
+
+        let srt_es = SubtitleFile::from_path(Path::new("fixtures/sample.es.srt")).unwrap();
+        let srt_en = SubtitleFile::from_path(Path::new("fixtures/sample.en.srt")).unwrap();
+        let ass = AssFile::bilingual(&srt_es, &srt_en, &CostModel::default());
+        assert_eq!(vec!["Foreign", "Native"],
+                   ass.styles.iter().map(|s| s.name()).collect::<Vec<_>>());
+        assert_eq!("Foreign", ass.events[0].style);
+        assert_eq!(vec!["¡Si! ¡Aang ha vuelto!".to_owned()], ass.events[0].subtitle.lines);
+        assert_eq!("Native", ass.events[1].style);
+        assert_eq!(ass.events[0].subtitle.period, ass.events[1].subtitle.period);
+    }
+}
@@ -0,0 +1,226 @@
+//! Finding every episode of a series in a directory, so that we can export
+//! a whole season at once.
+//!
+//! We pair each video with the subtitle files which share its name, such
+//! as `Show.S01E03.mkv` with `Show.S01E03.es.srt` and `Show.S01E03.en.srt`.
+//! If a subtitle file has no language suffix, we try to detect its language
+//! from its contents.
+
+use common_failures::prelude::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use lang::Lang;
+use srt::SubtitleFile;
+
+/// File extensions which we treat as videos.
+const VIDEO_EXTENSIONS: &[&str] = &["avi", "m4v", "mkv", "mov", "mp4", "webm"];
+
+/// File extensions which we treat as subtitles.
+const SUBTITLE_EXTENSIONS: &[&str] =
+    &["ass", "dfxp", "sbv", "srt", "ssa", "sub", "ttml", "vtt"];
+
+/// A video in a batch, plus its subtitles.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Episode {
+    /// The path to the video.
+    pub video: PathBuf,
+    /// The path to the foreign-language subtitles.
+    pub foreign_subs: PathBuf,
+    /// The path to the native-language subtitles, if we found any.
+    pub native_subs: Option<PathBuf>,
+}
+
+/// Does `path` have one of the specified extensions?
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map_or(false, |ext| extensions.contains(&&ext.to_lowercase()[..]))
+}
+
+/// Get the file name of `path` as a string, if it has one.
+fn file_name(path: &Path) -> Option<&str> {
+    path.file_name().and_then(|name| name.to_str())
+}
+
+/// Get the language from a subtitle file name suffix like `es.srt`. Returns
+/// `None` for a bare extension like `srt`.
+fn suffix_lang(suffix: &str) -> Option<Lang> {
+    match suffix.rfind('.') {
+        Some(dot) => Lang::bcp47(&suffix[..dot]).ok(),
+        None => None,
+    }
+}
+
+/// Get the language of a subtitle file from its name, if it has a language
+/// suffix like `Show.es.srt`.
+pub fn lang_from_file_name(path: &Path) -> Option<Lang> {
+    let name = file_name(path)?;
+    let ext = name.rfind('.')?;
+    let dot = name[..ext].rfind('.')?;
+    suffix_lang(&name[dot + 1..])
+}
+
+/// Find all the episodes in `dir` which have `foreign_lang` subtitles,
+/// sorted by file name. Videos without foreign subtitles are skipped.
+pub fn find_episodes(
+    dir: &Path,
+    foreign_lang: Lang,
+    native_lang: Option<Lang>,
+) -> Result<Vec<Episode>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir).io_read_context(dir)? {
+        let path = entry.io_read_context(dir)?.path();
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+    Ok(pair_episodes(&paths, foreign_lang, native_lang, |path| {
+        match SubtitleFile::from_path(path) {
+            Ok(file) => file.detect_language(),
+            Err(err) => {
+                warn!("Could not read {}: {}", path.display(), err);
+                None
+            }
+        }
+    }))
+}
+
+/// Pair up videos and subtitles from `paths`, calling `detect_language`
+/// for any subtitle file without a recognizable language suffix.
+fn pair_episodes<F>(
+    paths: &[PathBuf],
+    foreign_lang: Lang,
+    native_lang: Option<Lang>,
+    mut detect_language: F,
+) -> Vec<Episode>
+where
+    F: FnMut(&Path) -> Option<Lang>,
+{
+    let mut videos: Vec<&PathBuf> = paths
+        .iter()
+        .filter(|p| has_extension(p, VIDEO_EXTENSIONS))
+        .collect();
+    videos.sort();
+    let mut subtitles: Vec<&PathBuf> = paths
+        .iter()
+        .filter(|p| has_extension(p, SUBTITLE_EXTENSIONS))
+        .collect();
+    subtitles.sort();
+
+    // Assign each subtitle file to the video with the longest matching
+    // name, so that `Show.S01E03.extended.es.srt` goes with
+    // `Show.S01E03.extended.mkv` instead of `Show.S01E03.mkv`.
+    let mut matches: Vec<(Option<&PathBuf>, Option<&PathBuf>)> =
+        vec![(None, None); videos.len()];
+    for sub in subtitles {
+        let sub_name = match file_name(sub) {
+            Some(name) => name,
+            None => continue,
+        };
+        let best = videos
+            .iter()
+            .enumerate()
+            .filter_map(|(i, video)| {
+                let stem = video.file_stem().and_then(|s| s.to_str())?;
+                let rest = sub_name.get(stem.len()..)?;
+                if sub_name.starts_with(stem) && rest.starts_with('.') {
+                    Some((i, stem.len(), &rest[1..]))
+                } else {
+                    None
+                }
+            })
+            .max_by_key(|&(_, len, _)| len);
+        let (i, suffix) = match best {
+            Some((i, _, suffix)) => (i, suffix),
+            None => continue,
+        };
+
+        let lang = suffix_lang(suffix).or_else(|| detect_language(sub));
+        let lang = match lang {
+            Some(lang) => lang,
+            None => continue,
+        };
+
+        // Keep the first file we see for each language.
+        let entry = &mut matches[i];
+        if foreign_lang.matches(lang) {
+            entry.0 = entry.0.or(Some(sub));
+        } else if native_lang.map_or(false, |native| native.matches(lang)) {
+            entry.1 = entry.1.or(Some(sub));
+        }
+    }
+
+    let mut episodes = vec![];
+    for (video, (foreign, native)) in videos.into_iter().zip(matches) {
+        match foreign {
+            Some(foreign) => episodes.push(Episode {
+                video: video.to_owned(),
+                foreign_subs: foreign.to_owned(),
+                native_subs: native.cloned(),
+            }),
+            None => {
+                warn!("No {} subtitles for {}", foreign_lang, video.display());
+            }
+        }
+    }
+    episodes
+}
+
+#[test]
+fn pair_episodes_by_name_and_language() {
+    let paths: Vec<PathBuf> = [
+        "Show.S01E01.mkv",
+        "Show.S01E01.es.srt",
+        "Show.S01E01.en.srt",
+        "Show.S01E02.mkv",
+        "Show.S01E02.srt",
+        "Show.S01E02.en.vtt",
+        "Show.S01E02.extended.mkv",
+        "Show.S01E02.extended.es-MX.ass",
+        "Show.S01E03.mkv",
+        "Show.S01E03.en.srt",
+        "notes.txt",
+    ].iter()
+        .map(|p| PathBuf::from(p))
+        .collect();
+    let es = Lang::iso639("es").unwrap();
+    let en = Lang::iso639("en").unwrap();
+    let episodes = pair_episodes(&paths, es, Some(en), |path| {
+        assert_eq!(path, Path::new("Show.S01E02.srt"));
+        Some(es)
+    });
+    let episode = |video: &str, foreign: &str, native: Option<&str>| Episode {
+        video: PathBuf::from(video),
+        foreign_subs: PathBuf::from(foreign),
+        native_subs: native.map(PathBuf::from),
+    };
+    assert_eq!(
+        episodes,
+        vec![
+            episode(
+                "Show.S01E01.mkv",
+                "Show.S01E01.es.srt",
+                Some("Show.S01E01.en.srt"),
+            ),
+            episode(
+                "Show.S01E02.extended.mkv",
+                "Show.S01E02.extended.es-MX.ass",
+                None,
+            ),
+            episode(
+                "Show.S01E02.mkv",
+                "Show.S01E02.srt",
+                Some("Show.S01E02.en.vtt"),
+            ),
+        ]
+    );
+}
+
+#[test]
+fn lang_from_file_name_uses_suffix() {
+    let es_mx = Lang::bcp47("es-MX").unwrap();
+    assert_eq!(Some(es_mx), lang_from_file_name(Path::new("dir/Show.es-MX.srt")));
+    assert_eq!(None, lang_from_file_name(Path::new("Show.srt")));
+    assert_eq!(None, lang_from_file_name(Path::new("Show.S01E02.srt")));
+}
@@ -3,23 +3,43 @@
 #[macro_use]
 extern crate common_failures;
 extern crate env_logger;
+#[macro_use]
+extern crate failure;
 extern crate structopt;
 #[macro_use]
 extern crate structopt_derive;
 extern crate substudy;
 
 use common_failures::prelude::*;
+use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::result;
+use std::str::FromStr;
+use std::sync::Arc;
 use structopt::StructOpt;
-use substudy::srt::SubtitleFile;
-use substudy::align::combine_files;
+use substudy::ass::AssFile;
+use substudy::batch;
+use substudy::bundle;
+use substudy::clean::clean_subtitle_file;
+use substudy::format::Format;
+use substudy::lang::Lang;
+use substudy::srt::{Subtitle, SubtitleFile};
+use substudy::sync::{sync_to_video, SyncOptions};
+use substudy::time::{parse_hhmmss, seconds_to_hhmmss, Retiming};
+use substudy::align::{align_files_scored, combine_files_with, copy_timing, estimate_offset,
+                      low_confidence_regions, AlignedGroup, CostModel};
+use substudy::dictionary::Dictionary;
 use substudy::video;
 use substudy::export;
 
 #[derive(Debug, StructOpt)]
-/// Subtitle processing tools for students of foreign languages. (For now, all
-/// subtitles must be in *.srt format. Many common encodings will be
-/// automatically detected, but try converting to UTF-8 if you have problems.)
+/// Subtitle processing tools for students of foreign languages. (Subtitles may
+/// be in *.srt, *.vtt, *.ass, MicroDVD *.sub, *.sbv or TTML format, which
+/// will be detected automatically. Many common encodings will also be
+/// detected, but try converting to UTF-8 if you have problems. Foreign
+/// subtitles may also be read from an aligned media bundle directory, in
+/// which case native subtitles will be read from the bundle, too.)
 #[structopt(name = "substudy")]
 enum Args {
     /// Clean a subtitle file, removing things that don't look like dialog.
@@ -28,6 +48,10 @@ enum Args {
         /// Path to the subtitle file to clean.
         #[structopt(parse(from_os_str))]
         subs: PathBuf,
+
+        /// Output format (srt, vtt, ass, microdvd, sbv or ttml).
+        #[structopt(long = "format", default_value = "srt")]
+        format: Format,
     },
 
     /// Combine two subtitle files into a single bilingual subtitle file.
@@ -39,106 +63,365 @@ enum Args {
 
         /// Path to the native language subtitle file to be combined.
         #[structopt(parse(from_os_str))]
-        native_subs: PathBuf,
+        native_subs: Option<PathBuf>,
+
+        /// Seconds by which the native subtitles lag behind the foreign
+        /// ones. Detected automatically if not specified.
+        #[structopt(long = "offset")]
+        offset: Option<f32>,
+
+        /// Output format (srt, vtt, ass, microdvd, sbv or ttml). The ass
+        /// format shows the foreign language at the top of the screen and
+        /// the native language at the bottom.
+        #[structopt(long = "format", default_value = "srt")]
+        format: Format,
     },
 
-    /// Export subtitles in one of several formats (Anki cards, music tracks,
-    /// etc).
-    #[structopt(name = "export")]
-    Export {
-        #[structopt(subcommand)]
-        format: ExportFormat,
+    /// Align two subtitle files, and show how confident we are about each
+    /// match.
+    #[structopt(name = "align")]
+    Align {
+        /// Path to the foreign language subtitle file.
+        #[structopt(parse(from_os_str))]
+        foreign_subs: PathBuf,
+
+        /// Path to the native language subtitle file.
+        #[structopt(parse(from_os_str))]
+        native_subs: Option<PathBuf>,
+
+        /// Seconds by which the native subtitles lag behind the foreign
+        /// ones. Detected automatically if not specified.
+        #[structopt(long = "offset")]
+        offset: Option<f32>,
+
+        /// Only list regions where our confidence is below the threshold,
+        /// so that they can be checked by hand.
+        #[structopt(long = "report")]
+        report: bool,
+
+        /// The confidence below which a match is considered dubious.
+        #[structopt(long = "threshold", default_value = "0.5")]
+        threshold: f32,
     },
 
-    /// List information about a file.
-    #[structopt(name = "list")]
-    List {
-        #[structopt(subcommand)]
-        to_list: ToList,
+    /// Shift or stretch the timing of a subtitle file.
+    #[structopt(name = "retime")]
+    Retime {
+        /// Path to the subtitle file to retime.
+        #[structopt(parse(from_os_str))]
+        subs: PathBuf,
+
+        /// Seconds to add to each subtitle. Use "--offset=-1.5" to move
+        /// subtitles earlier.
+        #[structopt(long = "offset")]
+        offset: Option<f32>,
+
+        /// Amount to stretch all times by. May be written as a ratio, such as
+        /// "25/23.976" to fix subtitles timed for the wrong frame rate.
+        #[structopt(long = "scale", parse(try_from_str = "parse_ratio"))]
+        scale: Option<f32>,
+
+        /// Move the subtitle with index N to begin at TIME, specified as
+        /// "N=TIME" (for example, "12=0:01:02.5"). Pass this twice to fit
+        /// the timing through two points.
+        #[structopt(long = "sync", parse(try_from_str = "parse_sync_point"))]
+        sync: Vec<(usize, f32)>,
+
+        /// Output format (srt, vtt, ass, microdvd, sbv or ttml).
+        #[structopt(long = "format", default_value = "srt")]
+        format: Format,
     },
-}
 
-#[derive(Debug, StructOpt)]
-enum ExportFormat {
-    /// Export as CSV file and media for use with Anki.
-    #[structopt(name = "csv")]
-    Csv {
+    /// Synchronize a subtitle file with the speech in a video's soundtrack.
+    #[structopt(name = "sync")]
+    Sync {
         /// Path to the video.
         #[structopt(parse(from_os_str))]
         video: PathBuf,
 
-        /// Path to the file containing foreign language subtitles.
+        /// Path to the subtitle file to synchronize.
         #[structopt(parse(from_os_str))]
-        foreign_subs: PathBuf,
+        subs: PathBuf,
 
-        /// Path to the file containing native language subtitles.
-        #[structopt(parse(from_os_str))]
-        native_subs: Option<PathBuf>,
+        /// The largest offset to search for, in seconds.
+        #[structopt(long = "max-offset", default_value = "60")]
+        max_offset: f32,
+
+        /// Use the audio stream with this index (as shown by `list
+        /// tracks`), instead of choosing one automatically.
+        #[structopt(long = "audio-stream")]
+        audio_stream: Option<usize>,
+
+        /// Output format (srt, vtt, ass, microdvd, sbv or ttml).
+        #[structopt(long = "format", default_value = "srt")]
+        format: Format,
     },
 
-    /// Export as an HTML page allowing you to review the subtitles.
-    #[structopt(name = "review")]
-    Review {
-        /// Path to the video.
+    /// Export subtitles in one of several formats (Anki cards, music tracks,
+    /// etc). If given a directory instead of a video, export every video in
+    /// it, pairing each one with subtitles which share its name (such as
+    /// `Show.S01E03.mkv` and `Show.S01E03.es.srt`). Subtitles without a
+    /// language suffix will be identified automatically, and CSV exports
+    /// will be combined into a single deck.
+    #[structopt(name = "export")]
+    Export {
+        /// Export format: csv (a CSV file and media for use with Anki), apkg
+        /// (an Anki package which can be imported in one step), aligned (an
+        /// aligned media bundle for use by other tools), review (an HTML
+        /// page allowing you to review the subtitles) or tracks (audio
+        /// tracks for listening on the go).
+        format: ExportKind,
+
+        /// Path to the video, or to a directory of videos and subtitles.
+        /// Directories require --foreign-lang.
         #[structopt(parse(from_os_str))]
         video: PathBuf,
 
-        /// Path to the file containing foreign language subtitles.
+        /// Path to the file containing foreign language subtitles. Omit
+        /// this when using --foreign-lang.
         #[structopt(parse(from_os_str))]
-        foreign_subs: PathBuf,
+        foreign_subs: Option<PathBuf>,
 
-        /// Path to the file containing native language subtitles.
+        /// Path to the file containing native language subtitles (not used
+        /// by tracks).
         #[structopt(parse(from_os_str))]
         native_subs: Option<PathBuf>,
+
+        /// Use the embedded subtitles in this language (such as "es") as
+        /// the foreign language subtitles.
+        #[structopt(long = "foreign-lang")]
+        foreign_lang: Option<Lang>,
+
+        /// Use the embedded subtitles in this language (such as "en") as
+        /// the native language subtitles.
+        #[structopt(long = "native-lang")]
+        native_lang: Option<Lang>,
+
+        /// Seconds by which the native subtitles lag behind the foreign
+        /// ones. Detected automatically if not specified.
+        #[structopt(long = "offset")]
+        offset: Option<f32>,
+
+        /// Ignore the timing of the native subtitles, and align them using
+        /// only their text. Use this if their timestamps are broken.
+        #[structopt(long = "text-only")]
+        text_only: bool,
+
+        /// A file containing "foreign<TAB>native" word pairs, used as hints
+        /// when aligning with --text-only.
+        #[structopt(long = "dictionary", parse(from_os_str))]
+        dictionary: Option<PathBuf>,
+
+        /// Use the audio stream with this index (as shown by `list
+        /// tracks`), instead of choosing one automatically.
+        #[structopt(long = "audio-stream")]
+        audio_stream: Option<usize>,
+
+        /// Seconds of audio to include before each subtitle (csv, apkg and
+        /// review only).
+        #[structopt(long = "lead-in")]
+        lead_in: Option<f32>,
+
+        /// Seconds of audio to include after each subtitle (csv, apkg and
+        /// review only).
+        #[structopt(long = "lead-out")]
+        lead_out: Option<f32>,
+
+        /// Don't let the lead-in or lead-out extend into neighbouring
+        /// subtitles.
+        #[structopt(long = "snap-to-gaps")]
+        snap_to_gaps: bool,
+
+        /// Also export a longer audio clip covering the previous, current
+        /// and next subtitles (csv, apkg and review only).
+        #[structopt(long = "context-audio")]
+        context_audio: bool,
+
+        /// Audio format for clips (mp3, aac, opus or ogg).
+        #[structopt(long = "audio-format", default_value = "mp3")]
+        audio_format: video::AudioFormat,
+
+        /// Audio bitrate for clips, in kbit/s. Uses the format's default if
+        /// not specified.
+        #[structopt(long = "audio-bitrate")]
+        audio_bitrate: Option<u32>,
+
+        /// Normalize the loudness of each audio clip.
+        #[structopt(long = "normalize-audio")]
+        normalize_audio: bool,
+
+        /// Image format (jpg, png or webp for still images, or gif or webm
+        /// for animated snippets).
+        #[structopt(long = "image-format", default_value = "jpg")]
+        image_format: video::ImageFormat,
+
+        /// Maximum image size, as WIDTHxHEIGHT.
+        #[structopt(long = "image-size", default_value = "240x160")]
+        image_size: video::ImageSize,
+
+        /// How many copies of ffmpeg to run at once. Defaults to the number
+        /// of CPUs.
+        #[structopt(long = "jobs", short = "j")]
+        jobs: Option<usize>,
+
+        /// Continue an interrupted export, skipping any media files which
+        /// were already extracted.
+        #[structopt(long = "resume")]
+        resume: bool,
     },
 
-    /// Export as MP3 tracks for listening on the go.
-    #[structopt(name = "tracks")]
-    Tracks {
-        /// Path to the video.
-        #[structopt(parse(from_os_str))]
-        video: PathBuf,
+    /// List information about a file.
+    #[structopt(name = "list")]
+    List {
+        #[structopt(subcommand)]
+        to_list: ToList,
+    },
 
-        /// Path to the file containing foreign language subtitles.
-        #[structopt(parse(from_os_str))]
-        foreign_subs: PathBuf,
+    /// Convert between aligned media bundles and subtitle files.
+    #[structopt(name = "bundle")]
+    Bundle {
+        #[structopt(subcommand)]
+        command: BundleCommand,
     },
 }
 
-impl ExportFormat {
-    /// Get the name of the export format to use.
-    fn name(&self) -> &str {
+/// The formats supported by `export`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ExportKind {
+    Csv,
+    Apkg,
+    Aligned,
+    Review,
+    Tracks,
+}
+
+impl ExportKind {
+    /// Get the name of the export format, which we also use when naming
+    /// output directories.
+    fn name(&self) -> &'static str {
         match *self {
-            ExportFormat::Csv { .. } => "csv",
-            ExportFormat::Review { .. } => "review",
-            ExportFormat::Tracks { .. } => "tracks",
+            ExportKind::Csv => "csv",
+            ExportKind::Apkg => "apkg",
+            ExportKind::Aligned => "aligned",
+            ExportKind::Review => "review",
+            ExportKind::Tracks => "tracks",
         }
     }
 
-    /// Get the path to the video.
-    fn video(&self) -> &Path {
-        match *self {
-            ExportFormat::Csv { ref video, .. } => &video,
-            ExportFormat::Review { ref video, .. } => &video,
-            ExportFormat::Tracks { ref video, .. } => &video,
+    /// Does this format use native-language subtitles?
+    fn uses_native_subs(&self) -> bool {
+        *self != ExportKind::Tracks
+    }
+}
+
+impl FromStr for ExportKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ExportKind> {
+        match s {
+            "csv" => Ok(ExportKind::Csv),
+            "apkg" => Ok(ExportKind::Apkg),
+            "aligned" => Ok(ExportKind::Aligned),
+            "review" => Ok(ExportKind::Review),
+            "tracks" => Ok(ExportKind::Tracks),
+            _ => Err(format_err!("Unknown export format: {}", s)),
         }
     }
+}
 
-    /// Get the path to the foreign-language subtitles, if present.
-    fn foreign_subs(&self) -> &Path {
-        match *self {
-            ExportFormat::Csv { ref foreign_subs, .. } => &foreign_subs,
-            ExportFormat::Review { ref foreign_subs, .. } => &foreign_subs,
-            ExportFormat::Tracks { ref foreign_subs, .. } => &foreign_subs,
+/// The options shared by all our export formats.
+#[derive(Debug)]
+struct ExportArgs {
+    /// The format to export.
+    kind: ExportKind,
+    /// The language of the embedded foreign-language subtitles, if any.
+    foreign_lang: Option<Lang>,
+    /// The language of the embedded native-language subtitles, if any.
+    native_lang: Option<Lang>,
+    /// The offset of the native-language subtitles, if specified.
+    native_offset: Option<f32>,
+    /// Should we align native-language subtitles using only their text?
+    text_only: bool,
+    /// A dictionary to use when aligning native-language subtitles using
+    /// only their text.
+    dictionary: Option<PathBuf>,
+    /// The audio stream chosen by the user, if any.
+    audio_stream: Option<usize>,
+    /// The number of ffmpeg processes to run at once, if specified.
+    jobs: Option<usize>,
+    /// Should we resume an interrupted export?
+    resume: bool,
+    /// How to choose clip boundaries and encode exported media.
+    options: export::ExportOptions,
+}
+
+impl ExportArgs {
+    /// Figure out where to find our subtitles. When `--foreign-lang` is
+    /// used, `first` (if present) contains the native-language subtitles.
+    fn subtitle_sources<'a>(
+        &self,
+        first: Option<&'a Path>,
+        second: Option<&'a Path>,
+    ) -> Result<(SubtitleSource<'a>, Option<SubtitleSource<'a>>)> {
+        let (foreign, native_path) = match (self.foreign_lang, first) {
+            (Some(_), Some(_)) if second.is_some() => {
+                return Err(format_err!("too many subtitle files for use with --foreign-lang"));
+            }
+            (Some(lang), _) => (SubtitleSource::Embedded(lang), first),
+            (None, Some(path)) => (SubtitleSource::File(path), second),
+            (None, None) => {
+                return Err(format_err!("please specify foreign subtitles or --foreign-lang"));
+            }
+        };
+        if !self.kind.uses_native_subs() {
+            if native_path.is_some() {
+                return Err(format_err!(
+                    "{} exports do not use native subtitles",
+                    self.kind.name()
+                ));
+            }
+            return Ok((foreign, None));
         }
+        let native = match (native_path, self.native_lang) {
+            (Some(_), Some(_)) => {
+                return Err(format_err!("cannot use both native subtitles and --native-lang"));
+            }
+            (Some(path), None) => Some(SubtitleSource::File(path)),
+            (None, Some(lang)) => Some(SubtitleSource::Embedded(lang)),
+            (None, None) => None,
+        };
+        Ok((foreign, native))
     }
 
-    /// Get the path to the native-language subtitles, if present.
-    fn native_subs(&self) -> Option<&Path> {
+    /// Should we align native-language subtitles using only their text? If
+    /// so, return the path to our dictionary, if any.
+    fn text_only(&self) -> Result<Option<Option<&Path>>> {
+        let dictionary = self.dictionary.as_ref().map(|p| p.as_path());
+        match (self.text_only, dictionary) {
+            (true, dictionary) => Ok(Some(dictionary)),
+            (false, Some(_)) => Err(format_err!("--dictionary requires --text-only")),
+            (false, None) => Ok(None),
+        }
+    }
+}
+
+/// Where to find a set of subtitles.
+#[derive(Clone, Copy, Debug)]
+enum SubtitleSource<'a> {
+    /// A subtitle file or aligned media bundle.
+    File(&'a Path),
+    /// The embedded subtitle stream for a language in the video we're
+    /// working with.
+    Embedded(Lang),
+}
+
+impl<'a> SubtitleSource<'a> {
+    /// Read these subtitles without cleaning them. Any image-based
+    /// subtitles will be extracted to `dir`.
+    fn read(&self, video: &video::Video, dir: &Path) -> Result<SubtitleFile> {
         match *self {
-            ExportFormat::Csv { ref native_subs, .. } => native_subs.as_ref().map(|p| p.as_path()),
-            ExportFormat::Review { ref native_subs, .. } => native_subs.as_ref().map(|p| p.as_path()),
-            ExportFormat::Tracks { .. } => None,
+            SubtitleSource::File(path) => SubtitleFile::from_path(path),
+            SubtitleSource::Embedded(lang) => embedded_subtitles(video, lang, dir),
         }
     }
 }
@@ -154,6 +437,50 @@ enum ToList {
     },
 }
 
+#[derive(Debug, StructOpt)]
+enum BundleCommand {
+    /// Write each language in an aligned media bundle to a separate
+    /// subtitle file.
+    #[structopt(name = "split")]
+    Split {
+        /// Path to the bundle directory or its metadata.json file.
+        #[structopt(parse(from_os_str))]
+        bundle: PathBuf,
+
+        /// Directory in which to write the subtitles.
+        #[structopt(long = "out-dir", parse(from_os_str), default_value = ".")]
+        out_dir: PathBuf,
+
+        /// Output format (srt, vtt, ass, microdvd, sbv or ttml).
+        #[structopt(long = "format", default_value = "srt")]
+        format: Format,
+    },
+
+    /// Create an aligned media bundle from a video and one or more subtitle
+    /// files, which will be aligned to the first.
+    #[structopt(name = "create")]
+    Create {
+        /// Path to the video.
+        #[structopt(parse(from_os_str))]
+        video: PathBuf,
+
+        /// Paths to the subtitle files, one per language.
+        #[structopt(parse(from_os_str))]
+        subs: Vec<PathBuf>,
+
+        /// The bundle directory to create. Defaults to the video's name
+        /// with an `.aligned` extension, in the current directory.
+        #[structopt(long = "out-dir", parse(from_os_str))]
+        out_dir: Option<PathBuf>,
+
+        /// The language of each subtitle file, in order. May be specified
+        /// once per file. Defaults to the detected language, or the file
+        /// name's language suffix (such as "Show.es.srt").
+        #[structopt(long = "lang", number_of_values_raw = "1")]
+        langs: Vec<Lang>,
+    },
+}
+
 // Choose and run the appropriate command.
 fn run() -> Result<()> {
     env_logger::init().expect("could not initialize logging");
@@ -162,36 +489,315 @@ fn run() -> Result<()> {
     let args: Args = Args::from_args();
 
     match args {
-        Args::Clean { ref subs } => {
-            cmd_clean(subs)
+        Args::Clean { ref subs, format } => {
+            cmd_clean(subs, format)
         }
-        Args::Combine { ref foreign_subs, ref native_subs } => {
-            cmd_combine(foreign_subs, native_subs)
+        Args::Combine { ref foreign_subs, ref native_subs, offset, format } => {
+            cmd_combine(foreign_subs, native_subs.as_ref().map(|p| p.as_path()), offset, format)
         }
-        Args::Export { ref format } => {
-            cmd_export(
-                format.name(),
-                format.video(),
-                format.foreign_subs(),
-                format.native_subs()
+        Args::Align { ref foreign_subs, ref native_subs, offset, report, threshold } => {
+            cmd_align(
+                foreign_subs,
+                native_subs.as_ref().map(|p| p.as_path()),
+                offset,
+                report,
+                threshold,
             )
         }
+        Args::Retime { ref subs, offset, scale, ref sync, format } => {
+            cmd_retime(subs, offset, scale, sync, format)
+        }
+        Args::Sync { ref video, ref subs, max_offset, audio_stream, format } => {
+            cmd_sync(video, subs, max_offset, audio_stream, format)
+        }
+        Args::Export {
+            format,
+            ref video,
+            ref foreign_subs,
+            ref native_subs,
+            foreign_lang,
+            native_lang,
+            offset,
+            text_only,
+            ref dictionary,
+            audio_stream,
+            lead_in,
+            lead_out,
+            snap_to_gaps,
+            context_audio,
+            audio_format,
+            audio_bitrate,
+            normalize_audio,
+            image_format,
+            image_size,
+            jobs,
+            resume,
+        } => {
+            let args = ExportArgs {
+                kind: format,
+                foreign_lang: foreign_lang,
+                native_lang: native_lang,
+                native_offset: offset,
+                text_only: text_only,
+                dictionary: dictionary.clone(),
+                audio_stream: audio_stream,
+                jobs: jobs,
+                resume: resume,
+                options: export::ExportOptions {
+                    audio: video::AudioOptions {
+                        format: audio_format,
+                        bitrate: audio_bitrate,
+                        normalize: normalize_audio,
+                    },
+                    image: video::ImageOptions {
+                        format: image_format,
+                        size: image_size,
+                    },
+                    clips: export::ClipOptions {
+                        lead_in: lead_in,
+                        lead_out: lead_out,
+                        snap_to_gaps: snap_to_gaps,
+                        context_audio: context_audio,
+                    },
+                },
+            };
+            let first = foreign_subs.as_ref().map(|p| p.as_path());
+            let second = native_subs.as_ref().map(|p| p.as_path());
+            if video.is_dir() {
+                if first.is_some() {
+                    return Err(format_err!(
+                        "cannot specify subtitle files when exporting a directory"
+                    ));
+                }
+                cmd_batch(video, &args)
+            } else {
+                let (foreign, native) = args.subtitle_sources(first, second)?;
+                cmd_export(video, foreign, native, &args)
+            }
+        }
         Args::List { to_list: ToList::Tracks { ref video } } => {
             cmd_tracks(video)
         }
+        Args::Bundle { command: BundleCommand::Split { ref bundle, ref out_dir, format } } => {
+            cmd_bundle_split(bundle, out_dir, format)
+        }
+        Args::Bundle {
+            command: BundleCommand::Create { ref video, ref subs, ref out_dir, ref langs },
+        } => {
+            cmd_bundle_create(video, subs, out_dir.as_ref().map(|p| p.as_path()), langs)
+        }
     }
 }
 
-fn cmd_clean(path: &Path) -> Result<()> {
+fn cmd_clean(path: &Path, format: Format) -> Result<()> {
     let file1 = SubtitleFile::cleaned_from_path(path)?;
-    print!("{}", file1.to_string());
+    print!("{}", file1.to_string_as(format));
     Ok(())
 }
 
-fn cmd_combine(path1: &Path, path2: &Path) -> Result<()> {
-    let file1 = SubtitleFile::cleaned_from_path(path1)?;
-    let file2 = SubtitleFile::cleaned_from_path(path2)?;
-    print!("{}", combine_files(&file1, &file2).to_string());
+/// Load cleaned foreign and native subtitles. If `foreign_path` is an
+/// aligned media bundle, we also take the native subtitles from it, unless
+/// `native_path` is specified.
+fn load_subtitles(
+    foreign_path: &Path,
+    native_path: Option<&Path>,
+) -> Result<(SubtitleFile, Option<SubtitleFile>)> {
+    let (foreign, native) = if bundle::is_bundle(foreign_path) {
+        let (foreign, native) = bundle::load_subtitles(foreign_path, None, None)?;
+        let foreign = clean_subtitle_file(&foreign)?;
+        match native_path {
+            Some(p) => (foreign, Some(SubtitleFile::cleaned_from_path(p)?)),
+            None => match native {
+                Some(native) => (foreign, Some(clean_subtitle_file(&native)?)),
+                None => (foreign, None),
+            },
+        }
+    } else {
+        let foreign = SubtitleFile::cleaned_from_path(foreign_path)?;
+        match native_path {
+            Some(p) => (foreign, Some(SubtitleFile::cleaned_from_path(p)?)),
+            None => (foreign, None),
+        }
+    };
+    Ok((foreign, native))
+}
+
+/// Like `load_subtitles`, but fail if we can't find native subtitles.
+fn load_subtitle_pair(
+    foreign_path: &Path,
+    native_path: Option<&Path>,
+) -> Result<(SubtitleFile, SubtitleFile)> {
+    match load_subtitles(foreign_path, native_path)? {
+        (foreign, Some(native)) => Ok((foreign, native)),
+        (_, None) => Err(format_err!("no native language subtitles specified")),
+    }
+}
+
+/// Use `offset` if the user specified it, or estimate it and report it.
+fn native_offset(
+    foreign: &SubtitleFile,
+    native: &SubtitleFile,
+    offset: Option<f32>,
+) -> f32 {
+    offset.unwrap_or_else(|| {
+        let offset = estimate_offset(foreign, native);
+        eprintln!("Detected native subtitle offset of {:.3}s", offset);
+        offset
+    })
+}
+
+fn cmd_combine(
+    path1: &Path,
+    path2: Option<&Path>,
+    offset: Option<f32>,
+    format: Format,
+) -> Result<()> {
+    let (file1, file2) = load_subtitle_pair(path1, path2)?;
+    let model = CostModel {
+        offset: Some(native_offset(&file1, &file2, offset)),
+        ..CostModel::default()
+    };
+    match format {
+        Format::Ass => print!("{}", AssFile::bilingual(&file1, &file2, &model).to_string()),
+        _ => print!("{}", combine_files_with(&file1, &file2, &model).to_string_as(format)),
+    }
+    Ok(())
+}
+
+/// Print a single aligned group on one line.
+fn print_aligned_group(group: &AlignedGroup) {
+    let text = |sub: &Option<Subtitle>| {
+        sub.as_ref().map(|s| s.plain_text()).unwrap_or_else(|| "-".to_owned())
+    };
+    println!(
+        "{}  {:.2}  {:<11}  {} | {}",
+        seconds_to_hhmmss(group.period().begin()),
+        group.confidence,
+        group.reason.to_string(),
+        text(&group.pair.0),
+        text(&group.pair.1)
+    );
+}
+
+fn cmd_align(
+    path1: &Path,
+    path2: Option<&Path>,
+    offset: Option<f32>,
+    report: bool,
+    threshold: f32,
+) -> Result<()> {
+    let (file1, file2) = load_subtitle_pair(path1, path2)?;
+    let model = CostModel {
+        offset: Some(native_offset(&file1, &file2, offset)),
+        ..CostModel::default()
+    };
+    let groups = align_files_scored(&file1, &file2, &model);
+    if !report {
+        for group in &groups {
+            print_aligned_group(group);
+        }
+        return Ok(());
+    }
+
+    let regions = low_confidence_regions(&groups, threshold);
+    for region in &regions {
+        let first = region[0].period();
+        let last = region[region.len() - 1].period();
+        println!(
+            "{}-{}: {} low-confidence group(s)",
+            seconds_to_hhmmss(first.begin()),
+            seconds_to_hhmmss(last.end()),
+            region.len()
+        );
+        for group in region.iter() {
+            print!("  ");
+            print_aligned_group(group);
+        }
+        println!();
+    }
+    let flagged: usize = regions.iter().map(|r| r.len()).sum();
+    println!(
+        "{} of {} groups have confidence below {}",
+        flagged,
+        groups.len(),
+        threshold
+    );
+    Ok(())
+}
+
+/// Parse a positive number or a ratio like "25/23.976".
+fn parse_ratio(s: &str) -> result::Result<f32, String> {
+    let parse = |n: &str| f32::from_str(n.trim()).map_err(|e| e.to_string());
+    let ratio = match s.find('/') {
+        Some(i) => parse(&s[..i])? / parse(&s[i + 1..])?,
+        None => parse(s)?,
+    };
+    if !ratio.is_finite() || ratio <= 0.0 {
+        return Err(format!("expected a positive number, found {:?}", s));
+    }
+    Ok(ratio)
+}
+
+/// Parse a sync point like "12=0:01:02.5".
+fn parse_sync_point(s: &str) -> result::Result<(usize, f32), String> {
+    let i = s.find('=')
+        .ok_or_else(|| format!("expected N=TIME, found {:?}", s))?;
+    let index = usize::from_str(s[..i].trim()).map_err(|e| e.to_string())?;
+    let time = parse_hhmmss(&s[i + 1..]).map_err(|e| e.to_string())?;
+    Ok((index, time))
+}
+
+fn cmd_retime(
+    path: &Path,
+    offset: Option<f32>,
+    scale: Option<f32>,
+    sync: &[(usize, f32)],
+    format: Format,
+) -> Result<()> {
+    let file = SubtitleFile::from_path(path)?;
+    if !sync.is_empty() && (offset.is_some() || scale.is_some()) {
+        return Err(format_err!("--sync cannot be used with --offset or --scale"));
+    }
+    let retiming = match sync.len() {
+        0 => Retiming::new(scale.unwrap_or(1.0), offset.unwrap_or(0.0)),
+        1 => {
+            let (index, time) = sync[0];
+            let sub = file.find(index)
+                .ok_or_else(|| format_err!("no subtitle with index {}", index))?;
+            Retiming::offset(time - sub.period.begin())
+        }
+        2 => file.fit_retiming(sync[0], sync[1])?,
+        _ => return Err(format_err!("--sync may only be specified twice")),
+    };
+    print!("{}", file.retime(retiming)?.to_string_as(format));
+    Ok(())
+}
+
+fn cmd_sync(
+    video_path: &Path,
+    sub_path: &Path,
+    max_offset: f32,
+    audio_stream: Option<usize>,
+    format: Format,
+) -> Result<()> {
+    let mut video = video::Video::new(video_path)?;
+    if let Some(index) = audio_stream {
+        video.set_audio_stream(index)?;
+    }
+    let file = SubtitleFile::from_path(sub_path)?;
+    let stream = video.audio_for(file.detect_language());
+    let options = SyncOptions {
+        max_offset: max_offset,
+        ..SyncOptions::default()
+    };
+    let (sync, synced) = sync_to_video(&file, &video, stream, &options)?;
+    eprintln!(
+        "Shifted subtitles by {:.3}s, scaled by {:.5}, with {} drift corrections",
+        sync.retiming.offset,
+        sync.retiming.scale,
+        sync.drift.len()
+    );
+    print!("{}", synced.to_string_as(format));
     Ok(())
 }
 
@@ -199,35 +805,310 @@ fn cmd_tracks(path: &Path) -> Result<()> {
     let v = video::Video::new(path)?;
     for stream in v.streams() {
         let lang = stream.language();
-        let lang_str = lang.map(|l| l.as_str().to_owned())
+        let lang_str = lang.map(|l| l.to_string())
             .unwrap_or("??".to_owned());
-        println!("#{} {} {:?}", stream.index, &lang_str, stream.codec_type);
+        let mut details = vec![];
+        if let Some(channels) = stream.channel_description() {
+            details.push(channels);
+        }
+        let flags = [
+            (stream.disposition.default, "default"),
+            (stream.is_commentary(), "commentary"),
+            (stream.is_audio_description(), "audio description"),
+            (stream.is_forced(), "forced"),
+            (stream.is_sdh(), "SDH"),
+        ];
+        for &(set, name) in &flags {
+            if set {
+                details.push(name.to_owned());
+            }
+        }
+        if let Some(title) = stream.title() {
+            details.push(format!("{:?}", title));
+        }
+        if details.is_empty() {
+            println!("#{} {} {:?}", stream.index, &lang_str, stream.codec_type);
+        } else {
+            println!(
+                "#{} {} {:?} ({})",
+                stream.index,
+                &lang_str,
+                stream.codec_type,
+                details.join(", ")
+            );
+        }
     }
     Ok(())
 }
 
 fn cmd_export(
-    kind: &str,
     video_path: &Path,
-    foreign_sub_path: &Path,
-    native_sub_path: Option<&Path>,
+    foreign: SubtitleSource,
+    native: Option<SubtitleSource>,
+    args: &ExportArgs,
 ) -> Result<()> {
+    let mut exporter = load_exporter(video_path, foreign, native, args, None)?;
+    run_exporter(args.kind, &mut exporter)
+}
+
+/// Load a video and its subtitles, and prepare to export them. If `dir` is
+/// specified, we'll write our output there, instead of using a new
+/// directory named after the video and the export format.
+fn load_exporter(
+    video_path: &Path,
+    foreign: SubtitleSource,
+    native: Option<SubtitleSource>,
+    args: &ExportArgs,
+    dir: Option<&Path>,
+) -> Result<export::Exporter> {
     // Load our input files.
-    let video = video::Video::new(video_path)?;
-    let foreign_subs = SubtitleFile::cleaned_from_path(foreign_sub_path)?;
-    let native_subs = match native_sub_path {
-        None => None,
-        Some(p) => Some(SubtitleFile::cleaned_from_path(p)?),
+    let text_only = args.text_only()?;
+    let mut video = video::Video::new(video_path)?;
+    if let Some(index) = args.audio_stream {
+        video.set_audio_stream(index)?;
+    }
+    let out_dir = match dir {
+        Some(dir) => dir.to_owned(),
+        None => export::Exporter::default_dir(&video, args.kind.name()),
+    };
+    let (foreign_subs, bundle_native_subs) = match foreign {
+        SubtitleSource::File(path) => load_subtitles(path, None)?,
+        SubtitleSource::Embedded(_) => {
+            (clean_subtitle_file(&foreign.read(&video, &out_dir)?)?, None)
+        }
+    };
+    let native_subs = match (native, text_only) {
+        (None, _) => bundle_native_subs,
+        (Some(source), None) => Some(clean_subtitle_file(&source.read(&video, &out_dir)?)?),
+        (Some(source), Some(dictionary_path)) => {
+            // Don't clean these subtitles, because cleaning relies on the
+            // timing we want to ignore.
+            let untimed = source.read(&video, &out_dir)?;
+            let dictionary = match dictionary_path {
+                Some(path) => Some(Arc::new(Dictionary::from_path(path)?)),
+                None => None,
+            };
+            let model = CostModel::text_only(&foreign_subs, &untimed, dictionary);
+            Some(copy_timing(&foreign_subs, &untimed, &model)?)
+        }
+    };
+
+    let offset = match text_only {
+        Some(_) => native_subs.as_ref().map(|_| 0.0),
+        None => native_subs
+            .as_ref()
+            .map(|native| native_offset(&foreign_subs, native, args.native_offset)),
     };
 
-    let mut exporter = export::Exporter::new(video, foreign_subs, native_subs, kind)?;
+    let mut exporter = if let Some(dir) = dir {
+        export::Exporter::in_dir(video, foreign_subs, native_subs, dir)?
+    } else if args.resume {
+        export::Exporter::resume(video, foreign_subs, native_subs, args.kind.name())?
+    } else {
+        export::Exporter::new(video, foreign_subs, native_subs, args.kind.name())?
+    };
+    if let Some(offset) = offset {
+        exporter.set_native_offset(offset);
+    }
+    if let Some(jobs) = args.jobs {
+        exporter.set_jobs(jobs);
+    }
+    exporter.set_options(args.options);
+    Ok(exporter)
+}
+
+/// Read the best embedded subtitle stream in `video` for `lang`. DVD
+/// subtitles are images, so we extract them to `dir` for use with an OCR
+/// tool instead.
+fn embedded_subtitles(video: &video::Video, lang: Lang, dir: &Path) -> Result<SubtitleFile> {
+    let index = video.subtitles_for(Some(lang)).ok_or_else(|| {
+        format_err!("Could not find {} subtitles in {}", lang, video.path().display())
+    })?;
+    let stream = video
+        .streams()
+        .iter()
+        .find(|s| s.index == index)
+        .expect("subtitles_for should return a valid stream");
+    if stream.is_text_subtitle() {
+        return video.subtitles(index);
+    }
+
+    let codec = stream.codec_name.as_ref().map(|n| &n[..]).unwrap_or("unknown");
+    if codec == "dvd_subtitle" {
+        fs::create_dir_all(dir).io_write_context(dir)?;
+        let idx_path = dir.join(format!(
+            "{}.{}.idx",
+            video.file_stem().to_string_lossy(),
+            lang
+        ));
+        video.extract_vobsub(index, &idx_path)?;
+        Err(format_err!(
+            "The {} subtitles in {} are images, not text. They have been \
+             extracted to {}, which can be converted to *.srt using an OCR \
+             tool like subtitles2srt. Then export the *.srt file using \
+             --resume",
+            lang,
+            video.path().display(),
+            idx_path.display()
+        ))
+    } else {
+        Err(format_err!(
+            "The {} subtitles in {} are {} images, not text",
+            lang,
+            video.path().display(),
+            codec
+        ))
+    }
+}
+
+/// Run the exporter for `kind`.
+fn run_exporter(kind: ExportKind, exporter: &mut export::Exporter) -> Result<()> {
     match kind {
-        "csv" => export::export_csv(&mut exporter)?,
-        "review" => export::export_review(&mut exporter)?,
-        "tracks" => export::export_tracks(&mut exporter)?,
-        _ => panic!("Uknown export type: {}", kind),
+        ExportKind::Csv => export::export_csv(exporter)?,
+        ExportKind::Apkg => export::export_apkg(exporter)?,
+        ExportKind::Aligned => export::export_aligned(exporter)?,
+        ExportKind::Review => export::export_review(exporter)?,
+        ExportKind::Tracks => export::export_tracks(exporter)?,
+    }
+
+    Ok(())
+}
+
+/// Export every video in `dir` which has subtitles in `--foreign-lang`.
+fn cmd_batch(dir: &Path, args: &ExportArgs) -> Result<()> {
+    let foreign_lang = args.foreign_lang.ok_or_else(|| {
+        format_err!("please specify --foreign-lang when exporting a directory")
+    })?;
+    let native_lang = if args.kind.uses_native_subs() {
+        args.native_lang
+    } else {
+        None
+    };
+    let episodes = batch::find_episodes(dir, foreign_lang, native_lang)?;
+    if episodes.is_empty() {
+        return Err(format_err!(
+            "Could not find any videos with {} subtitles in {}",
+            foreign_lang,
+            dir.display()
+        ));
+    }
+
+    // Export everything except CSV files one episode at a time.
+    if args.kind != ExportKind::Csv {
+        for episode in &episodes {
+            eprintln!("Exporting {}", episode.video.display());
+            cmd_export(
+                &episode.video,
+                SubtitleSource::File(&episode.foreign_subs),
+                episode.native_subs.as_ref().map(|p| SubtitleSource::File(p)),
+                args,
+            )?;
+        }
+        return Ok(());
+    }
+
+    // Combine our CSV exports into a single deck, in a directory named
+    // after `dir`.
+    let full_dir = dir.canonicalize().io_read_context(dir)?;
+    let name = full_dir
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "batch".to_owned());
+    let out_dir = Path::new("./").join(format!("{}_csv", name));
+    if !args.resume && fs::metadata(&out_dir).is_ok() {
+        return Err(format_err!(
+            "Directory already exists: {}",
+            out_dir.display()
+        ));
+    }
+    let mut exporters = vec![];
+    for episode in &episodes {
+        exporters.push(load_exporter(
+            &episode.video,
+            SubtitleSource::File(&episode.foreign_subs),
+            episode.native_subs.as_ref().map(|p| SubtitleSource::File(p)),
+            args,
+            Some(&out_dir),
+        )?);
+    }
+    export::export_csv_combined(&mut exporters)
+}
+
+fn cmd_bundle_split(bundle_path: &Path, out_dir: &Path, format: Format) -> Result<()> {
+    let metadata = bundle::load_metadata(bundle_path)?;
+    // Name our output files after the bundle directory, minus `.aligned`.
+    let bundle_dir = if bundle_path.is_dir() {
+        bundle_path
+    } else {
+        bundle_path.parent().unwrap_or_else(|| Path::new("."))
+    };
+    let stem = bundle_dir
+        .canonicalize()
+        .io_read_context(bundle_dir)?
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "subtitles".to_owned());
+
+    let files = bundle::subtitles_by_language(&metadata).io_read_context(bundle_path)?;
+    if files.is_empty() {
+        let err: Error = format_err!("aligned media contains no timed text");
+        Err(err).io_read_context(bundle_path)?;
+    }
+    fs::create_dir_all(out_dir).io_write_context(out_dir)?;
+    for (lang, subs) in files {
+        let path = out_dir.join(format!("{}.{}.{}", stem, lang, format.extension()));
+        let mut f = fs::File::create(&path).io_write_context(&path)?;
+        f.write_all(subs.to_string_as(format).as_bytes())
+            .io_write_context(&path)?;
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+fn cmd_bundle_create(
+    video_path: &Path,
+    sub_paths: &[PathBuf],
+    out_dir: Option<&Path>,
+    langs: &[Lang],
+) -> Result<()> {
+    if langs.len() > sub_paths.len() {
+        return Err(format_err!(
+            "got {} languages for {} subtitle files",
+            langs.len(),
+            sub_paths.len()
+        ));
+    }
+    let video_name = video_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| format_err!("invalid video path: {}", video_path.display()))?;
+    let dir = match out_dir {
+        Some(dir) => dir.to_owned(),
+        None => Path::new(video_name).with_extension("aligned"),
+    };
+
+    let mut files = vec![];
+    for (i, path) in sub_paths.iter().enumerate() {
+        let subs = SubtitleFile::cleaned_from_path(path)?;
+        let lang = langs
+            .get(i)
+            .cloned()
+            .or_else(|| subs.detect_language())
+            .or_else(|| batch::lang_from_file_name(path))
+            .ok_or_else(|| {
+                format_err!(
+                    "could not detect the language of {}; try --lang",
+                    path.display()
+                )
+            })?;
+        files.push((lang, subs));
     }
+    let metadata = bundle::metadata_from_subtitles(video_name, &files)?;
 
+    fs::create_dir_all(&dir).io_write_context(&dir)?;
+    bundle::link_or_copy(video_path, &dir.join(video_name))?;
+    bundle::write_metadata(&dir, &metadata)?;
+    println!("{}", dir.display());
     Ok(())
 }
 
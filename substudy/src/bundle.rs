@@ -0,0 +1,409 @@
+//! Converting between [aligned media][spec] bundles, such as those written
+//! by `export aligned`, and ordinary subtitle files.
+//!
+//! [spec]: https://github.com/language-learners/aligned-media-spec
+
+use aligned_media::{Alignment, Metadata, TimeSpan, Track, TrackType};
+use aligned_media::html::Fragment;
+use common_failures::prelude::*;
+use isolang;
+use serde_json;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use align::estimate_offset;
+use lang::Lang;
+use srt::{Subtitle, SubtitleFile};
+use time::{Period, Retiming};
+
+/// The name of the metadata file in an aligned media bundle.
+const METADATA_FILE_NAME: &str = "metadata.json";
+
+/// Given either a bundle directory or a `metadata.json` file, return the
+/// path to `metadata.json`, or `None` if `path` doesn't look like a bundle.
+fn metadata_path(path: &Path) -> Option<PathBuf> {
+    if path.is_dir() {
+        let metadata_path = path.join(METADATA_FILE_NAME);
+        if metadata_path.is_file() {
+            return Some(metadata_path);
+        }
+    } else if path.file_name().map_or(false, |n| n == METADATA_FILE_NAME) {
+        return Some(path.to_owned());
+    }
+    None
+}
+
+/// Does `path` point to an aligned media bundle (either a directory
+/// containing `metadata.json`, or the `metadata.json` file itself)?
+pub fn is_bundle(path: &Path) -> bool {
+    metadata_path(path).is_some()
+}
+
+/// Load the metadata for the aligned media bundle at `path`, which may be
+/// either a directory or a `metadata.json` file.
+pub fn load_metadata(path: &Path) -> Result<Metadata> {
+    let path = metadata_path(path).unwrap_or_else(|| path.join(METADATA_FILE_NAME));
+    let mut file = File::open(&path).io_read_context(&path)?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes).io_read_context(&path)?;
+    Ok(Metadata::from_bytes(&bytes).io_read_context(&path)?)
+}
+
+/// Convert an HTML fragment to subtitle lines, keeping formatting where SRT
+/// allows it.
+///
+/// ```
+/// use substudy::bundle::fragment_to_lines;
+///
+/// let fragment = "<i>Jean &amp; Luc:</i><br>On y va !".parse().unwrap();
+/// assert_eq!(
+///     fragment_to_lines(&fragment),
+///     vec!["<i>Jean & Luc:</i>", "On y va !"],
+/// );
+/// ```
+pub fn fragment_to_lines(fragment: &Fragment) -> Vec<String> {
+    fragment
+        .to_srt()
+        .lines()
+        .map(|l| l.trim().to_owned())
+        .filter(|l| !l.is_empty())
+        .collect()
+}
+
+/// Write `metadata` to `metadata.json` in the bundle directory `dir`.
+pub fn write_metadata(dir: &Path, metadata: &Metadata) -> Result<()> {
+    let path = dir.join(METADATA_FILE_NAME);
+    let json = serde_json::to_vec_pretty(metadata)
+        .with_context(|_| format_err!("error serializing to RAM"))?;
+    let mut f = File::create(&path).io_write_context(&path)?;
+    f.write_all(&json).io_write_context(&path)?;
+    Ok(())
+}
+
+/// Hard-link `src` to `dest` so we can include it in a bundle without
+/// using extra disk space, or copy it if we can't link.
+pub fn link_or_copy(src: &Path, dest: &Path) -> Result<()> {
+    // If `dest` is left over from an earlier run, it may be a link to `src`,
+    // and copying a file onto itself would truncate it.
+    if dest.exists() {
+        fs::remove_file(dest).io_write_context(dest)?;
+    }
+    if fs::hard_link(src, dest).is_err() {
+        fs::copy(src, dest).io_write_context(dest)?;
+    }
+    Ok(())
+}
+
+/// Build an HTML track for a subtitle, keeping any formatting that we can
+/// parse, and falling back to plain text otherwise.
+pub(crate) fn subtitle_track(sub: &Subtitle, lang: Option<isolang::Language>) -> Track {
+    let html = sub.lines
+        .join("<br>")
+        .parse::<Fragment>()
+        .unwrap_or_else(|_| Fragment::from_text(sub.plain_text()));
+    let mut track = Track::with_type(TrackType::Html);
+    track.lang = lang;
+    track.html = Some(html);
+    track
+}
+
+/// Find the languages of all HTML tracks in `metadata`, in the order in
+/// which we first see them.
+fn html_languages(metadata: &Metadata) -> Vec<isolang::Language> {
+    let mut langs = vec![];
+    for alignment in &metadata.alignments {
+        for track in &alignment.tracks {
+            if track.track_type == TrackType::Html {
+                if let Some(lang) = track.lang {
+                    if !langs.contains(&lang) {
+                        langs.push(lang);
+                    }
+                }
+            }
+        }
+    }
+    langs
+}
+
+/// Build a subtitle file from the HTML tracks in `lang`.
+fn subtitles_for(metadata: &Metadata, lang: isolang::Language) -> Result<SubtitleFile> {
+    let mut subtitles = vec![];
+    for alignment in &metadata.alignments {
+        let span = match alignment.time_span() {
+            Some(span) => span,
+            None => continue,
+        };
+        let html = alignment
+            .tracks
+            .iter()
+            .filter(|t| t.track_type == TrackType::Html && t.lang == Some(lang))
+            .filter_map(|t| t.html.as_ref())
+            .next();
+        if let Some(html) = html {
+            let lines = fragment_to_lines(html);
+            if !lines.is_empty() {
+                let index = subtitles.len() + 1;
+                subtitles.push(Subtitle {
+                    index: index,
+                    period: Period::new(span.begin(), span.end())?,
+                    lines: lines,
+                });
+            }
+        }
+    }
+    Ok(SubtitleFile { subtitles: subtitles })
+}
+
+/// Extract a foreign-language and (if available) a native-language subtitle
+/// file from `metadata`. If `foreign` is not specified, we use the language
+/// of the base track, or the first language we find. If `native` is not
+/// specified, we use the first other language we find.
+pub fn subtitles_from_metadata(
+    metadata: &Metadata,
+    foreign: Option<Lang>,
+    native: Option<Lang>,
+) -> Result<(SubtitleFile, Option<SubtitleFile>)> {
+    if metadata.alignments.iter().all(|a| a.time_span().is_none()) {
+        return Err(format_err!("aligned media contains no timed alignments"));
+    }
+
+    let to_iso = |lang: Lang| {
+        lang.to_isolang()
+            .ok_or_else(|| format_err!("unsupported language: {}", lang))
+    };
+    let langs = html_languages(metadata);
+    let foreign_iso = match foreign {
+        Some(lang) => to_iso(lang)?,
+        None => metadata
+            .base_track
+            .as_ref()
+            .and_then(|t| t.lang)
+            .or_else(|| langs.first().cloned())
+            .ok_or_else(|| format_err!("aligned media contains no text"))?,
+    };
+    let native_iso = match native {
+        Some(lang) => Some(to_iso(lang)?),
+        None => langs.iter().cloned().find(|&l| l != foreign_iso),
+    };
+
+    let foreign_subs = subtitles_for(metadata, foreign_iso)?;
+    if foreign_subs.subtitles.is_empty() {
+        let lang = Lang::from_isolang(foreign_iso);
+        return Err(format_err!("aligned media contains no text in {}", lang));
+    }
+    let native_subs = match native_iso {
+        Some(lang) => Some(subtitles_for(metadata, lang)?),
+        None => None,
+    };
+    Ok((foreign_subs, native_subs))
+}
+
+/// Extract a subtitle file for every language in `metadata`, in the order
+/// in which we first see each language.
+pub fn subtitles_by_language(metadata: &Metadata) -> Result<Vec<(Lang, SubtitleFile)>> {
+    let mut result = vec![];
+    for lang in html_languages(metadata) {
+        let subs = subtitles_for(metadata, lang)?;
+        if !subs.subtitles.is_empty() {
+            result.push((Lang::from_isolang(lang), subs));
+        }
+    }
+    Ok(result)
+}
+
+/// Adjust `periods`, which must be sorted by start time, so that no two of
+/// them overlap, because the time spans of aligned media alignments may not
+/// overlap. Overlapping periods are split halfway through the overlap.
+pub(crate) fn remove_overlaps(periods: &mut [Period]) {
+    for i in 1..periods.len() {
+        let (prev, curr) = (periods[i - 1], periods[i]);
+        if curr.begin() < prev.end() {
+            let boundary = (curr.begin() + prev.end().min(curr.end())) / 2.0;
+            periods[i - 1] = Period::new(prev.begin(), boundary).unwrap_or(prev);
+            periods[i] = Period::new(boundary, curr.end()).unwrap_or(curr);
+        }
+    }
+}
+
+#[test]
+fn remove_overlapping_periods() {
+    let p = |begin, end| Period::new(begin, end).unwrap();
+    let mut periods = vec![p(1.0, 3.0), p(2.0, 5.0), p(4.0, 4.5), p(6.0, 7.0)];
+    remove_overlaps(&mut periods);
+    assert_eq!(
+        periods,
+        vec![p(1.0, 2.5), p(2.5, 4.25), p(4.25, 4.5), p(6.0, 7.0)]
+    );
+}
+
+/// Build aligned media metadata for the media file `media_file` (a path
+/// relative to the bundle) from one or more subtitle files, each in a
+/// different language.
+///
+/// We create one alignment for each subtitle in the first file. Each
+/// subtitle in the other files is attached to the alignment it overlaps
+/// most, after correcting for any constant offset between the files.
+/// Subtitles which don't overlap anything get alignments of their own.
+pub fn metadata_from_subtitles(
+    media_file: &str,
+    files: &[(Lang, SubtitleFile)],
+) -> Result<Metadata> {
+    let base = match files.first() {
+        Some(&(_, ref file)) => file,
+        None => return Err(format_err!("no subtitle files to bundle")),
+    };
+    let mut isos = vec![];
+    for &(lang, _) in files {
+        let iso = lang.to_isolang()
+            .ok_or_else(|| format_err!("unsupported language: {}", lang))?;
+        if isos.contains(&iso) {
+            return Err(format_err!("more than one subtitle file in {}", lang));
+        }
+        isos.push(iso);
+    }
+
+    // Each alignment starts out with the subtitles from our base file.
+    let mut alignments: Vec<(Period, Vec<Track>)> = base.subtitles
+        .iter()
+        .map(|sub| (sub.period, vec![subtitle_track(sub, Some(isos[0]))]))
+        .collect();
+    let mut unmatched = vec![];
+
+    for (&(_, ref file), &iso) in files[1..].iter().zip(&isos[1..]) {
+        let shift = Retiming::offset(-estimate_offset(base, file));
+        let mut matched: Vec<Vec<&Subtitle>> = vec![vec![]; base.subtitles.len()];
+        for sub in &file.subtitles {
+            let period = sub.period.retime(shift).unwrap_or(sub.period);
+            let best = base.subtitles
+                .iter()
+                .enumerate()
+                .map(|(i, b)| (i, b.period.overlap(period)))
+                .filter(|&(_, overlap)| overlap > 0.0)
+                .fold(None, |best: Option<(usize, f32)>, candidate| match best {
+                    Some(b) if b.1 >= candidate.1 => Some(b),
+                    _ => Some(candidate),
+                });
+            match best {
+                Some((i, _)) => matched[i].push(sub),
+                None => unmatched.push((period, vec![subtitle_track(sub, Some(iso))])),
+            }
+        }
+        for (subs, alignment) in matched.iter().zip(alignments.iter_mut()) {
+            if subs.is_empty() {
+                continue;
+            }
+            // Merge everything that matched into a single subtitle.
+            let merged = Subtitle {
+                index: subs[0].index,
+                period: subs[0].period,
+                lines: subs.iter().flat_map(|s| s.lines.iter().cloned()).collect(),
+            };
+            alignment.1.push(subtitle_track(&merged, Some(iso)));
+        }
+    }
+    alignments.extend(unmatched);
+    alignments.sort_by(|a, b| {
+        a.0.begin().partial_cmp(&b.0.begin()).expect("time should never be NaN")
+    });
+
+    let mut periods: Vec<Period> = alignments.iter().map(|a| a.0).collect();
+    remove_overlaps(&mut periods);
+
+    let mut builder = Metadata::builder();
+    builder.base_track(Track::media(Some(isos[0]), media_file)?);
+    for (period, (_, tracks)) in periods.into_iter().zip(alignments) {
+        let mut alignment = Alignment::new(TimeSpan::new(period.begin(), period.end())?);
+        for track in tracks {
+            alignment.add_track(track);
+        }
+        builder.alignment(alignment);
+    }
+    Ok(builder.build()?)
+}
+
+/// Load a foreign-language and (if available) a native-language subtitle
+/// file from the aligned media bundle at `path`.
+pub fn load_subtitles(
+    path: &Path,
+    foreign: Option<Lang>,
+    native: Option<Lang>,
+) -> Result<(SubtitleFile, Option<SubtitleFile>)> {
+    let metadata = load_metadata(path)?;
+    Ok(subtitles_from_metadata(&metadata, foreign, native).io_read_context(path)?)
+}
+
+#[test]
+fn load_subtitles_from_bundle() {
+    let path = Path::new("../aligned_media/fixtures/examples/subtitle_example.aligned");
+    assert!(is_bundle(path));
+    let (foreign, native) = load_subtitles(path, None, None).unwrap();
+    assert_eq!(foreign.subtitles.len(), 1);
+    assert_eq!(foreign.subtitles[0].period, Period::new(10.0, 15.5).unwrap());
+    assert_eq!(foreign.subtitles[0].lines, vec!["<i>Jean & Luc:</i> On y va !"]);
+    let native = native.expect("should have native subtitles");
+    assert_eq!(native.subtitles[0].lines, vec!["<i>Jean & Luc:</i> Let's go!"]);
+
+    // We can also ask for languages explicitly.
+    let en = Lang::iso639("en").unwrap();
+    let (foreign, native) = load_subtitles(path, Some(en), None).unwrap();
+    assert_eq!(foreign.subtitles[0].plain_text(), "Jean & Luc: Let's go!");
+    assert!(native.is_some());
+    assert!(load_subtitles(path, Some(Lang::iso639("de").unwrap()), None).is_err());
+}
+
+#[test]
+fn subtitle_track_keeps_formatting() {
+    let sub = Subtitle {
+        index: 1,
+        period: Period::new(1.0, 2.0).unwrap(),
+        lines: vec!["<i>Jean & Luc:</i>".to_owned(), "On y va !".to_owned()],
+    };
+    // `&` isn't valid HTML, so we fall back to plain text.
+    let track = subtitle_track(&sub, Some(isolang::Language::Fra));
+    assert_eq!(
+        format!("{}", track.html.unwrap()),
+        "Jean &amp; Luc: On y va !"
+    );
+
+    let sub = Subtitle {
+        lines: vec!["<i>Jean &amp; Luc:</i>".to_owned(), "On y va !".to_owned()],
+        ..sub
+    };
+    let track = subtitle_track(&sub, Some(isolang::Language::Fra));
+    assert_eq!(
+        format!("{}", track.html.unwrap()),
+        "<i>Jean &amp; Luc:</i><br>On y va !"
+    );
+}
+
+#[test]
+fn bundle_and_split_subtitles() {
+    let load = |path: &str| SubtitleFile::from_path(Path::new(path)).unwrap();
+    let (es, en) = (Lang::iso639("es").unwrap(), Lang::iso639("en").unwrap());
+    let late_en = load("fixtures/sample.en.srt")
+        .retime(Retiming::offset(3.0))
+        .unwrap();
+    let files = vec![(es, load("fixtures/sample.es.srt")), (en, late_en)];
+    let metadata = metadata_from_subtitles("empty.mp4", &files).unwrap();
+    // One alignment per Spanish subtitle, plus "<i>cheering</i>", which
+    // doesn't overlap anything.
+    assert_eq!(metadata.alignments.len(), 6);
+    assert_eq!(metadata.base_track.as_ref().unwrap().file().unwrap().as_str(), "empty.mp4");
+
+    let split = subtitles_by_language(&metadata).unwrap();
+    assert_eq!(split.len(), 2);
+    assert!(split[0].0 == es && split[1].0 == en);
+    let spanish = &split[0].1.subtitles;
+    assert_eq!(spanish.len(), 5);
+    assert_eq!(spanish[0].lines, vec!["¡Si! ¡Aang ha vuelto!"]);
+    // The English subtitles have been merged to match the Spanish ones, and
+    // use the Spanish timing.
+    let english = &split[1].1.subtitles;
+    assert_eq!(english.len(), 6);
+    assert_eq!(english[0].period, spanish[0].period);
+    assert_eq!(english[0].lines, vec!["Yay!", "Yay!", "Aang's back!"]);
+    assert_eq!(english[1].lines, vec!["<i>cheering</i>"]);
+
+    assert!(metadata_from_subtitles("empty.mp4", &[]).is_err());
+}
@@ -6,9 +6,15 @@ use regex::Regex;
 use srt::{Subtitle, SubtitleFile};
 use std::borrow::Cow;
 
-/// Remove the formatting from a subtitle.
+/// Remove the formatting from a subtitle, including both HTML-style tags and
+/// ASS-style override tags like `{\i1}`.
+///
+/// ```
+/// use substudy::clean::strip_formatting;
+/// assert_eq!("Hey, wait!", strip_formatting("<i>Hey,</i> {\\b1}wait!{\\b0}"));
+/// ```
 pub fn strip_formatting(line: &str) -> Cow<str> {
-    let formatting = Regex::new(r"<[a-z/][^>]*>").unwrap();
+    let formatting = Regex::new(r"<[a-z/][^>]*>|\{\\[^}]*\}").unwrap();
     formatting.replace_all(&line, "")
 }
 
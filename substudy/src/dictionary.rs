@@ -0,0 +1,125 @@
+//! Simple bilingual dictionaries, used as hints when aligning subtitles.
+
+use common_failures::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use decode::smart_decode;
+
+/// A bilingual dictionary mapping words in one language to possible
+/// translations in another.
+#[derive(Debug, Default)]
+pub struct Dictionary {
+    translations: HashMap<String, HashSet<String>>,
+}
+
+impl Dictionary {
+    /// Parse a dictionary containing one `word<TAB>translation` pair per
+    /// line. A word may appear on several lines if it has more than one
+    /// translation. Blank lines and lines starting with `#` are ignored.
+    /// Everything is converted to lowercase as we load it.
+    ///
+    /// ```
+    /// use substudy::dictionary::Dictionary;
+    ///
+    /// let dict = Dictionary::from_str("# es\ten\nSabía\tknew\nsabía\tknow\n").unwrap();
+    /// assert!(dict.translates("sabía", "knew"));
+    /// assert!(!dict.translates("sabía", "Aang"));
+    /// ```
+    pub fn from_str(data: &str) -> Result<Dictionary> {
+        let mut dict = Dictionary::default();
+        for (line_no, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.splitn(2, '\t');
+            match (fields.next(), fields.next()) {
+                (Some(word), Some(translation)) => {
+                    dict.translations
+                        .entry(word.trim().to_lowercase())
+                        .or_insert_with(HashSet::new)
+                        .insert(translation.trim().to_lowercase());
+                }
+                _ => {
+                    return Err(format_err!(
+                        "expected word and translation on line {} of dictionary",
+                        line_no + 1
+                    ));
+                }
+            }
+        }
+        Ok(dict)
+    }
+
+    /// Load a dictionary from a file.
+    pub fn from_path(path: &Path) -> Result<Dictionary> {
+        let mut file = File::open(path).io_read_context(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).io_read_context(path)?;
+        let data = smart_decode(&bytes).io_read_context(path)?;
+        Ok(Dictionary::from_str(&data).io_read_context(path)?)
+    }
+
+    /// Do we know any translations for `word`? Like all our lookups, this
+    /// expects a lowercase word, such as those returned by `words`.
+    pub fn contains(&self, word: &str) -> bool {
+        self.translations.contains_key(word)
+    }
+
+    /// Can the lowercase `word` be translated as the lowercase
+    /// `translation`?
+    pub fn translates(&self, word: &str, translation: &str) -> bool {
+        self.translations
+            .get(word)
+            .map_or(false, |t| t.contains(translation))
+    }
+
+    /// Return the fraction of the words in `words` which we know how to
+    /// translate that have a translation in `translations`, or `None` if we
+    /// don't know any of the words. Both are passed as lists of lowercase
+    /// words, one list per subtitle.
+    ///
+    /// ```
+    /// use substudy::dictionary::{words, Dictionary};
+    ///
+    /// let dict = Dictionary::from_str("sabía\tknew\nlo\tit\n").unwrap();
+    /// let (es, en) = (words("¡Lo sabía!"), words("I knew"));
+    /// assert_eq!(dict.match_fraction(&[&es], &[&en]), Some(0.5));
+    /// assert_eq!(dict.match_fraction(&[&en], &[&es]), None);
+    /// ```
+    pub fn match_fraction(&self, words: &[&[String]], translations: &[&[String]]) -> Option<f32> {
+        let (mut known, mut matched) = (0, 0);
+        for word in words.iter().flat_map(|ws| ws.iter()) {
+            if let Some(candidates) = self.translations.get(word) {
+                known += 1;
+                let found = translations
+                    .iter()
+                    .flat_map(|ts| ts.iter())
+                    .any(|t| candidates.contains(t));
+                if found {
+                    matched += 1;
+                }
+            }
+        }
+        if known == 0 {
+            return None;
+        }
+        Some(matched as f32 / known as f32)
+    }
+}
+
+/// Split text into lowercase words.
+///
+/// ```
+/// use substudy::dictionary::words;
+/// assert_eq!(vec!["lo", "sabía"], words("¡Lo sabía!"));
+/// ```
+pub fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
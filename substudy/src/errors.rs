@@ -1,10 +1,17 @@
 //! Error-handling for this library.
 
+use std::fmt;
+use std::process::{ExitStatus, Output};
+
+/// The maximum number of lines of `stderr` to include in an error message.
+const MAX_STDERR_LINES: usize = 10;
+
 /// An error occurred running an external command.
 #[derive(Debug, Fail)]
-#[fail(display = "error running external command {:?}", command)]
 pub struct RunCommandError {
     command: String,
+    status: Option<ExitStatus>,
+    stderr: Option<String>,
 }
 
 impl RunCommandError {
@@ -13,6 +20,19 @@ impl RunCommandError {
     pub(crate) fn new<S: Into<String>>(command: S) -> RunCommandError {
         RunCommandError {
             command: command.into(),
+            status: None,
+            stderr: None,
+        }
+    }
+
+    /// Create a new error for a command which ran, but which failed,
+    /// including its exit status and any error output.
+    pub(crate) fn from_output<S: Into<String>>(command: S, output: &Output) -> RunCommandError {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+        RunCommandError {
+            command: command.into(),
+            status: Some(output.status),
+            stderr: if stderr.is_empty() { None } else { Some(stderr) },
         }
     }
 
@@ -20,4 +40,31 @@ impl RunCommandError {
     pub fn command(&self) -> &str {
         &self.command
     }
+
+    /// The exit status of the command, if it ran.
+    pub fn status(&self) -> Option<ExitStatus> {
+        self.status
+    }
+
+    /// Anything the command printed to standard error before failing.
+    pub fn stderr(&self) -> Option<&str> {
+        self.stderr.as_ref().map(|s| &s[..])
+    }
+}
+
+impl fmt::Display for RunCommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error running external command {:?}", self.command)?;
+        if let Some(status) = self.status {
+            write!(f, " ({})", status)?;
+        }
+        if let Some(ref stderr) = self.stderr {
+            // Only show the end of the output, which is where the actual
+            // error message usually is.
+            let lines: Vec<&str> = stderr.lines().collect();
+            let start = lines.len().saturating_sub(MAX_STDERR_LINES);
+            write!(f, ":\n{}", lines[start..].join("\n"))?;
+        }
+        Ok(())
+    }
 }
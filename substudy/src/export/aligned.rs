@@ -0,0 +1,83 @@
+//! Export to the [aligned media format][spec], for use by other
+//! language-learning tools.
+//!
+//! [spec]: https://github.com/language-learners/aligned-media-spec
+
+use aligned_media::{Alignment, Metadata, Track, TimeSpan};
+use common_failures::prelude::*;
+
+use bundle::{link_or_copy, remove_overlaps, subtitle_track, write_metadata};
+use export::{os_str_to_string, Exporter};
+use time::Period;
+
+/// Export the video and subtitles as an aligned media bundle: a
+/// `metadata.json` file describing each aligned subtitle pair, plus images
+/// and audio clips for each pair.
+pub fn export_aligned(exporter: &mut Exporter) -> Result<()> {
+    let foreign_lang = exporter.foreign().language;
+    let foreign_iso = foreign_lang.and_then(|l| l.to_isolang());
+    let native_iso = exporter
+        .native()
+        .and_then(|n| n.language)
+        .and_then(|l| l.to_isolang());
+
+    // Our base track is the video itself, which we link into our bundle
+    // (or copy, if we can't link).
+    let video_path = exporter.video().path().to_owned();
+    let video_name = os_str_to_string(exporter.video().file_name());
+    link_or_copy(&video_path, &exporter.dir().join(&video_name))?;
+
+    let mut builder = Metadata::builder();
+    builder
+        .title(exporter.title())
+        .base_track(Track::media(foreign_iso, video_name)?);
+
+    // Align our input files, and figure out which time span to use for
+    // each pair. We extract media for the whole pair, but the time spans
+    // of our alignments may not overlap.
+    let aligned = exporter.align();
+    let periods: Vec<Period> = aligned
+        .iter()
+        .map(|&(ref foreign, ref native)| {
+            Period::from_union_opt(
+                foreign.as_ref().map(|s| s.period),
+                native.as_ref().map(|s| s.period),
+            ).expect("subtitle pair must not be empty")
+        })
+        .collect();
+    let mut spans = periods.clone();
+    remove_overlaps(&mut spans);
+
+    // Build an `Alignment` for each pair.
+    for ((&(ref foreign, ref native), &period), span) in
+        aligned.iter().zip(&periods).zip(spans)
+    {
+        let image_path = exporter.schedule_image_export(period);
+        let audio_path =
+            exporter.schedule_audio_export(foreign_lang, period.grow(0.01, 0.01));
+
+        let mut alignment =
+            Alignment::new(TimeSpan::new(span.begin(), span.end())?);
+        if let Some(ref foreign) = *foreign {
+            alignment.add_track(subtitle_track(foreign, foreign_iso));
+        }
+        if let Some(ref native) = *native {
+            alignment.add_track(subtitle_track(native, native_iso));
+        }
+        if exporter.options().image.format.is_video() {
+            alignment.add_track(Track::media(None, image_path)?);
+        } else {
+            alignment.add_track(Track::image(image_path)?);
+        }
+        alignment.add_track(Track::media(foreign_iso, audio_path)?);
+        builder.alignment(alignment);
+    }
+
+    // Write out our metadata.
+    write_metadata(exporter.dir(), &builder.build()?)?;
+
+    // Extract our media files.
+    exporter.finish_exports()?;
+
+    Ok(())
+}
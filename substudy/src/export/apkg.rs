@@ -0,0 +1,308 @@
+//! Exporting to Anki `*.apkg` packages, which can be imported in one step.
+//!
+//! An `*.apkg` file is a zip archive containing an SQLite database named
+//! `collection.anki2`, a JSON file named `media` which maps numbered file
+//! names to real media file names, and the numbered media files themselves.
+
+use common_failures::prelude::*;
+use regex::Regex;
+use rusqlite::Connection;
+use serde_json;
+use sha1::Sha1;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zip::ZipWriter;
+use zip::write::FileOptions;
+
+use export::Exporter;
+use export::csv::{anki_notes, AnkiNote, ANKI_NOTE_FIELDS};
+
+/// The ID of our note type. This is fixed so that repeated imports share a
+/// single note type instead of creating a new one each time. It must be
+/// changed whenever `ANKI_NOTE_FIELDS` changes.
+const MODEL_ID: i64 = 1_512_086_400_001;
+
+/// The index of the field we sort by (`Time`).
+const SORT_FIELD: usize = 1;
+
+/// The schema used by version 11 of the Anki collection format.
+const SCHEMA: &str = r#"
+CREATE TABLE col (
+    id integer primary key, crt integer not null, mod integer not null,
+    scm integer not null, ver integer not null, dty integer not null,
+    usn integer not null, ls integer not null, conf text not null,
+    models text not null, decks text not null, dconf text not null,
+    tags text not null
+);
+CREATE TABLE notes (
+    id integer primary key, guid text not null, mid integer not null,
+    mod integer not null, usn integer not null, tags text not null,
+    flds text not null, sfld integer not null, csum integer not null,
+    flags integer not null, data text not null
+);
+CREATE TABLE cards (
+    id integer primary key, nid integer not null, did integer not null,
+    ord integer not null, mod integer not null, usn integer not null,
+    type integer not null, queue integer not null, due integer not null,
+    ivl integer not null, factor integer not null, reps integer not null,
+    lapses integer not null, left integer not null, odue integer not null,
+    odid integer not null, flags integer not null, data text not null
+);
+CREATE TABLE revlog (
+    id integer primary key, cid integer not null, usn integer not null,
+    ease integer not null, ivl integer not null, lastIvl integer not null,
+    factor integer not null, time integer not null, type integer not null
+);
+CREATE TABLE graves (
+    usn integer not null, oid integer not null, type integer not null
+);
+CREATE INDEX ix_notes_usn on notes (usn);
+CREATE INDEX ix_cards_usn on cards (usn);
+CREATE INDEX ix_revlog_usn on revlog (usn);
+CREATE INDEX ix_cards_nid on cards (nid);
+CREATE INDEX ix_cards_sched on cards (did, queue, due);
+CREATE INDEX ix_revlog_cid on revlog (cid);
+CREATE INDEX ix_notes_csum on notes (csum);
+"#;
+
+/// The front of our cards.
+const FRONT_TEMPLATE: &str = r#"{{Sound}}
+<div class="image">{{Image}}</div>
+<div class="foreign">{{ForeignCurr}}</div>"#;
+
+/// The back of our cards.
+const BACK_TEMPLATE: &str = r#"{{FrontSide}}
+<hr id="answer">
+<div class="native">{{NativeCurr}}</div>
+<div class="context">
+  <div>{{ForeignPrev}} <i>{{NativePrev}}</i></div>
+  <div>{{ForeignNext}} <i>{{NativeNext}}</i></div>
+</div>
+<div class="source">{{Source}} {{Time}}</div>
+{{ContextSound}}"#;
+
+/// Styles for our cards.
+const CARD_CSS: &str = r#".card { font-family: sans-serif; font-size: 20px; text-align: center; }
+.foreign { font-size: 28px; margin: 0.5em 0; }
+.native { font-style: italic; }
+.context, .source { color: #888; font-size: 14px; margin-top: 1em; }"#;
+
+/// Strip HTML tags from a field, the way Anki does before checksumming it.
+fn strip_html(field: &str) -> String {
+    lazy_static! {
+        static ref TAG: Regex = Regex::new(r"<[^>]*>").unwrap();
+    }
+    TAG.replace_all(field, "").into_owned()
+}
+
+/// Compute the checksum Anki uses to find duplicate notes: the first 8
+/// hex digits of the SHA1 hash of the first field, without any HTML.
+fn field_checksum(field: &str) -> i64 {
+    let hex = Sha1::from(strip_html(field).as_bytes()).digest().to_string();
+    i64::from_str_radix(&hex[..8], 16).expect("SHA1 digest should be hex")
+}
+
+/// A stable, unique ID for a note, so that re-importing the same deck
+/// updates existing notes instead of duplicating them.
+fn note_guid(note: &AnkiNote) -> String {
+    let key = format!("substudy\x1f{}\x1f{}", &note.source, &note.time);
+    Sha1::from(key.as_bytes()).digest().to_string()[..16].to_owned()
+}
+
+/// Build the `models` JSON for our note type.
+fn models_json(deck_id: i64, now: i64) -> serde_json::Value {
+    let fields: Vec<serde_json::Value> = ANKI_NOTE_FIELDS
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            json!({
+                "name": name, "ord": i, "sticky": false, "rtl": false,
+                "font": "Arial", "size": 20, "media": [],
+            })
+        })
+        .collect();
+    json!({
+        MODEL_ID.to_string(): {
+            "id": MODEL_ID,
+            "name": "substudy",
+            "type": 0,
+            "mod": now,
+            "usn": -1,
+            "sortf": SORT_FIELD,
+            "did": deck_id,
+            "tmpls": [{
+                "name": "Listening",
+                "ord": 0,
+                "qfmt": FRONT_TEMPLATE,
+                "afmt": BACK_TEMPLATE,
+                "did": null,
+                "bqfmt": "",
+                "bafmt": "",
+            }],
+            "flds": fields,
+            "css": CARD_CSS,
+            "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\begin{document}\n",
+            "latexPost": "\\end{document}",
+            "tags": [],
+            "vers": [],
+            // Our card needs the `Sound` field to be non-empty.
+            "req": [[0, "any", [0]]],
+        }
+    })
+}
+
+/// Build the `decks` JSON, including Anki's mandatory default deck.
+fn decks_json(deck_id: i64, name: &str, now: i64) -> serde_json::Value {
+    let deck = |id: i64, name: &str| {
+        json!({
+            "id": id, "name": name, "desc": "", "mod": now, "usn": -1,
+            "collapsed": false, "newToday": [0, 0], "revToday": [0, 0],
+            "lrnToday": [0, 0], "timeToday": [0, 0], "dyn": 0, "conf": 1,
+            "extendNew": 10, "extendRev": 50,
+        })
+    };
+    json!({
+        "1": deck(1, "Default"),
+        deck_id.to_string(): deck(deck_id, name),
+    })
+}
+
+/// Build the `dconf` JSON containing Anki's default deck options.
+fn dconf_json(now: i64) -> serde_json::Value {
+    json!({
+        "1": {
+            "id": 1, "name": "Default", "mod": now, "usn": -1, "maxTaken": 60,
+            "autoplay": true, "timer": 0, "replayq": true, "dyn": false,
+            "new": {
+                "delays": [1, 10], "ints": [1, 4, 7], "initialFactor": 2500,
+                "order": 1, "perDay": 20, "bury": true, "separate": true,
+            },
+            "rev": {
+                "perDay": 100, "ease4": 1.3, "fuzz": 0.05, "maxIvl": 36500,
+                "bury": true, "minSpace": 1,
+            },
+            "lapse": {
+                "delays": [10], "mult": 0.0, "minInt": 1, "leechFails": 8,
+                "leechAction": 0,
+            },
+        }
+    })
+}
+
+/// Build the `conf` JSON containing Anki's collection settings. New cards
+/// added by the user will be positioned after our `note_count` cards.
+fn conf_json(deck_id: i64, note_count: usize) -> serde_json::Value {
+    json!({
+        "nextPos": note_count + 1, "estTimes": true, "activeDecks": [deck_id],
+        "sortType": "noteFld", "timeLim": 0, "sortBackwards": false,
+        "addToCur": true, "curDeck": deck_id, "newBury": true,
+        "newSpread": 0, "dueCounts": true, "curModel": MODEL_ID.to_string(),
+        "collapseTime": 1200,
+    })
+}
+
+/// Write an Anki collection containing `notes` to a new SQLite database at
+/// `path`.
+fn write_collection(path: &Path, deck_name: &str, notes: &[AnkiNote]) -> Result<()> {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .with_context(|_| format_err!("system clock is set before 1970"))?;
+    let now = elapsed.as_secs() as i64;
+    let now_ms = now * 1000 + (elapsed.subsec_nanos() / 1_000_000) as i64;
+    let deck_id = now_ms;
+
+    // Start from scratch if we're resuming an interrupted export.
+    if path.exists() {
+        fs::remove_file(path).io_write_context(path)?;
+    }
+    let conn = Connection::open(path)?;
+    conn.execute_batch(SCHEMA)?;
+    conn.execute(
+        "INSERT INTO col VALUES (1, ?, ?, ?, 11, 0, 0, 0, ?, ?, ?, ?, '{}')",
+        params![
+            now,
+            now,
+            now_ms,
+            conf_json(deck_id, notes.len()).to_string(),
+            models_json(deck_id, now).to_string(),
+            decks_json(deck_id, deck_name, now).to_string(),
+            dconf_json(now).to_string(),
+        ],
+    )?;
+
+    for (i, note) in notes.iter().enumerate() {
+        let id = now_ms + i as i64;
+        let fields = note.fields();
+        conn.execute(
+            "INSERT INTO notes VALUES (?, ?, ?, ?, -1, '', ?, ?, ?, 0, '')",
+            params![
+                id,
+                note_guid(note),
+                MODEL_ID,
+                now,
+                fields.join("\x1f"),
+                fields[SORT_FIELD],
+                field_checksum(&fields[0]),
+            ],
+        )?;
+        // A new card, positioned after the previous cards.
+        conn.execute(
+            "INSERT INTO cards VALUES (?, ?, ?, 0, ?, -1, 0, 0, ?, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+            params![id, id, deck_id, now, i as i64 + 1],
+        )?;
+    }
+    Ok(())
+}
+
+/// Export the video and subtitles as an Anki `*.apkg` file containing a
+/// deck, a note type and all the media files it uses.
+pub fn export_apkg(exporter: &mut Exporter) -> Result<()> {
+    let (notes, media) = anki_notes(exporter);
+    exporter.finish_exports()?;
+
+    let collection_path = exporter.dir().join("collection.anki2");
+    write_collection(&collection_path, exporter.title(), &notes)?;
+
+    // Build our zip archive.
+    let apkg_path = exporter.dir().join(format!("{}.apkg", exporter.file_stem()));
+    let file = fs::File::create(&apkg_path).io_write_context(&apkg_path)?;
+    let mut zip = ZipWriter::new(file);
+    let add_file = |zip: &mut ZipWriter<fs::File>, name: &str, data: &[u8]| -> Result<()> {
+        zip.start_file(name, FileOptions::default())?;
+        zip.write_all(data).io_write_context(&apkg_path)?;
+        Ok(())
+    };
+
+    let read = |path: &Path| -> Result<Vec<u8>> {
+        let mut data = vec![];
+        let mut f = fs::File::open(path).io_read_context(path)?;
+        f.read_to_end(&mut data).io_read_context(path)?;
+        Ok(data)
+    };
+    add_file(&mut zip, "collection.anki2", &read(&collection_path)?)?;
+
+    let mut media_map = serde_json::Map::new();
+    for (i, name) in media.iter().enumerate() {
+        media_map.insert(i.to_string(), json!(name));
+        add_file(&mut zip, &i.to_string(), &read(&exporter.dir().join(name))?)?;
+    }
+    add_file(&mut zip, "media", serde_json::Value::Object(media_map).to_string().as_bytes())?;
+    zip.finish()?;
+
+    fs::remove_file(&collection_path).io_write_context(&collection_path)?;
+    Ok(())
+}
+
+#[test]
+fn test_conf_json_next_pos() {
+    assert_eq!(1, conf_json(1, 0)["nextPos"]);
+    assert_eq!(4, conf_json(1, 3)["nextPos"]);
+}
+
+#[test]
+fn test_field_checksum() {
+    // The first 8 hex digits of SHA1("hello") are aaf4c61d.
+    assert_eq!(0xaaf4c61d, field_checksum("<b>hello</b>"));
+}
@@ -4,7 +4,7 @@ use common_failures::prelude::*;
 use csv;
 use regex::Regex;
 
-use contexts::ItemsInContextExt;
+use contexts::{Context, ItemsInContextExt};
 use export::Exporter;
 use srt::Subtitle;
 use time::seconds_to_hhmmss_sss;
@@ -13,7 +13,23 @@ use time::seconds_to_hhmmss_sss;
 /// Honestly, this might be a bit too clever--the original subs2srs CSV
 /// format something this as part of a sort key, but we may be able to do a
 /// lot better if we rethink the CSV columns we're exporting.
+///
+/// Names like `Show.S01E03` or `Show 1x03` are turned into a zero-padded
+/// `01.03` prefix, so that episodes from a whole season sort correctly
+/// when they're combined into a single deck.
 fn episode_prefix(file_stem: &str) -> String {
+    lazy_static! {
+        static ref SEASON_EPISODE: Regex = Regex::new(
+            r"(?i)\bs([0-9]{1,3})[ ._-]?e([0-9]{1,4})|\b([0-9]{1,2})x([0-9]{2,3})\b"
+        ).unwrap();
+    }
+    if let Some(c) = SEASON_EPISODE.captures(file_stem) {
+        let num = |i: usize, j: usize| -> u32 {
+            c.get(i).or_else(|| c.get(j)).unwrap().as_str().parse().unwrap()
+        };
+        return format!("{:02}.{:02} ", num(1, 3), num(2, 4));
+    }
+
     let re = Regex::new(r"[0-9][-_.0-9]+$").unwrap();
     re.captures(file_stem)
         .map(|c| {
@@ -27,83 +43,185 @@ fn episode_prefix(file_stem: &str) -> String {
 fn test_episode_prefix() {
     assert_eq!("01.02 ", episode_prefix("series_01_02"));
     assert_eq!("", episode_prefix("film"));
+    assert_eq!("01.03 ", episode_prefix("Show.S01E03"));
+    assert_eq!("02.10 ", episode_prefix("Show.s2e10.720p"));
+    assert_eq!("03.07 ", episode_prefix("Show 3x07 Title"));
 }
 
+/// Build the HTML needed to display an exported image, which may actually
+/// be a short video clip.
+fn image_html(exporter: &Exporter, image_path: &str) -> String {
+    if exporter.options().image.format.is_video() {
+        format!("<video src=\"{}\" autoplay loop muted></video>", image_path)
+    } else {
+        format!("<img src=\"{}\" />", image_path)
+    }
+}
+
+/// The field names of `AnkiNote`, in order, as they should appear in an
+/// Anki note type.
+pub(crate) const ANKI_NOTE_FIELDS: &[&str] = &[
+    "Sound",
+    "Time",
+    "Source",
+    "Image",
+    "ForeignCurr",
+    "NativeCurr",
+    "ForeignPrev",
+    "NativePrev",
+    "ForeignNext",
+    "NativeNext",
+    "ContextSound",
+];
+
+/// A single Anki note, with one column for each of `ANKI_NOTE_FIELDS`.
 #[derive(Debug, Serialize)]
-struct AnkiNote {
-    sound: String,
-    time: String,
-    source: String,
-    image: String,
-    foreign_curr: Option<String>,
-    native_curr: Option<String>,
-    foreign_prev: Option<String>,
-    native_prev: Option<String>,
-    foreign_next: Option<String>,
-    native_next: Option<String>,
+pub(crate) struct AnkiNote {
+    pub(crate) sound: String,
+    pub(crate) time: String,
+    pub(crate) source: String,
+    pub(crate) image: String,
+    pub(crate) foreign_curr: Option<String>,
+    pub(crate) native_curr: Option<String>,
+    pub(crate) foreign_prev: Option<String>,
+    pub(crate) native_prev: Option<String>,
+    pub(crate) foreign_next: Option<String>,
+    pub(crate) native_next: Option<String>,
+    /// Only present with `ClipOptions::context_audio`. We leave this column
+    /// out of CSV files entirely when it's missing, so that we don't break
+    /// the field mappings of existing Anki note types.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) context_sound: Option<String>,
 }
 
-/// Export the video and subtitles as a CSV file with accompanying media
-/// files, for import into Anki.
-pub fn export_csv(exporter: &mut Exporter) -> Result<()> {
+impl AnkiNote {
+    /// The values of our fields, in the same order as `ANKI_NOTE_FIELDS`.
+    pub(crate) fn fields(&self) -> Vec<String> {
+        let opt = |s: &Option<String>| s.clone().unwrap_or_else(String::new);
+        vec![
+            self.sound.clone(),
+            self.time.clone(),
+            self.source.clone(),
+            self.image.clone(),
+            opt(&self.foreign_curr),
+            opt(&self.native_curr),
+            opt(&self.foreign_prev),
+            opt(&self.native_prev),
+            opt(&self.foreign_next),
+            opt(&self.native_next),
+            opt(&self.context_sound),
+        ]
+    }
+}
+
+#[test]
+fn context_sound_column_is_optional() {
+    let note = |context_sound: Option<&str>| AnkiNote {
+        sound: "[sound:a.mp3]".to_owned(),
+        time: "00:00:01.000".to_owned(),
+        source: "film".to_owned(),
+        image: "<img src=\"a.jpg\" />".to_owned(),
+        foreign_curr: Some("Hola".to_owned()),
+        native_curr: None,
+        foreign_prev: None,
+        native_prev: None,
+        foreign_next: None,
+        native_next: None,
+        context_sound: context_sound.map(|s| s.to_owned()),
+    };
+    let header = |note: AnkiNote| {
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        wtr.serialize(&note).unwrap();
+        let data = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        data.lines().next().unwrap().to_owned()
+    };
+    assert!(!header(note(None)).contains("context_sound"));
+    assert!(header(note(Some("[sound:b.mp3]"))).ends_with(",context_sound"));
+}
+
+/// Build an `AnkiNote` for each subtitle with foreign-language text,
+/// scheduling exports of the associated media. Returns the notes and the
+/// file names of all the media files we'll need.
+pub(crate) fn anki_notes(exporter: &mut Exporter) -> (Vec<AnkiNote>, Vec<String>) {
     let foreign_lang = exporter.foreign().language;
     let prefix = episode_prefix(exporter.file_stem());
 
-    // Create our CSV writer.
-    let mut buffer = Vec::<u8>::new();
-    {
-        let mut wtr = csv::Writer::from_writer(&mut buffer);
+    // Align our input files, filtering out ones with no foreign-language
+    // text, because those make lousy SRS cards.  (Yes, it seems like it
+    // should work, but I've seen multiple people try it now, and they're
+    // maybe only 20% as effective as cards with foreign-language text, at
+    // least for people below CEFRL C1.)
+    let aligned: Vec<(Option<Subtitle>, Option<Subtitle>)> = exporter.align()
+        .iter()
+        // The double ref `&&` is thanks to `filter`'s type signature.
+        .filter(|&&(ref f, _)| f.is_some())
+        .cloned().collect();
 
-        // Align our input files, filtering out ones with no foreign-language
-        // text, because those make lousy SRS cards.  (Yes, it seems like it
-        // should work, but I've seen multiple people try it now, and they're
-        // maybe only 20% as effective as cards with foreign-language text, at
-        // least for people below CEFRL C1.)
-        let aligned: Vec<(Option<Subtitle>, Option<Subtitle>)> = exporter.align()
-            .iter()
-            // The double ref `&&` is thanks to `filter`'s type signature.
-            .filter(|&&(ref f, _)| f.is_some())
-            .cloned().collect();
+    let mut notes = vec![];
+    let mut media = vec![];
+    for ctx in aligned.items_in_context() {
+        // We have a `Context<&(Option<Subtitle>, Option<Subtitle>)>`
+        // containing the previous subtitle pair, the current subtitle
+        // pair, and the next subtitle pair.  We want to split apart that
+        // tuple and flatten any nested `Option<&Option<T>>` types into
+        // `Option<&T>`.
+        let foreign = ctx.map(|&(ref f, _)| f).flatten();
+        let native = ctx.map(|&(_, ref n)| n).flatten();
 
-        // Output each row in the CSV file.
-        for ctx in aligned.items_in_context() {
-            // We have a `Context<&(Option<Subtitle>, Option<Subtitle>)>`
-            // containing the previous subtitle pair, the current subtitle
-            // pair, and the next subtitle pair.  We want to split apart that
-            // tuple and flatten any nested `Option<&Option<T>>` types into
-            // `Option<&T>`.
-            let foreign = ctx.map(|&(ref f, _)| f).flatten();
-            let native = ctx.map(|&(_, ref n)| n).flatten();
-
-            if let Some(curr) = foreign.curr {
-                let period = curr.period.grow(1.5, 1.5);
-
-                let image_path = exporter.schedule_image_export(period.midpoint());
-                let audio_path = exporter.schedule_audio_export(foreign_lang, period);
-
-                // Try to emulate something like the wierd sort-key column
-                // generated by subs2srs without requiring the user to always
-                // pass in an explicit episode number.
-                let sort_key =
-                    format!("{}{}", &prefix, &seconds_to_hhmmss_sss(period.begin()));
-
-                let note = AnkiNote {
-                    sound: format!("[sound:{}]", &audio_path),
-                    time: sort_key,
-                    source: exporter.title().to_owned(),
-                    image: format!("<img src=\"{}\" />", &image_path),
-                    foreign_curr: foreign.curr.map(|s| s.plain_text()),
-                    native_curr: native.curr.map(|s| s.plain_text()),
-                    foreign_prev: foreign.prev.map(|s| s.plain_text()),
-                    native_prev: native.prev.map(|s| s.plain_text()),
-                    foreign_next: foreign.next.map(|s| s.plain_text()),
-                    native_next: native.next.map(|s| s.plain_text()),
-                };
-                wtr.serialize(&note)
-                    .with_context(|_| format_err!("error serializing to RAM"))?;
+        if let Some(curr) = foreign.curr {
+            let periods = Context {
+                prev: foreign.prev.map(|s| s.period),
+                curr: curr.period,
+                next: foreign.next.map(|s| s.period),
+            };
+            let clips = exporter.options().clips;
+            let period = clips.clip_period(&periods, 1.5);
+
+            let image_path = exporter.schedule_image_export(period);
+            let audio_path = exporter.schedule_audio_export(foreign_lang, period);
+            let context_path = if clips.context_audio {
+                let context_period = clips.context_period(&periods, 1.5);
+                Some(exporter.schedule_audio_export(foreign_lang, context_period))
+            } else {
+                None
+            };
+
+            // Try to emulate something like the wierd sort-key column
+            // generated by subs2srs without requiring the user to always
+            // pass in an explicit episode number.
+            let sort_key =
+                format!("{}{}", &prefix, &seconds_to_hhmmss_sss(period.begin()));
+
+            notes.push(AnkiNote {
+                sound: format!("[sound:{}]", &audio_path),
+                time: sort_key,
+                source: exporter.title().to_owned(),
+                image: image_html(exporter, &image_path),
+                foreign_curr: foreign.curr.map(|s| s.plain_text()),
+                native_curr: native.curr.map(|s| s.plain_text()),
+                foreign_prev: foreign.prev.map(|s| s.plain_text()),
+                native_prev: native.prev.map(|s| s.plain_text()),
+                foreign_next: foreign.next.map(|s| s.plain_text()),
+                native_next: native.next.map(|s| s.plain_text()),
+                context_sound: context_path.as_ref().map(|p| format!("[sound:{}]", p)),
+            });
+            if let Some(context_path) = context_path {
+                // This may be the same file as our regular clip.
+                if context_path != audio_path {
+                    media.push(context_path);
+                }
             }
+            media.push(audio_path);
+            media.push(image_path);
         }
     }
+    (notes, media)
+}
+
+/// Export the video and subtitles as a CSV file with accompanying media
+/// files, for import into Anki.
+pub fn export_csv(exporter: &mut Exporter) -> Result<()> {
+    let buffer = notes_csv(&mut [&mut *exporter])?;
 
     // Write out our CSV file.
     exporter.export_data_file("cards.csv", &buffer)?;
@@ -113,3 +231,45 @@ pub fn export_csv(exporter: &mut Exporter) -> Result<()> {
 
     Ok(())
 }
+
+/// Export several videos as a single combined CSV file, for example, all
+/// the episodes in a season. The exporters should all share a single output
+/// directory (see `Exporter::in_dir`).
+pub fn export_csv_combined(exporters: &mut [Exporter]) -> Result<()> {
+    let buffer = {
+        let mut refs: Vec<&mut Exporter> = exporters.iter_mut().collect();
+        notes_csv(&mut refs)?
+    };
+
+    // Write out our CSV file.
+    match exporters.first() {
+        Some(exporter) => exporter.export_data_file("cards.csv", &buffer)?,
+        None => return Err(format_err!("no videos to export")),
+    }
+
+    // Extract our media files.
+    for exporter in exporters {
+        exporter.finish_exports()?;
+    }
+
+    Ok(())
+}
+
+/// Build a CSV file containing the notes for each of `exporters`.
+fn notes_csv(exporters: &mut [&mut Exporter]) -> Result<Vec<u8>> {
+    // Create our CSV writer.
+    let mut buffer = Vec::<u8>::new();
+    {
+        let mut wtr = csv::Writer::from_writer(&mut buffer);
+
+        // Output each row in the CSV file.
+        for exporter in exporters {
+            let (notes, _) = anki_notes(exporter);
+            for note in &notes {
+                wtr.serialize(note)
+                    .with_context(|_| format_err!("error serializing to RAM"))?;
+            }
+        }
+    }
+    Ok(buffer)
+}
@@ -2,6 +2,8 @@
 
 use common_failures::prelude::*;
 use common_failures::io::{Operation, Target};
+use num_cpus;
+use std::collections::HashSet;
 use std::convert::AsRef;
 use std::default::Default;
 use std::io::Write;
@@ -10,11 +12,12 @@ use std::fmt::Write as fmt_Write;
 use std::fs;
 use std::path::{Path, PathBuf};
 
-use align::align_available_files;
+use align::{align_available_files_with, CostModel};
+use contexts::Context;
 use lang::Lang;
 use srt::{Subtitle, SubtitleFile};
 use time::{Period, ToTimestamp};
-use video::{Extraction, ExtractionSpec, Id3Metadata, Video};
+use video::{AudioOptions, Extraction, ExtractionSpec, Id3Metadata, ImageOptions, Video};
 
 /// Take a platform-specific pathname fragment and turn it into a regular
 /// Unicode string.
@@ -42,6 +45,109 @@ impl LanguageResources {
     }
 }
 
+/// How should we choose the boundaries of the audio clips we export?
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ClipOptions {
+    /// Seconds of padding to add before each subtitle, or `None` to use the
+    /// exporter's default.
+    pub lead_in: Option<f32>,
+    /// Seconds of padding to add after each subtitle, or `None` to use the
+    /// exporter's default.
+    pub lead_out: Option<f32>,
+    /// Should we stop padding at neighbouring subtitles, so that a clip
+    /// never includes part of another line?
+    pub snap_to_gaps: bool,
+    /// Should we also export a "context audio" clip covering the previous,
+    /// current and next subtitles?
+    pub context_audio: bool,
+}
+
+impl ClipOptions {
+    /// Choose the period to use for a clip of `ctx.curr`, padding it by
+    /// `default_padding` seconds on each side unless the user has asked
+    /// for something else.
+    pub fn clip_period(&self, ctx: &Context<Period>, default_padding: f32) -> Period {
+        let padded = ctx.curr.grow(
+            self.lead_in.unwrap_or(default_padding),
+            self.lead_out.unwrap_or(default_padding),
+        );
+        if !self.snap_to_gaps {
+            return padded;
+        }
+
+        // Stop at the edges of our neighbours, but never trim the current
+        // subtitle itself, even if it overlaps them.
+        let mut begin = padded.begin();
+        let mut end = padded.end();
+        if let Some(prev) = ctx.prev {
+            begin = begin.max(prev.end().min(ctx.curr.begin()));
+        }
+        if let Some(next) = ctx.next {
+            end = end.min(next.begin().max(ctx.curr.end()));
+        }
+        Period::new(begin, end).unwrap_or(padded)
+    }
+
+    /// Choose the period to use for a "context audio" clip covering
+    /// `ctx.prev`, `ctx.curr` and `ctx.next`, padded just like
+    /// `clip_period`.
+    pub fn context_period(&self, ctx: &Context<Period>, default_padding: f32) -> Period {
+        let mut period = ctx.curr;
+        if let Some(prev) = ctx.prev {
+            period = period.union(prev);
+        }
+        if let Some(next) = ctx.next {
+            period = period.union(next);
+        }
+        period.grow(
+            self.lead_in.unwrap_or(default_padding),
+            self.lead_out.unwrap_or(default_padding),
+        )
+    }
+}
+
+#[test]
+fn clip_periods() {
+    let p = |begin, end| Period::new(begin, end).unwrap();
+    let ctx = Context {
+        prev: Some(p(1.0, 2.0)),
+        curr: p(2.5, 4.0),
+        next: Some(p(4.5, 6.0)),
+    };
+
+    let options = ClipOptions::default();
+    assert_eq!(options.clip_period(&ctx, 1.0), p(1.5, 5.0));
+    assert_eq!(options.context_period(&ctx, 1.0), p(0.0, 7.0));
+
+    let options = ClipOptions {
+        lead_in: Some(0.25),
+        lead_out: Some(2.0),
+        snap_to_gaps: true,
+        ..ClipOptions::default()
+    };
+    assert_eq!(options.clip_period(&ctx, 1.0), p(2.25, 4.5));
+    assert_eq!(options.context_period(&ctx, 1.0), p(0.75, 8.0));
+
+    // Overlapping neighbours never cut into the current subtitle.
+    let overlapping = Context {
+        prev: Some(p(1.0, 3.0)),
+        curr: p(2.5, 4.0),
+        next: Some(p(3.5, 6.0)),
+    };
+    assert_eq!(options.clip_period(&overlapping, 1.0), p(2.5, 4.0));
+}
+
+/// How should we encode the media files that we export?
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ExportOptions {
+    /// Options for audio clips.
+    pub audio: AudioOptions,
+    /// Options for images and animated snippets.
+    pub image: ImageOptions,
+    /// Options for choosing clip boundaries.
+    pub clips: ClipOptions,
+}
+
 /// Information about media file and associated subtitles that the user
 /// wants to export.
 pub struct Exporter {
@@ -63,6 +169,20 @@ pub struct Exporter {
     /// A list of media files we want to extract from our video as
     /// efficiently as possible.
     extractions: Vec<Extraction>,
+
+    /// The paths of the audio clips in `extractions`, so that we can avoid
+    /// scheduling the same clip twice.
+    audio_paths: HashSet<PathBuf>,
+
+    /// How many seconds later the native subtitles appear than the foreign
+    /// ones. If this is `None`, we estimate it when aligning.
+    native_offset: Option<f32>,
+
+    /// How many copies of ffmpeg to run at once.
+    jobs: usize,
+
+    /// How to encode our media files.
+    options: ExportOptions,
 }
 
 impl Exporter {
@@ -75,23 +195,44 @@ impl Exporter {
         native_subtitles: Option<SubtitleFile>,
         label: &str,
     ) -> Result<Exporter> {
-        let foreign = LanguageResources::new(foreign_subtitles);
-        let native = native_subtitles.map(|subs| LanguageResources::new(subs));
-
-        // Construct a path `dir` which we'll use to store our output
-        // files.  This is much uglier than it ought to be because paths
-        // are not necessarily valid Unicode strings on all OSes, so we
-        // need to jump through extra hoops.  We test for a directory's
-        // existence using the `metadata` call, which is the only way to do
-        // it in stable Rust.
-        let file_stem = os_str_to_string(video.file_stem());
-        let dir = Path::new("./").join(format!("{}_{}", &file_stem, label));
+        // We test for a directory's existence using the `metadata` call,
+        // which is the only way to do it in stable Rust.
+        let dir = Exporter::default_dir(&video, label);
         if fs::metadata(&dir).is_ok() {
             return Err(format_err!(
                 "Directory already exists: {}",
                 &dir.to_string_lossy()
             ));
         }
+        Exporter::in_dir(video, foreign_subtitles, native_subtitles, &dir)
+    }
+
+    /// Like `new`, but if the output directory already exists, continue
+    /// an earlier export instead of failing. Media files which were already
+    /// extracted will not be extracted again.
+    pub fn resume(
+        video: Video,
+        foreign_subtitles: SubtitleFile,
+        native_subtitles: Option<SubtitleFile>,
+        label: &str,
+    ) -> Result<Exporter> {
+        let dir = Exporter::default_dir(&video, label);
+        Exporter::in_dir(video, foreign_subtitles, native_subtitles, &dir)
+    }
+
+    /// Create a new exporter which writes its output to `dir`, creating it
+    /// if necessary. Several exporters may share one directory, because
+    /// the media files we extract are named after the video.
+    pub fn in_dir(
+        video: Video,
+        foreign_subtitles: SubtitleFile,
+        native_subtitles: Option<SubtitleFile>,
+        dir: &Path,
+    ) -> Result<Exporter> {
+        let foreign = LanguageResources::new(foreign_subtitles);
+        let native = native_subtitles.map(|subs| LanguageResources::new(subs));
+        let file_stem = os_str_to_string(video.file_stem());
+        let dir = dir.to_owned();
         fs::create_dir_all(&dir).io_context(
             Operation::Create,
             Target::Directory(dir.to_owned()),
@@ -104,15 +245,32 @@ impl Exporter {
             file_stem: file_stem,
             dir: dir,
             extractions: vec![],
+            audio_paths: HashSet::new(),
+            native_offset: None,
+            jobs: num_cpus::get(),
+            options: ExportOptions::default(),
         })
     }
 
+    /// The directory `new` and `resume` use for `video`. This is much
+    /// uglier than it ought to be because paths are not necessarily valid
+    /// Unicode strings on all OSes, so we need to jump through extra hoops.
+    pub fn default_dir(video: &Video, label: &str) -> PathBuf {
+        let file_stem = os_str_to_string(video.file_stem());
+        Path::new("./").join(format!("{}_{}", &file_stem, label))
+    }
+
     /// The base name of this file, with the directory and file extension
     /// removed.
     pub fn file_stem(&self) -> &str {
         &self.file_stem
     }
 
+    /// The directory into which we're exporting files.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
     /// Return a title for this video.
     pub fn title(&self) -> &str {
         &self.file_stem
@@ -133,11 +291,39 @@ impl Exporter {
         self.native.as_ref()
     }
 
+    /// Specify how many seconds later the native subtitles appear than the
+    /// foreign ones, instead of estimating it automatically.
+    pub fn set_native_offset(&mut self, offset: f32) {
+        self.native_offset = Some(offset);
+    }
+
+    /// Specify how many copies of ffmpeg to run at once when extracting
+    /// media. Defaults to the number of CPUs.
+    pub fn set_jobs(&mut self, jobs: usize) {
+        self.jobs = jobs;
+    }
+
+    /// Get the options used to encode our media files.
+    pub fn options(&self) -> &ExportOptions {
+        &self.options
+    }
+
+    /// Specify how to encode our media files. Defaults to MP3 audio and
+    /// 240x160 JPEG images.
+    pub fn set_options(&mut self, options: ExportOptions) {
+        self.options = options;
+    }
+
     /// Align our two sets of subtitles.
     pub fn align(&self) -> Vec<(Option<Subtitle>, Option<Subtitle>)> {
-        align_available_files(
+        let model = CostModel {
+            offset: self.native_offset,
+            ..CostModel::default()
+        };
+        align_available_files_with(
             &self.foreign.subtitles,
             self.native.as_ref().map(|n| &n.subtitles),
+            &model,
         )
     }
 
@@ -152,19 +338,30 @@ impl Exporter {
         let mut file_name =
             format!("{}_{}", &self.file_stem, timestamp.to_file_timestamp());
         if let Some(l) = lang {
-            write!(&mut file_name, ".{}", l.as_str()).unwrap();
+            write!(&mut file_name, ".{}", l).unwrap();
         }
         write!(&mut file_name, ".{}", extension).unwrap();
         self.dir.join(file_name)
     }
 
-    /// Schedule an export of the image at the specified time code.
-    /// Returns the path to which the image will be written.
-    pub fn schedule_image_export(&mut self, time: f32) -> String {
-        let path = self.media_path(time, None, "jpg");
+    /// Schedule an export of an image representing the specified period:
+    /// either a still frame from the middle, or an animated snippet of the
+    /// whole period, depending on our options. Returns the path to which
+    /// the image will be written.
+    pub fn schedule_image_export(&mut self, period: Period) -> String {
+        let options = self.options.image;
+        let extension = options.format.extension();
+        let (path, spec) = if options.format.is_animated() {
+            let path = self.media_path(period, None, extension);
+            (path, ExtractionSpec::Snippet(period, options))
+        } else {
+            let time = period.midpoint();
+            let path = self.media_path(time, None, extension);
+            (path, ExtractionSpec::Image(time, options))
+        };
         self.extractions.push(Extraction {
             path: path.clone(),
-            spec: ExtractionSpec::Image(time),
+            spec: spec,
         });
         os_str_to_string(path.file_name().unwrap())
     }
@@ -188,11 +385,17 @@ impl Exporter {
         period: Period,
         metadata: Id3Metadata,
     ) -> String {
-        let path = self.media_path(period, lang, "mp3");
-        let stream = lang.and_then(|l| self.video.audio_for(l));
+        let options = self.options.audio;
+        let path = self.media_path(period, lang, options.format.extension());
+        let stream = self.video.audio_for(lang);
+        // Context clips may be identical to regular ones, and we don't
+        // want two ffmpeg outputs writing the same file.
+        if !self.audio_paths.insert(path.clone()) {
+            return os_str_to_string(path.file_name().unwrap());
+        }
         self.extractions.push(Extraction {
             path: path.clone(),
-            spec: ExtractionSpec::Audio(stream, period, metadata),
+            spec: ExtractionSpec::Audio(stream, period, metadata, options),
         });
         os_str_to_string(path.file_name().unwrap())
     }
@@ -210,7 +413,7 @@ impl Exporter {
 
     /// Finish all scheduled exports.
     pub fn finish_exports(&mut self) -> Result<()> {
-        self.video.extract(&self.extractions)?;
+        self.video.extract(&self.extractions, self.jobs)?;
         Ok(())
     }
 }
@@ -1,10 +1,14 @@
 //! Interfaces to various spaced repetition systems.
 
 pub use self::exporter::*;
+pub use self::aligned::export_aligned;
+pub use self::apkg::export_apkg;
 pub use self::review::export_review;
-pub use self::csv::export_csv;
+pub use self::csv::{export_csv, export_csv_combined};
 pub use self::tracks::export_tracks;
 
+mod aligned;
+mod apkg;
 mod exporter;
 mod csv;
 mod review;
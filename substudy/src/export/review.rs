@@ -4,8 +4,10 @@ use common_failures::prelude::*;
 use failure::SyncFailure;
 use handlebars::Handlebars;
 
+use contexts::ItemsInContextExt;
 use export::Exporter;
 use lang::Lang;
+use srt::Subtitle;
 use time::Period;
 
 /// Information about a subtitle for use by our Handlebars HTML template.
@@ -13,7 +15,9 @@ use time::Period;
 struct SubtitleInfo {
     index: usize,
     image_path: String,
+    image_is_video: bool,
     audio_path: String,
+    context_audio_path: Option<String>,
     foreign_text: Option<String>,
     native_text: Option<String>,
 }
@@ -27,6 +31,14 @@ struct ExportInfo {
     native_lang: Option<Lang>,
 }
 
+/// The period covered by a pair of aligned subtitles.
+fn pair_period(pair: &(Option<Subtitle>, Option<Subtitle>)) -> Period {
+    Period::from_union_opt(
+        pair.0.as_ref().map(|s| s.period),
+        pair.1.as_ref().map(|s| s.period),
+    ).expect("subtitle pair must not be empty")
+}
+
 /// Export the video and subtitles as a web page in "reviewable" format.
 pub fn export_review(exporter: &mut Exporter) -> Result<()> {
     let foreign_lang = exporter.foreign().language;
@@ -40,22 +52,30 @@ pub fn export_review(exporter: &mut Exporter) -> Result<()> {
 
     // Align our input files and iterate.
     let aligned = exporter.align();
-    for sub in aligned.iter().enumerate() {
-        let (i, &(ref foreign, ref native)) = sub;
+    let pair_periods: Vec<Period> = aligned.iter().map(pair_period).collect();
+    for (i, (ctx, &(ref foreign, ref native))) in
+        pair_periods.items_in_context().zip(aligned.iter()).enumerate()
+    {
         let index = i + 1;
-        let period = Period::from_union_opt(
-            foreign.as_ref().map(|s| s.period),
-            native.as_ref().map(|s| s.period),
-        ).expect("subtitle pair must not be empty")
-            .grow(0.01, 0.01);
+        let periods = ctx.cloned();
+        let clips = exporter.options().clips;
+        let period = clips.clip_period(&periods, 0.01);
 
-        let image_path = exporter.schedule_image_export(period.midpoint());
+        let image_path = exporter.schedule_image_export(period);
         let audio_path = exporter.schedule_audio_export(foreign_lang, period);
+        let context_audio_path = if clips.context_audio {
+            let context_period = clips.context_period(&periods, 0.01);
+            Some(exporter.schedule_audio_export(foreign_lang, context_period))
+        } else {
+            None
+        };
 
         bindings.subtitles.push(SubtitleInfo {
             index: index,
             image_path: image_path,
+            image_is_video: exporter.options().image.format.is_video(),
             audio_path: audio_path,
+            context_audio_path: context_audio_path,
             foreign_text: foreign.as_ref().map(|s| s.plain_text()),
             native_text: native.as_ref().map(|s| s.plain_text()),
         });
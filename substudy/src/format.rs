@@ -0,0 +1,129 @@
+//! The various subtitle file formats that we know how to read and write.
+
+use common_failures::prelude::*;
+use std::fmt;
+use std::path::Path;
+use std::result;
+use std::str::FromStr;
+
+use sbv;
+use ttml;
+
+/// A subtitle file format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// SubRip `*.srt` files, the most widely supported text format.
+    Srt,
+    /// WebVTT `*.vtt` files, as used by HTML 5 video and most streaming
+    /// services.
+    Vtt,
+    /// Advanced SubStation Alpha `*.ass` files, and the older `*.ssa`
+    /// format. Popular for anime fansubs.
+    Ass,
+    /// MicroDVD `*.sub` files, which are timed using frame numbers.
+    MicroDvd,
+    /// SubViewer-style `*.sbv` files, as used by YouTube.
+    Sbv,
+    /// Timed Text Markup Language (also known as DFXP), an XML format.
+    Ttml,
+}
+
+impl Format {
+    /// Guess the format of a subtitle file using its extension, falling back
+    /// to SRT if we don't recognize it.
+    ///
+    /// ```
+    /// use std::path::Path;
+    /// use substudy::format::Format;
+    ///
+    /// assert_eq!(Format::Vtt, Format::for_path(Path::new("ep1.es.vtt")));
+    /// assert_eq!(Format::Ass, Format::for_path(Path::new("ep1.es.SSA")));
+    /// assert_eq!(Format::Srt, Format::for_path(Path::new("ep1.es.srt")));
+    /// assert_eq!(Format::Srt, Format::for_path(Path::new("ep1")));
+    /// ```
+    pub fn for_path(path: &Path) -> Format {
+        let ext = path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+        match ext.as_ref().map(|ext| &ext[..]) {
+            Some("vtt") => Format::Vtt,
+            Some("ass") | Some("ssa") => Format::Ass,
+            Some("sub") => Format::MicroDvd,
+            Some("sbv") => Format::Sbv,
+            Some("ttml") | Some("dfxp") | Some("xml") => Format::Ttml,
+            _ => Format::Srt,
+        }
+    }
+
+    /// Try to detect the format of subtitle data by looking at its contents.
+    /// Returns `None` if we can't tell.
+    ///
+    /// ```
+    /// use substudy::format::Format;
+    ///
+    /// assert_eq!(Some(Format::Srt),
+    ///            Format::detect("1\n00:00:01,000 --> 00:00:02,000\nHi!\n"));
+    /// assert_eq!(Some(Format::Vtt), Format::detect("WEBVTT\n"));
+    /// assert_eq!(Some(Format::MicroDvd), Format::detect("{24}{48}Hi!\n"));
+    /// assert_eq!(None, Format::detect("Hi!\n"));
+    /// ```
+    pub fn detect(data: &str) -> Option<Format> {
+        let data = data.trim_left_matches("\u{FEFF}");
+        let mut lines = data.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+        let first = lines.next().unwrap_or("");
+        if first.starts_with("WEBVTT") {
+            Some(Format::Vtt)
+        } else if first.eq_ignore_ascii_case("[Script Info]") {
+            Some(Format::Ass)
+        } else if ttml::is_ttml(data) {
+            Some(Format::Ttml)
+        } else if sbv::is_timing_line(first) {
+            Some(Format::Sbv)
+        } else if first.starts_with('{') && first[1..].find("}{").is_some() {
+            Some(Format::MicroDvd)
+        } else if first.chars().all(|c| c.is_digit(10))
+            && lines.next().map_or(false, |l| l.contains("-->"))
+        {
+            Some(Format::Srt)
+        } else {
+            None
+        }
+    }
+
+    /// The standard file extension for this format, without a leading ".".
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Format::Srt => "srt",
+            Format::Vtt => "vtt",
+            Format::Ass => "ass",
+            Format::MicroDvd => "sub",
+            Format::Sbv => "sbv",
+            Format::Ttml => "ttml",
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match *self {
+            Format::MicroDvd => write!(f, "microdvd"),
+            _ => write!(f, "{}", self.extension()),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Format> {
+        match s {
+            "srt" => Ok(Format::Srt),
+            "vtt" => Ok(Format::Vtt),
+            "ass" | "ssa" => Ok(Format::Ass),
+            "microdvd" => Ok(Format::MicroDvd),
+            "sbv" => Ok(Format::Sbv),
+            "ttml" | "dfxp" => Ok(Format::Ttml),
+            _ => Err(format_err!("Unknown subtitle format: {}", s)),
+        }
+    }
+}
@@ -1,80 +1,53 @@
 //! Naming and identifying languages.  We use
 
 use common_failures::prelude::*;
+use isolang;
 use serde::{Serialize, Serializer};
 use std::collections::HashMap;
 use std::fmt;
 use std::iter::FromIterator;
-use std::str::from_utf8;
+use std::str::{from_utf8, FromStr};
 use std::result;
 use whatlang;
 
 // Use the third-party `lazy_static!` macro to declare variables that will
 // initialized the first time we use them.
 lazy_static! {
-    /// Maps ISO 639 codes to their preferred internal forms.  Based on
-    /// http://www.loc.gov/standards/iso639-2/ISO-639-2_utf-8.txt
-    static ref CANONICAL_CODE: HashMap<&'static str, &'static str> = {
+    /// Maps language codes which `isolang` doesn't understand to the
+    /// equivalent ISO 639-3 codes. Everything else is canonicalized using
+    /// `isolang`.
+    static ref ISO_639_3_ALIASES: HashMap<&'static str, &'static str> = {
         HashMap::from_iter([
-            ("aar", "aa"), ("abk", "ab"), ("afr", "af"), ("aka", "ak"),
-            ("alb", "sq"), ("sqi", "sq"), ("amh", "am"), ("ara", "ar"),
-            ("arg", "an"), ("arm", "hy"), ("hye", "hy"), ("asm", "as"),
-            ("ava", "av"), ("ave", "ae"), ("aym", "ay"), ("aze", "az"),
-            ("bak", "ba"), ("bam", "bm"), ("baq", "eu"), ("eus", "eu"),
-            ("bel", "be"), ("ben", "bn"), ("bih", "bh"), ("bis", "bi"),
-            ("bos", "bs"), ("bre", "br"), ("bul", "bg"), ("bur", "my"),
-            ("mya", "my"), ("cat", "ca"), ("cha", "ch"), ("che", "ce"),
-            ("chi", "zh"), ("zho", "zh"), ("chu", "cu"), ("chv", "cv"),
-            ("cor", "kw"), ("cos", "co"), ("cre", "cr"), ("ces", "cs"),
-            ("cze", "cs"), ("dan", "da"), ("div", "dv"), ("dut", "nl"),
-            ("nld", "nl"), ("dzo", "dz"), ("eng", "en"), ("epo", "eo"),
-            ("est", "et"), ("ewe", "ee"), ("fao", "fo"), ("fij", "fj"),
-            ("fin", "fi"), ("fra", "fr"), ("fre", "fr"), ("fry", "fy"),
-            ("ful", "ff"), ("geo", "ka"), ("kat", "ka"), ("deu", "de"),
-            ("ger", "de"), ("gla", "gd"), ("gle", "ga"), ("glg", "gl"),
-            ("glv", "gv"), ("ell", "el"), ("gre", "el"), ("grn", "gn"),
-            ("guj", "gu"), ("hat", "ht"), ("hau", "ha"), ("heb", "he"),
-            ("her", "hz"), ("hin", "hi"), ("hmo", "ho"), ("hrv", "hr"),
-            ("hun", "hu"), ("ibo", "ig"), ("ice", "is"), ("isl", "is"),
-            ("ido", "io"), ("iii", "ii"), ("iku", "iu"), ("ile", "ie"),
-            ("ina", "ia"), ("ind", "id"), ("ipk", "ik"), ("ita", "it"),
-            ("jav", "jv"), ("jpn", "ja"), ("kal", "kl"), ("kan", "kn"),
-            ("kas", "ks"), ("kau", "kr"), ("kaz", "kk"), ("khm", "km"),
-            ("kik", "ki"), ("kin", "rw"), ("kir", "ky"), ("kom", "kv"),
-            ("kon", "kg"), ("kor", "ko"), ("kua", "kj"), ("kur", "ku"),
-            ("lao", "lo"), ("lat", "la"), ("lav", "lv"), ("lim", "li"),
-            ("lin", "ln"), ("lit", "lt"), ("ltz", "lb"), ("lub", "lu"),
-            ("lug", "lg"), ("mac", "mk"), ("mkd", "mk"), ("mah", "mh"),
-            ("mal", "ml"), ("mao", "mi"), ("mri", "mi"), ("mar", "mr"),
-            ("may", "ms"), ("msa", "ms"), ("mlg", "mg"), ("mlt", "mt"),
-            ("mon", "mn"), ("nau", "na"), ("nav", "nv"), ("nbl", "nr"),
-            ("nde", "nd"), ("ndo", "ng"), ("nep", "ne"), ("nno", "nn"),
-            ("nob", "nb"), ("nor", "no"), ("nya", "ny"), ("oci", "oc"),
-            ("oji", "oj"), ("ori", "or"), ("orm", "om"), ("oss", "os"),
-            ("pan", "pa"), ("fas", "fa"), ("per", "fa"), ("pli", "pi"),
-            ("pol", "pl"), ("por", "pt"), ("pus", "ps"), ("que", "qu"),
-            ("roh", "rm"), ("ron", "ro"), ("rum", "ro"), ("run", "rn"),
-            ("rus", "ru"), ("sag", "sg"), ("san", "sa"), ("sin", "si"),
-            ("slk", "sk"), ("slo", "sk"), ("slv", "sl"), ("sme", "se"),
-            ("smo", "sm"), ("sna", "sn"), ("snd", "sd"), ("som", "so"),
-            ("sot", "st"), ("spa", "es"), ("srd", "sc"), ("srp", "sr"),
-            ("ssw", "ss"), ("sun", "su"), ("swa", "sw"), ("swe", "sv"),
-            ("tah", "ty"), ("tam", "ta"), ("tat", "tt"), ("tel", "te"),
-            ("tgk", "tg"), ("tgl", "tl"), ("tha", "th"), ("bod", "bo"),
-            ("tib", "bo"), ("tir", "ti"), ("ton", "to"), ("tsn", "tn"),
-            ("tso", "ts"), ("tuk", "tk"), ("tur", "tr"), ("twi", "tw"),
-            ("uig", "ug"), ("ukr", "uk"), ("urd", "ur"), ("uzb", "uz"),
-            ("ven", "ve"), ("vie", "vi"), ("vol", "vo"), ("cym", "cy"),
-            ("wel", "cy"), ("wln", "wa"), ("wol", "wo"), ("xho", "xh"),
-            ("yid", "yi"), ("yor", "yo"), ("zha", "za"), ("zul", "zu"),
+            // ISO 639-2/B "bibliographic" codes, which differ from the
+            // ISO 639-2/T and 639-3 codes for these languages.
+            ("alb", "sqi"), ("arm", "hye"), ("baq", "eus"), ("bur", "mya"),
+            ("chi", "zho"), ("cze", "ces"), ("dut", "nld"), ("fre", "fra"),
+            ("geo", "kat"), ("ger", "deu"), ("gre", "ell"), ("ice", "isl"),
+            ("mac", "mkd"), ("mao", "mri"), ("may", "msa"), ("per", "fas"),
+            ("rum", "ron"), ("slo", "slk"), ("tib", "bod"), ("wel", "cym"),
+            // Individual languages which are usually tagged using their
+            // macrolanguage in video files, but which `whatlang` reports
+            // using their own codes.
+            ("arb", "ara"), ("azj", "aze"), ("cmn", "zho"), ("ekk", "est"),
+            ("khk", "mon"), ("lvs", "lav"), ("npi", "nep"), ("pes", "fas"),
+            ("swh", "swa"), ("ydd", "yid"), ("zsm", "msa"),
         ].iter().cloned())
     };
 }
 
-/// A language identifier.
-#[derive(Clone, Copy, PartialEq, Eq)]
+/// A language identifier, with an optional script and region, as in the
+/// BCP 47 tags `zh-Hans` or `pt-BR`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Lang {
     code: [u8; 3],
+    script: Option<[u8; 4]>,
+    region: Option<[u8; 3]>,
+}
+
+/// Convert a space-padded code back to a string.
+fn code_str(code: &[u8]) -> &str {
+    // We could actually use the unsafe from_utf8_unchecked here.
+    from_utf8(code).unwrap().trim_right_matches(' ')
 }
 
 impl Lang {
@@ -86,21 +59,92 @@ impl Lang {
     /// ```
     /// use substudy::lang::Lang;
     /// assert_eq!(Lang::iso639("en").unwrap(), Lang::iso639("eng").unwrap());
+    /// assert_eq!(Lang::iso639("de").unwrap(), Lang::iso639("ger").unwrap());
+    /// assert_eq!(Lang::iso639("zh").unwrap(), Lang::iso639("cmn").unwrap());
+    /// assert_eq!(Lang::iso639("yue").unwrap().as_str(), "yue");
     /// assert!(Lang::iso639("en").unwrap() != Lang::iso639("fr").unwrap());
     /// assert!(Lang::iso639("abcd").is_err());
     /// ```
     pub fn iso639(code: &str) -> Result<Lang> {
-        let canon = CANONICAL_CODE.get(code).cloned().unwrap_or(code);
+        let lower = code.to_ascii_lowercase();
+        let lower = ISO_639_3_ALIASES.get(&lower[..]).cloned().unwrap_or(&lower[..]);
+        // Prefer ISO 639-1 codes where they exist.
+        let canon = isolang::Language::from_639_3(lower)
+            .and_then(|l| l.to_639_1())
+            .unwrap_or(lower);
         let c = canon.as_bytes();
-        match (canon.is_ascii(), c.len()) {
-            (true, 2) => Ok(Lang {
-                code: [c[0], c[1], b' '],
-            }),
-            (true, 3) => Ok(Lang {
-                code: [c[0], c[1], c[2]],
-            }),
-            _ => Err(format_err!("Unsupported language code: {}", code)),
+        let code = match (canon.is_ascii(), c.len()) {
+            (true, 2) => [c[0], c[1], b' '],
+            (true, 3) => [c[0], c[1], c[2]],
+            _ => return Err(format_err!("Unsupported language code: {}", code)),
+        };
+        Ok(Lang {
+            code: code,
+            script: None,
+            region: None,
+        })
+    }
+
+    /// Parse a BCP 47 language tag like `pt-BR`, `zh-Hans` or `sr-Latn-RS`.
+    /// The language may be any code accepted by `iso639`, and we also
+    /// accept `_` as a separator, as in POSIX locale names. We keep the
+    /// script and region, and ignore any other subtags.
+    ///
+    /// ```
+    /// use substudy::lang::Lang;
+    /// let pt_br = Lang::bcp47("pt-BR").unwrap();
+    /// assert_eq!(pt_br.to_string(), "pt-BR");
+    /// assert_eq!(pt_br.region(), Some("BR"));
+    /// assert_eq!(pt_br, Lang::bcp47("por_br").unwrap());
+    /// assert!(pt_br != Lang::bcp47("pt-PT").unwrap());
+    /// assert_eq!(Lang::bcp47("zh-hant-tw").unwrap().to_string(), "zh-Hant-TW");
+    /// assert_eq!(Lang::bcp47("es-419").unwrap().region(), Some("419"));
+    /// assert_eq!(Lang::bcp47("eng").unwrap(), Lang::iso639("en").unwrap());
+    /// assert!(Lang::bcp47("pt-BR-").is_err());
+    /// ```
+    pub fn bcp47(tag: &str) -> Result<Lang> {
+        let mut subtags = tag.split(|c| c == '-' || c == '_');
+        let mut lang = Lang::iso639(subtags.next().unwrap_or(""))?;
+        let mut subtags = subtags.peekable();
+
+        let is_alpha = |s: &str| s.chars().all(|c| c.is_ascii() && c.is_alphabetic());
+        let is_digit = |s: &str| s.chars().all(|c| c.is_digit(10));
+        if let Some(&script) = subtags.peek() {
+            if script.len() == 4 && is_alpha(script) {
+                let s = script.as_bytes();
+                lang.script = Some([
+                    s[0].to_ascii_uppercase(),
+                    s[1].to_ascii_lowercase(),
+                    s[2].to_ascii_lowercase(),
+                    s[3].to_ascii_lowercase(),
+                ]);
+                subtags.next();
+            }
+        }
+        if let Some(&region) = subtags.peek() {
+            let r = region.as_bytes();
+            if r.len() == 2 && is_alpha(region) {
+                lang.region = Some([
+                    r[0].to_ascii_uppercase(),
+                    r[1].to_ascii_uppercase(),
+                    b' ',
+                ]);
+                subtags.next();
+            } else if r.len() == 3 && is_digit(region) {
+                lang.region = Some([r[0], r[1], r[2]]);
+                subtags.next();
+            }
+        }
+        // Anything left should be a variant, extension or private-use
+        // subtag, all of which are between 1 and 8 characters.
+        for subtag in subtags {
+            if subtag.is_empty() || subtag.len() > 8
+                || !subtag.chars().all(|c| c.is_ascii() && c.is_alphanumeric())
+            {
+                return Err(format_err!("Unsupported language tag: {}", tag));
+            }
         }
+        Ok(lang)
     }
 
     /// Get the normalized language code as a `&str`.  Prefers ISO 639-1
@@ -111,15 +155,57 @@ impl Lang {
     /// assert_eq!("en", Lang::iso639("en").unwrap().as_str());
     /// assert_eq!("en", Lang::iso639("eng").unwrap().as_str());
     /// ```
+    /// This does not include the script or region; use `to_string` to get
+    /// a full BCP 47 tag.
     pub fn as_str(&self) -> &str {
-        // We could actually use the unsafe from_utf8_unchecked here.
-        if self.code[2] == b' ' {
-            from_utf8(&self.code[..2]).unwrap()
-        } else {
-            from_utf8(&self.code).unwrap()
+        code_str(&self.code)
+    }
+
+    /// The ISO 15924 script code, such as `Hans` or `Latn`, if known.
+    pub fn script(&self) -> Option<&str> {
+        self.script.as_ref().map(|s| code_str(s))
+    }
+
+    /// The ISO 3166-1 country code or UN M.49 region code, such as `BR` or
+    /// `419`, if known.
+    pub fn region(&self) -> Option<&str> {
+        self.region.as_ref().map(|r| code_str(r))
+    }
+
+    /// This language, without any script or region.
+    pub fn without_subtags(&self) -> Lang {
+        Lang {
+            code: self.code,
+            script: None,
+            region: None,
         }
     }
 
+    /// Could `self` and `other` refer to the same language? This is true if
+    /// they have the same language code, and their scripts and regions
+    /// don't conflict. This allows text detected as `pt` to match audio
+    /// tagged as `pt-BR`, but not `pt-BR` to match `pt-PT`.
+    ///
+    /// ```
+    /// use substudy::lang::Lang;
+    /// let lang = |tag| Lang::bcp47(tag).unwrap();
+    /// assert!(lang("pt").matches(lang("pt-BR")));
+    /// assert!(lang("zh-Hans").matches(lang("zh-CN")));
+    /// assert!(!lang("pt-BR").matches(lang("pt-PT")));
+    /// assert!(!lang("zh-Hans").matches(lang("zh-Hant")));
+    /// assert!(!lang("pt").matches(lang("es")));
+    /// ```
+    pub fn matches(&self, other: Lang) -> bool {
+        fn compatible<T: PartialEq>(a: Option<T>, b: Option<T>) -> bool {
+            match (a, b) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+        }
+        self.code == other.code && compatible(self.script, other.script)
+            && compatible(self.region, other.region)
+    }
+
     /// Try to determine the language of `text`.  We return `None` unless
     /// we're pretty sure.
     ///
@@ -137,17 +223,71 @@ impl Lang {
         }
         None
     }
+
+    /// Convert to an `isolang::Language`, as used by `aligned_media`, if
+    /// `isolang` knows about this language. `isolang` has no way to
+    /// represent scripts or regions, so these are lost.
+    ///
+    /// ```
+    /// use substudy::lang::Lang;
+    /// let lang = Lang::bcp47("pt-BR").unwrap();
+    /// assert_eq!(lang.to_isolang().map(|l| l.to_639_3()), Some("por"));
+    /// ```
+    pub fn to_isolang(&self) -> Option<isolang::Language> {
+        let code = self.as_str();
+        isolang::Language::from_639_1(code)
+            .or_else(|| isolang::Language::from_639_3(code))
+    }
+
+    /// Convert from an `isolang::Language`, as used by `aligned_media`.
+    ///
+    /// ```
+    /// extern crate isolang;
+    /// extern crate substudy;
+    ///
+    /// use substudy::lang::Lang;
+    ///
+    /// # fn main() {
+    /// let lang = Lang::from_isolang(isolang::Language::Cmn);
+    /// assert_eq!(lang, Lang::iso639("zh").unwrap());
+    /// # }
+    /// ```
+    pub fn from_isolang(lang: isolang::Language) -> Lang {
+        Lang::iso639(lang.to_639_1().unwrap_or_else(|| lang.to_639_3()))
+            .expect("isolang codes should always be valid")
+    }
+}
+
+impl From<isolang::Language> for Lang {
+    fn from(lang: isolang::Language) -> Lang {
+        Lang::from_isolang(lang)
+    }
+}
+
+impl FromStr for Lang {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Lang> {
+        Lang::bcp47(s)
+    }
 }
 
 impl fmt::Debug for Lang {
     fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
-        write!(f, "{}", self.as_str())
+        write!(f, "{}", self)
     }
 }
 
 impl fmt::Display for Lang {
     fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
-        write!(f, "{}", self.as_str())
+        write!(f, "{}", self.as_str())?;
+        if let Some(script) = self.script() {
+            write!(f, "-{}", script)?;
+        }
+        if let Some(region) = self.region() {
+            write!(f, "-{}", region)?;
+        }
+        Ok(())
     }
 }
 
@@ -156,6 +296,6 @@ impl Serialize for Lang {
     where
         S: Serializer,
     {
-        self.as_str().serialize(serializer)
+        self.to_string().serialize(serializer)
     }
 }
@@ -7,8 +7,11 @@
 
 #![warn(missing_docs)]
 
+extern crate aligned_media;
 extern crate cast;
 extern crate chardet;
+#[cfg(test)]
+extern crate cli_test_dir;
 extern crate common_failures;
 extern crate csv;
 #[cfg(test)]
@@ -20,29 +23,47 @@ extern crate failure;
 #[macro_use]
 extern crate failure_derive;
 extern crate handlebars;
+extern crate isolang;
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
 extern crate log;
 extern crate num;
+extern crate num_cpus;
 extern crate pbr;
 extern crate regex;
+#[macro_use]
+extern crate rusqlite;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
+extern crate sha1;
+extern crate vobsub;
 extern crate whatlang;
+extern crate zip;
 
 pub mod errors;
 pub mod contexts;
 pub mod decode;
 pub mod lang;
+pub mod format;
 pub mod srt;
+pub mod vtt;
+pub mod ass;
+pub mod microdvd;
+pub mod sbv;
+pub mod ttml;
 pub mod clean;
 pub mod merge;
 pub mod time;
+pub mod dictionary;
 pub mod align;
+pub mod sync;
 pub mod video;
+pub mod bundle;
+pub mod batch;
 pub mod export;
 
 mod grammar {
@@ -0,0 +1,162 @@
+//! MicroDVD-format subtitle support. These files are usually named `*.sub`,
+//! and they use frame numbers instead of timestamps:
+//!
+//! ```text
+//! {1}{1}23.976
+//! {1500}{1560}First line|Second line
+//! ```
+//!
+//! The optional first line specifies the frame rate. If it's missing, we
+//! assume 23.976 frames per second, which is the most common rate for these
+//! files.
+
+use common_failures::prelude::*;
+use regex::Regex;
+use std::str::FromStr;
+
+use srt::{Subtitle, SubtitleFile};
+use time::Period;
+
+/// The frame rate we assume if a file doesn't specify one.
+pub const DEFAULT_FPS: f32 = 23.976;
+
+lazy_static! {
+    /// A single MicroDVD subtitle line.
+    static ref LINE: Regex = Regex::new(r"^\{(\d+)\}\{(\d*)\}(.*)$").unwrap();
+
+    /// Control codes like `{y:i}` or `{c:$0000ff}`.
+    static ref CONTROL_CODE: Regex = Regex::new(r"\{[A-Za-z]:[^}]*\}").unwrap();
+}
+
+/// Convert a MicroDVD text line into our subtitle lines.
+fn text_to_lines(text: &str) -> Vec<String> {
+    let italic = text.contains("{y:i}") || text.contains("{Y:i}");
+    CONTROL_CODE
+        .replace_all(text, "")
+        .split('|')
+        .map(|l| {
+            // A leading "/" marks a single line as italic.
+            let l = l.trim();
+            if l.starts_with('/') {
+                format!("<i>{}</i>", l[1..].trim())
+            } else if italic && !l.is_empty() {
+                format!("<i>{}</i>", l)
+            } else {
+                l.to_owned()
+            }
+        })
+        .filter(|l| !l.is_empty())
+        .collect()
+}
+
+/// Parse MicroDVD subtitle text.
+pub fn parse(data: &str) -> Result<SubtitleFile> {
+    let mut fps = DEFAULT_FPS;
+    let mut subtitles = vec![];
+    for (line_no, line) in data.trim_left_matches("\u{FEFF}").lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mkerr = || format_err!("error parsing line {} of MicroDVD file", line_no + 1);
+        let caps = LINE.captures(line).ok_or_else(|| mkerr())?;
+        let text = caps.get(3).unwrap().as_str();
+
+        // `{1}{1}23.976` at the start of the file specifies the frame rate.
+        if subtitles.is_empty() && &caps[1] == "1" && &caps[2] == "1" {
+            if let Ok(rate) = f32::from_str(text.trim()) {
+                if !rate.is_finite() || rate <= 0.0 {
+                    return Err(format_err!("invalid MicroDVD frame rate: {}", text.trim()));
+                }
+                fps = rate;
+                continue;
+            }
+        }
+
+        let begin = u64::from_str(&caps[1]).with_context(|_| mkerr())?;
+        // An empty end frame means "show for a few seconds".
+        let end = if caps[2].is_empty() {
+            begin + (3.0 * fps) as u64
+        } else {
+            u64::from_str(&caps[2]).with_context(|_| mkerr())?
+        };
+        let (begin, mut end) = (begin as f32 / fps, end as f32 / fps);
+        if begin == end {
+            end += 0.001;
+        }
+        let lines = text_to_lines(text);
+        if lines.is_empty() {
+            continue;
+        }
+        subtitles.push(Subtitle {
+            index: subtitles.len() + 1,
+            period: Period::new(begin, end).with_context(|_| mkerr())?,
+            lines: lines,
+        });
+    }
+    Ok(SubtitleFile { subtitles: subtitles })
+}
+
+/// Return a MicroDVD representation of a subtitle file, using our default
+/// frame rate.
+pub fn to_string(file: &SubtitleFile) -> String {
+    let mut out = format!("{{1}}{{1}}{}\n", DEFAULT_FPS);
+    for sub in &file.subtitles {
+        out.push_str(&format!(
+            "{{{}}}{{{}}}{}\n",
+            (sub.period.begin() * DEFAULT_FPS).round() as u64,
+            (sub.period.end() * DEFAULT_FPS).round() as u64,
+            sub.lines.join("|")
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use microdvd::{parse, to_string};
+
+    #[test]
+    fn parse_microdvd() {
+        let data = "{1}{1}25.000
+{25}{75}First line|/Second line
+{100}{150}{y:i}Both italic|lines
+";
+        let sub = parse(data).unwrap();
+        assert_eq!(2, sub.subtitles.len());
+        assert_eq!(1.0, sub.subtitles[0].period.begin());
+        assert_eq!(3.0, sub.subtitles[0].period.end());
+        assert_eq!(
+            vec!["First line".to_owned(), "<i>Second line</i>".to_owned()],
+            sub.subtitles[0].lines
+        );
+        assert_eq!(
+            vec!["<i>Both italic</i>".to_owned(), "<i>lines</i>".to_owned()],
+            sub.subtitles[1].lines
+        );
+    }
+
+    #[test]
+    fn parse_malformed_microdvd() {
+        // Frame rates must be positive and finite.
+        for rate in &["0", "-25", "inf", "NaN"] {
+            assert!(parse(&format!("{{1}}{{1}}{}\n{{25}}{{75}}Hello\n", rate)).is_err());
+        }
+        // A header which isn't a number is an ordinary subtitle.
+        let sub = parse("{1}{1}Hello\n{25}{75}world\n").unwrap();
+        assert_eq!(2, sub.subtitles.len());
+        assert_eq!(vec!["Hello".to_owned()], sub.subtitles[0].lines);
+
+        let err = parse("{25}{75}Hello\nnot a subtitle\n").unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+        let err = parse("{75}{25}Backwards\n").unwrap_err();
+        assert!(err.to_string().contains("line 1"));
+        assert!(parse("{25}{99999999999999999999}Too long\n").is_err());
+    }
+
+    #[test]
+    fn round_trip_microdvd() {
+        let data = "{1}{1}23.976\n{24}{72}Hello|world\n";
+        assert_eq!(data, &to_string(&parse(data).unwrap()));
+    }
+}
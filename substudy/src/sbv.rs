@@ -0,0 +1,121 @@
+//! SubViewer-style `*.sbv` subtitle support, as used by YouTube:
+//!
+//! ```text
+//! 0:00:01.000,0:00:03.500
+//! First line
+//! Second line
+//! ```
+
+use common_failures::prelude::*;
+use regex::Regex;
+use std::str::FromStr;
+
+use srt::{Subtitle, SubtitleFile};
+use time::Period;
+
+lazy_static! {
+    /// The timing line at the start of each subtitle.
+    static ref TIMING: Regex =
+        Regex::new(r"^(\d+):(\d{2}):(\d{2}\.\d{3}),(\d+):(\d{2}):(\d{2}\.\d{3})$").unwrap();
+}
+
+/// Does `line` look like an SBV timing line?
+pub(crate) fn is_timing_line(line: &str) -> bool {
+    TIMING.is_match(line.trim())
+}
+
+/// Format seconds using the SBV time format.
+pub fn format_time(time: f32) -> String {
+    let (h, rem) = ((time / 3600.0).trunc(), time % 3600.0);
+    let (m, s) = ((rem / 60.0).trunc(), rem % 60.0);
+    format!("{}:{:02}:{:0>6.3}", h, m, s)
+}
+
+/// Parse SBV subtitle text.
+pub fn parse(data: &str) -> Result<SubtitleFile> {
+    let data = data.trim_left_matches("\u{FEFF}").replace("\r\n", "\n");
+    let mut subtitles = vec![];
+    for block in data.split("\n\n") {
+        let mut lines = block.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+        let timing = match lines.next() {
+            Some(timing) => timing,
+            None => continue,
+        };
+        let caps = TIMING
+            .captures(timing)
+            .ok_or_else(|| format_err!("invalid SBV timing line: {:?}", timing))?;
+        let time = |i: usize| -> f32 {
+            let h = f32::from_str(&caps[i]).expect("regex should have validated hours");
+            let m = f32::from_str(&caps[i + 1]).expect("regex should have validated minutes");
+            let s = f32::from_str(&caps[i + 2]).expect("regex should have validated seconds");
+            h * 3600.0 + m * 60.0 + s
+        };
+        let (begin, mut end) = (time(1), time(4));
+        if begin == end {
+            end += 0.001;
+        }
+        let text: Vec<String> = lines.map(|l| l.to_owned()).collect();
+        if text.is_empty() {
+            continue;
+        }
+        subtitles.push(Subtitle {
+            index: subtitles.len() + 1,
+            period: Period::new(begin, end)
+                .with_context(|_| format_err!("invalid SBV timing line: {:?}", timing))?,
+            lines: text,
+        });
+    }
+    Ok(SubtitleFile { subtitles: subtitles })
+}
+
+/// Return an SBV representation of a subtitle file.
+pub fn to_string(file: &SubtitleFile) -> String {
+    let subs: Vec<String> = file.subtitles
+        .iter()
+        .map(|sub| {
+            format!(
+                "{},{}\n{}\n",
+                format_time(sub.period.begin()),
+                format_time(sub.period.end()),
+                sub.lines.join("\n")
+            )
+        })
+        .collect();
+    subs.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use sbv::{parse, to_string};
+
+    #[test]
+    fn round_trip_sbv() {
+        let data = "0:01:02.328,0:01:04.664
+¡Si! ¡Aang ha vuelto!
+
+0:01:13.840,0:01:16.579
+Tu diste la señal a la armada
+del fuego con la bengala,
+";
+        let sbv = parse(data).unwrap();
+        assert_eq!(2, sbv.subtitles.len());
+        assert_eq!(62.328, sbv.subtitles[0].period.begin());
+        assert_eq!(2, sbv.subtitles[1].lines.len());
+        assert_eq!(data, &to_string(&sbv));
+    }
+
+    #[test]
+    fn parse_malformed_sbv() {
+        for timing in &["0:01:02.328", "0:01:02,0:01:04", "1:2:3.000,1:2:4.000", "hello"] {
+            let err = parse(&format!("{}\nText\n", timing)).unwrap_err();
+            assert!(err.to_string().contains("invalid SBV timing line"));
+        }
+        let err = parse("0:01:04.664,0:01:02.328\nBackwards\n").unwrap_err();
+        assert!(err.to_string().contains("invalid SBV timing line"));
+
+        // Subtitles without any text are skipped.
+        let sbv = parse("0:01:02.328,0:01:04.664\n\n0:01:13.840,0:01:16.579\nText\n").unwrap();
+        assert_eq!(1, sbv.subtitles.len());
+        assert_eq!(73.84, sbv.subtitles[0].period.begin());
+    }
+}
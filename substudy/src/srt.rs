@@ -5,11 +5,18 @@ use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 
+use ass::AssFile;
 use decode::smart_decode;
 use clean::{clean_subtitle_file, strip_formatting};
+use format::Format;
 use grammar;
 use lang::Lang;
-use time::Period;
+use microdvd;
+use sbv;
+use time::{Period, Retiming};
+use ttml;
+use vobsub;
+use vtt;
 
 /// Format seconds using the standard SRT time format.
 pub fn format_time(time: f32) -> String {
@@ -51,7 +58,7 @@ impl Subtitle {
     }
 }
 
-/// The contents of an SRT-format subtitle file.
+/// The contents of a subtitle file.
 #[derive(Debug, PartialEq)]
 pub struct SubtitleFile {
     /// The subtitles in this file.
@@ -69,13 +76,37 @@ impl SubtitleFile {
             .with_context(|_| format_err!("could not parse subtitles"))?)
     }
 
-    /// Parse the subtitle file found at the specified path.
+    /// Parse raw subtitle text in the specified format.
+    pub fn from_str_as(data: &str, format: Format) -> Result<SubtitleFile> {
+        match format {
+            Format::Srt => SubtitleFile::from_str(data),
+            Format::Vtt => vtt::parse(data),
+            Format::Ass => Ok(AssFile::from_str(data)?.to_subtitle_file()),
+            Format::MicroDvd => microdvd::parse(data),
+            Format::Sbv => sbv::parse(data),
+            Format::Ttml => ttml::parse(data),
+        }
+    }
+
+    /// Parse the subtitle file found at the specified path. We try to detect
+    /// the format from the file's contents, and fall back to using the file
+    /// extension.
     pub fn from_path(path: &Path) -> Result<SubtitleFile> {
+        // VobSub `*.idx` and `*.sub` files contain bitmap images, which we
+        // can't read.
+        if vobsub::is_idx_file(path)? || vobsub::is_sub_file(path)? {
+            let err: Error = format_err!(
+                "VobSub subtitles are images, not text; please convert them \
+                 to *.srt first (using an OCR tool like subtitles2srt)"
+            );
+            Err(err).io_read_context(path)?;
+        }
         let mut file = File::open(path).io_read_context(path)?;
         let mut bytes = Vec::new();
         file.read_to_end(&mut bytes).io_read_context(path)?;
         let data = smart_decode(&bytes).io_read_context(path)?;
-        Ok(SubtitleFile::from_str(&data).io_read_context(path)?)
+        let format = Format::detect(&data).unwrap_or_else(|| Format::for_path(path));
+        Ok(SubtitleFile::from_str_as(&data, format).io_read_context(path)?)
     }
 
     /// Parse and normalize the subtitle file found at the specified path.
@@ -95,6 +126,18 @@ impl SubtitleFile {
         format!("\u{FEFF}{}", subs.join("\n"))
     }
 
+    /// Convert subtitles to a string in the specified format.
+    pub fn to_string_as(&self, format: Format) -> String {
+        match format {
+            Format::Srt => self.to_string(),
+            Format::Vtt => vtt::to_string(self),
+            Format::Ass => AssFile::from_subtitle_file(self).to_string(),
+            Format::MicroDvd => microdvd::to_string(self),
+            Format::Sbv => sbv::to_string(self),
+            Format::Ttml => ttml::to_string(self),
+        }
+    }
+
     /// Find the subtitle with the given index.
     pub fn find(&self, index: usize) -> Option<&Subtitle> {
         self.subtitles.iter().find(|s| s.index == index)
@@ -106,6 +149,38 @@ impl SubtitleFile {
         let text = subs.join("\n");
         Lang::for_text(&text)
     }
+
+    /// Apply `retiming` to every subtitle in this file. Returns an error if
+    /// any subtitle would end up with an inverted or negative period.
+    pub fn retime(&self, retiming: Retiming) -> Result<SubtitleFile> {
+        let mut subtitles = Vec::with_capacity(self.subtitles.len());
+        for sub in &self.subtitles {
+            let period = sub.period.retime(retiming).with_context(|_| {
+                format_err!("could not retime subtitle {}", sub.index)
+            })?;
+            subtitles.push(Subtitle {
+                index: sub.index,
+                period: period,
+                lines: sub.lines.clone(),
+            });
+        }
+        Ok(SubtitleFile { subtitles: subtitles })
+    }
+
+    /// Compute the retiming which moves the subtitle `index1` to begin at
+    /// `time1`, and `index2` to begin at `time2`.
+    pub fn fit_retiming(
+        &self,
+        (index1, time1): (usize, f32),
+        (index2, time2): (usize, f32),
+    ) -> Result<Retiming> {
+        let begin = |index: usize| -> Result<f32> {
+            self.find(index)
+                .map(|sub| sub.period.begin())
+                .ok_or_else(|| format_err!("no subtitle with index {}", index))
+        };
+        Retiming::fit((begin(index1)?, time1), (begin(index2)?, time2))
+    }
 }
 
 #[cfg(test)]
@@ -113,7 +188,7 @@ mod test {
     use std::path::Path;
     use srt::{Subtitle, SubtitleFile};
     use lang::Lang;
-    use time::Period;
+    use time::{Period, Retiming};
 
     #[test]
     fn subtitle_file_from_path() {
@@ -189,4 +264,37 @@ Text
         let srt_en = SubtitleFile::from_path(&path_en).unwrap();
         assert_eq!(Some(Lang::iso639("en").unwrap()), srt_en.detect_language());
     }
+
+    #[test]
+    fn retime_subtitle_file() {
+        let path = Path::new("fixtures/sample.es.srt");
+        let srt = SubtitleFile::from_path(&path).unwrap();
+        let first = srt.subtitles[0].period.begin();
+        let last = srt.subtitles[4].period.begin();
+
+        let retiming = srt.fit_retiming((16, 10.0), (20, 20.0)).unwrap();
+        let retimed = srt.retime(retiming).unwrap();
+        assert!((retimed.subtitles[0].period.begin() - 10.0).abs() < 0.01);
+        assert!((retimed.subtitles[4].period.begin() - 20.0).abs() < 0.01);
+        assert_eq!(srt.subtitles[2].lines, retimed.subtitles[2].lines);
+
+        assert!(srt.fit_retiming((16, last), (20, first)).is_err());
+        assert!(srt.retime(Retiming::offset(-first - 1.0)).is_err());
+    }
+
+    #[test]
+    fn subtitle_file_from_vtt_path() {
+        let path = Path::new("fixtures/sample.es.vtt");
+        let vtt = SubtitleFile::from_path(&path).unwrap();
+        let srt = SubtitleFile::from_path(Path::new("fixtures/sample.es.srt")).unwrap();
+        assert_eq!(srt, vtt);
+    }
+
+    #[test]
+    fn subtitle_file_from_vobsub_path() {
+        for path in &["../fixtures/example.idx", "../fixtures/example.sub"] {
+            let err = SubtitleFile::from_path(Path::new(path)).unwrap_err();
+            assert!(err.causes().any(|c| c.to_string().contains("VobSub")));
+        }
+    }
 }
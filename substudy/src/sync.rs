@@ -0,0 +1,476 @@
+//! Synchronize subtitles against the speech in a video's soundtrack.
+//!
+//! We decode the audio using ffmpeg, run a simple energy-based voice
+//! activity detector over it, and then search for the timing correction
+//! which best lines up subtitles with speech. This happens in two stages:
+//!
+//! 1. We look for a global offset (and possibly a frame-rate correction)
+//!    for the entire file.
+//! 2. We split the file into segments, look for a small additional offset
+//!    for each one, and interpolate between them to correct for drift.
+
+use common_failures::prelude::*;
+
+use srt::{Subtitle, SubtitleFile};
+use time::{Period, Retiming};
+use video::Video;
+
+/// The sample rate we use when decoding audio for speech detection.
+pub const SAMPLE_RATE: u32 = 16000;
+
+/// The length of each frame analyzed by our speech detector, in seconds.
+pub const FRAME_DURATION: f32 = 0.01;
+
+/// How many frames of speech to assume after speech stops, to smooth over
+/// short pauses between words.
+const HANGOVER_FRAMES: usize = 20;
+
+/// Scales which correct for the most common frame-rate mismatches.
+const FRAME_RATE_SCALES: &[f32] = &[
+    1.0,
+    25.0 / 23.976,
+    23.976 / 25.0,
+    24.0 / 23.976,
+    23.976 / 24.0,
+    25.0 / 24.0,
+    24.0 / 25.0,
+];
+
+/// Ignore drift segments containing fewer subtitles than this, because
+/// they don't give us enough data to work with.
+const MIN_SEGMENT_SUBTITLES: usize = 5;
+
+/// Which parts of a soundtrack appear to contain speech?
+#[derive(Debug)]
+pub struct SpeechMap {
+    /// One entry per `FRAME_DURATION` of audio, which is true if the frame
+    /// appears to contain speech.
+    pub frames: Vec<bool>,
+    /// Running totals of our scores, with one more entry than `frames`.
+    totals: Vec<i64>,
+}
+
+impl SpeechMap {
+    /// Create a speech map from a list of per-frame flags.
+    pub fn new(frames: Vec<bool>) -> SpeechMap {
+        let mut totals = Vec::with_capacity(frames.len() + 1);
+        let mut total = 0;
+        totals.push(total);
+        for &speech in &frames {
+            total += if speech { 1 } else { -1 };
+            totals.push(total);
+        }
+        SpeechMap {
+            frames: frames,
+            totals: totals,
+        }
+    }
+
+    /// Detect speech in mono audio samples recorded at `sample_rate`. We
+    /// compare the energy of each frame against the quiet and loud parts of
+    /// the soundtrack, so this works best on dialog-heavy audio without
+    /// much background music.
+    pub fn from_samples(samples: &[i16], sample_rate: u32) -> SpeechMap {
+        let frame_len = ((sample_rate as f32 * FRAME_DURATION) as usize).max(1);
+        let energies: Vec<f32> = samples
+            .chunks(frame_len)
+            .map(|frame| {
+                let sum: f32 = frame.iter().map(|&s| (s as f32) * (s as f32)).sum();
+                10.0 * (sum / frame.len() as f32 + 1.0).log10()
+            })
+            .collect();
+        if energies.is_empty() {
+            return SpeechMap::new(vec![]);
+        }
+
+        // Put our threshold part of the way between the noise floor and
+        // typical loud speech.
+        let mut sorted = energies.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).expect("energy should not be NaN"));
+        let floor = sorted[sorted.len() / 10];
+        let peak = sorted[sorted.len() * 9 / 10];
+        if peak - floor < 6.0 {
+            return SpeechMap::new(vec![false; energies.len()]);
+        }
+        let threshold = floor + (peak - floor) * 0.35;
+
+        let mut frames = vec![false; energies.len()];
+        let mut hangover = 0;
+        for (i, &energy) in energies.iter().enumerate() {
+            if energy >= threshold {
+                hangover = HANGOVER_FRAMES;
+                frames[i] = true;
+            } else if hangover > 0 {
+                hangover -= 1;
+                frames[i] = true;
+            }
+        }
+        SpeechMap::new(frames)
+    }
+
+    /// Decode the specified audio stream from `video` and detect speech.
+    pub fn from_video(video: &Video, stream: Option<usize>) -> Result<SpeechMap> {
+        let samples = video.audio_samples(stream, SAMPLE_RATE)?;
+        Ok(SpeechMap::from_samples(&samples, SAMPLE_RATE))
+    }
+
+    /// Score the frames from `begin` up to `end`, adding one for each
+    /// speech frame and subtracting one for anything else, including
+    /// frames outside the soundtrack.
+    fn score(&self, begin: i64, end: i64) -> i64 {
+        let len = self.frames.len() as i64;
+        let clamp = |f: i64| f.max(0).min(len);
+        let (b, e) = (clamp(begin), clamp(end.max(begin)));
+        let inside = self.totals[e as usize] - self.totals[b as usize];
+        inside - ((end.max(begin) - begin) - (e - b))
+    }
+
+    /// Score how well `periods` line up with speech once `retiming` has
+    /// been applied.
+    fn score_periods(&self, periods: &[Period], retiming: Retiming) -> i64 {
+        periods
+            .iter()
+            .map(|p| self.score(frame(retiming.apply(p.begin())), frame(retiming.apply(p.end()))))
+            .sum()
+    }
+}
+
+/// Convert a time to the index of the frame containing it.
+fn frame(time: f32) -> i64 {
+    (time / FRAME_DURATION).round() as i64
+}
+
+/// Options controlling how we synchronize subtitles.
+#[derive(Clone, Debug)]
+pub struct SyncOptions {
+    /// The largest global offset to consider, in seconds.
+    pub max_offset: f32,
+    /// Also try correcting for common frame-rate mismatches.
+    pub detect_frame_rate: bool,
+    /// The length of the segments used to correct drift, in seconds.
+    pub segment_duration: f32,
+    /// The largest additional offset to allow for each segment, in seconds.
+    pub max_drift: f32,
+}
+
+impl Default for SyncOptions {
+    fn default() -> SyncOptions {
+        SyncOptions {
+            max_offset: 60.0,
+            detect_frame_rate: true,
+            segment_duration: 300.0,
+            max_drift: 2.0,
+        }
+    }
+}
+
+/// A timing correction found by `synchronize`.
+#[derive(Clone, Debug)]
+pub struct Synchronization {
+    /// The global correction applied to every subtitle.
+    pub retiming: Retiming,
+    /// Additional offsets used to correct for drift, as `(time, offset)`
+    /// pairs sorted by time, where `time` has already been corrected using
+    /// `retiming`. We interpolate linearly between these points.
+    pub drift: Vec<(f32, f32)>,
+}
+
+impl Synchronization {
+    /// The additional drift correction to apply at `time`.
+    pub fn drift_at(&self, time: f32) -> f32 {
+        match (self.drift.first(), self.drift.last()) {
+            (Some(&(t0, d0)), _) if time <= t0 => d0,
+            (_, Some(&(tn, dn))) if time >= tn => dn,
+            (None, _) | (_, None) => 0.0,
+            _ => {
+                let i = self.drift
+                    .iter()
+                    .position(|&(t, _)| t > time)
+                    .expect("time should be inside drift range");
+                let ((t0, d0), (t1, d1)) = (self.drift[i - 1], self.drift[i]);
+                d0 + (d1 - d0) * (time - t0) / (t1 - t0)
+            }
+        }
+    }
+
+    /// Apply this correction to a subtitle file.
+    pub fn apply(&self, file: &SubtitleFile) -> Result<SubtitleFile> {
+        let mut subtitles = Vec::with_capacity(file.subtitles.len());
+        for sub in &file.subtitles {
+            let period = sub.period.retime(self.retiming)?;
+            let drift = self.drift_at(period.midpoint()).max(-period.begin());
+            let period = period.retime(Retiming::offset(drift)).with_context(|_| {
+                format_err!("could not synchronize subtitle {}", sub.index)
+            })?;
+            subtitles.push(Subtitle {
+                index: sub.index,
+                period: period,
+                lines: sub.lines.clone(),
+            });
+        }
+        Ok(SubtitleFile { subtitles: subtitles })
+    }
+}
+
+/// Find the offset in `[min, max]` which best aligns `periods` with speech
+/// after applying `scale`. Returns the offset and its score. We do a coarse
+/// search first, and then refine it.
+fn best_offset(
+    speech: &SpeechMap,
+    periods: &[Period],
+    scale: f32,
+    min: f32,
+    max: f32,
+) -> (f32, i64) {
+    let search = |lo: i64, hi: i64, step: i64| -> (i64, i64) {
+        let mut best: (i64, i64) = (0, i64::min_value());
+        let mut f = lo;
+        while f <= hi {
+            let retiming = Retiming::new(scale, f as f32 * FRAME_DURATION);
+            let score = speech.score_periods(periods, retiming);
+            // Prefer smaller corrections when scores are tied.
+            if score > best.1 || (score == best.1 && f.abs() < best.0.abs()) {
+                best = (f, score);
+            }
+            f += step;
+        }
+        best
+    };
+    let (lo, hi) = (frame(min), frame(max));
+    let (coarse, _) = search(lo, hi, 10);
+    let (fine, score) = search((coarse - 10).max(lo), (coarse + 10).min(hi), 1);
+    (fine as f32 * FRAME_DURATION, score)
+}
+
+/// Find a timing correction which lines up the subtitles in `file` with
+/// the speech in `speech`.
+pub fn synchronize(
+    file: &SubtitleFile,
+    speech: &SpeechMap,
+    options: &SyncOptions,
+) -> Synchronization {
+    let periods: Vec<Period> = file.subtitles.iter().map(|s| s.period).collect();
+    if periods.is_empty() {
+        return Synchronization {
+            retiming: Retiming::offset(0.0),
+            drift: vec![],
+        };
+    }
+
+    // Find our global correction. Don't let subtitles go negative.
+    let first = periods.iter().map(|p| p.begin()).fold(periods[0].begin(), f32::min);
+    let scales = if options.detect_frame_rate { FRAME_RATE_SCALES } else { &FRAME_RATE_SCALES[..1] };
+    let mut best: Option<(Retiming, i64)> = None;
+    for &scale in scales {
+        let min = (-options.max_offset).max(-first * scale);
+        let (offset, score) = best_offset(speech, &periods, scale, min, options.max_offset);
+        if best.map_or(true, |(_, best_score)| score > best_score) {
+            best = Some((Retiming::new(scale, offset), score));
+        }
+    }
+    let (retiming, _) = best.expect("should have tried at least one scale");
+    debug!("global sync correction: {:?}", retiming);
+
+    // Look for drift in each segment.
+    let corrected: Vec<Period> = periods
+        .iter()
+        .filter_map(|p| p.retime(retiming).ok())
+        .collect();
+    let mut drift = vec![];
+    let mut start = 0;
+    while start < corrected.len() {
+        let limit = corrected[start].begin() + options.segment_duration;
+        let mut end = start;
+        while end < corrected.len() && corrected[end].begin() < limit {
+            end += 1;
+        }
+        let segment = &corrected[start..end];
+        if segment.len() >= MIN_SEGMENT_SUBTITLES {
+            let seg_first = segment[0].begin();
+            let min = (-options.max_drift).max(-seg_first);
+            let (offset, _) = best_offset(speech, segment, 1.0, min, options.max_drift);
+            let mid = (seg_first + segment[segment.len() - 1].end()) / 2.0;
+            drift.push((mid, offset));
+        }
+        start = end;
+    }
+    debug!("sync drift corrections: {:?}", drift);
+
+    Synchronization {
+        retiming: retiming,
+        drift: drift,
+    }
+}
+
+/// Synchronize `file` against the speech in the specified audio stream of
+/// `video`.
+pub fn sync_to_video(
+    file: &SubtitleFile,
+    video: &Video,
+    stream: Option<usize>,
+    options: &SyncOptions,
+) -> Result<(Synchronization, SubtitleFile)> {
+    let speech = SpeechMap::from_video(video, stream)?;
+    let sync = synchronize(file, &speech, options);
+    let synced = sync.apply(file)?;
+    Ok((sync, synced))
+}
+
+#[cfg(test)]
+mod test {
+    use srt::{Subtitle, SubtitleFile};
+    use sync::{synchronize, SpeechMap, SyncOptions, Synchronization, FRAME_DURATION};
+    use time::{Period, Retiming};
+
+    /// Generate irregularly-spaced speech periods, one every 7 seconds or so,
+    /// starting before `end`.
+    fn spoken_until(end: f32) -> Vec<(f32, f32)> {
+        (0..)
+            .map(|i| {
+                let begin = 5.0 + i as f32 * 7.0 + (i % 3) as f32;
+                (begin, begin + 2.0 + (i % 4) as f32 * 0.5)
+            })
+            .take_while(|&(begin, _)| begin < end)
+            .collect()
+    }
+
+    /// Build a speech map with speech during each of `periods`.
+    fn speech_for(periods: &[(f32, f32)], duration: f32) -> SpeechMap {
+        let mut frames = vec![false; (duration / FRAME_DURATION) as usize];
+        for &(begin, end) in periods {
+            let (b, e) = ((begin / FRAME_DURATION) as usize, (end / FRAME_DURATION) as usize);
+            for f in &mut frames[b..e] {
+                *f = true;
+            }
+        }
+        SpeechMap::new(frames)
+    }
+
+    fn subs_for(periods: &[(f32, f32)]) -> SubtitleFile {
+        SubtitleFile {
+            subtitles: periods
+                .iter()
+                .enumerate()
+                .map(|(i, &(b, e))| Subtitle {
+                    index: i + 1,
+                    period: Period::new(b, e).unwrap(),
+                    lines: vec!["Hola".to_owned()],
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn detect_speech_in_samples() {
+        let mut samples = vec![0i16; 16000];
+        for (i, s) in samples[4000..8000].iter_mut().enumerate() {
+            *s = ((i as f32 * 0.1).sin() * 8000.0) as i16;
+        }
+        let speech = SpeechMap::from_samples(&samples, 16000);
+        assert_eq!(100, speech.frames.len());
+        assert!(!speech.frames[10]);
+        assert!(speech.frames[30]);
+        assert!(speech.frames[49]);
+        assert!(!speech.frames[90]);
+    }
+
+    #[test]
+    fn synchronize_with_offset() {
+        let spoken: Vec<(f32, f32)> = (0..40)
+            .map(|i| {
+                let begin = 5.0 + i as f32 * 7.0 + (i % 3) as f32;
+                (begin, begin + 2.0 + (i % 4) as f32 * 0.5)
+            })
+            .collect();
+        let speech = speech_for(&spoken, 320.0);
+        let late: Vec<(f32, f32)> = spoken.iter().map(|&(b, e)| (b + 3.5, e + 3.5)).collect();
+        let file = subs_for(&late);
+
+        let sync = synchronize(&file, &speech, &SyncOptions::default());
+        assert_eq!(1.0, sync.retiming.scale);
+        assert!((sync.retiming.offset + 3.5).abs() < 0.02);
+        let synced = sync.apply(&file).unwrap();
+        assert!((synced.subtitles[0].period.begin() - spoken[0].0).abs() < 0.02);
+    }
+
+    #[test]
+    fn synchronize_with_frame_rate_scale() {
+        let spoken = spoken_until(600.0);
+        let speech = speech_for(&spoken, 640.0);
+        let scale = 25.0 / 23.976;
+        let slow: Vec<(f32, f32)> = spoken.iter().map(|&(b, e)| (b / scale, e / scale)).collect();
+        let file = subs_for(&slow);
+
+        let sync = synchronize(&file, &speech, &SyncOptions::default());
+        assert_eq!(scale, sync.retiming.scale);
+        assert!(sync.retiming.offset.abs() < 0.02);
+        let synced = sync.apply(&file).unwrap();
+        for (sub, &(begin, _)) in synced.subtitles.iter().zip(&spoken) {
+            assert!((sub.period.begin() - begin).abs() < 0.03);
+        }
+    }
+
+    #[test]
+    fn synchronize_with_gradual_drift() {
+        // The subtitles start 0.5s late and finish 2.5s late.
+        let spoken = spoken_until(900.0);
+        let speech = speech_for(&spoken, 940.0);
+        let late = |t: f32| t + 0.5 + 2.0 * t / 900.0;
+        let drifting: Vec<(f32, f32)> = spoken.iter().map(|&(b, e)| (late(b), late(e))).collect();
+        let file = subs_for(&drifting);
+
+        let options = SyncOptions {
+            detect_frame_rate: false,
+            segment_duration: 60.0,
+            ..SyncOptions::default()
+        };
+        let sync = synchronize(&file, &speech, &options);
+        assert!(sync.drift.len() > 10);
+        let synced = sync.apply(&file).unwrap();
+
+        // Between the first and last drift points, we should interpolate to
+        // within a frame or two of the speech.
+        let (first, last) = (sync.drift[0].0, sync.drift[sync.drift.len() - 1].0);
+        for (sub, &(begin, _)) in synced.subtitles.iter().zip(&spoken) {
+            let error = (sub.period.begin() - begin).abs();
+            if begin >= first && begin <= last {
+                assert!(error <= 2.0 * FRAME_DURATION + 0.001, "{} off by {}", begin, error);
+            } else {
+                assert!(error < 0.1, "{} off by {}", begin, error);
+            }
+        }
+    }
+
+    #[test]
+    fn drift_at_interpolates_and_clamps() {
+        let sync = Synchronization {
+            retiming: Retiming::offset(0.0),
+            drift: vec![(10.0, 1.0), (20.0, 3.0), (40.0, -1.0)],
+        };
+        assert_eq!(1.0, sync.drift_at(0.0));
+        assert_eq!(1.0, sync.drift_at(10.0));
+        assert_eq!(2.0, sync.drift_at(15.0));
+        assert_eq!(3.0, sync.drift_at(20.0));
+        assert_eq!(1.0, sync.drift_at(30.0));
+        assert_eq!(-1.0, sync.drift_at(40.0));
+        assert_eq!(-1.0, sync.drift_at(100.0));
+
+        let no_drift = Synchronization {
+            retiming: Retiming::offset(0.0),
+            drift: vec![],
+        };
+        assert_eq!(0.0, no_drift.drift_at(15.0));
+    }
+
+    #[test]
+    fn apply_does_not_move_subtitles_before_zero() {
+        let sync = Synchronization {
+            retiming: Retiming::offset(-1.0),
+            drift: vec![(0.0, -2.0)],
+        };
+        let file = subs_for(&[(2.0, 4.0), (10.0, 12.0)]);
+        let synced = sync.apply(&file).unwrap();
+        assert_eq!(Period::new(0.0, 2.0).unwrap(), synced.subtitles[0].period);
+        assert_eq!(Period::new(7.0, 9.0).unwrap(), synced.subtitles[1].period);
+    }
+}
@@ -4,6 +4,7 @@ use common_failures::prelude::*;
 use serde::{Serialize, Serializer};
 use serde::ser::SerializeTuple;
 use std::result;
+use std::str::FromStr;
 
 /// The minimum spacing between two points in time to count as
 /// unambiguously different.  This is related to the typical precision used
@@ -44,6 +45,34 @@ pub fn seconds_to_hhmmss_sss(time: f32) -> String {
     format!("{}:{:02}:{:06.3}", hours, mins, seconds)
 }
 
+/// Parses a human-readable time like `62.5`, `1:02.5` or `0:01:02,500`
+/// into seconds.
+///
+/// ```
+/// use substudy::time::parse_hhmmss;
+/// assert_eq!(62.5, parse_hhmmss("62.5").unwrap());
+/// assert_eq!(62.5, parse_hhmmss("1:02.5").unwrap());
+/// assert_eq!(3662.5, parse_hhmmss("1:01:02,500").unwrap());
+/// assert!(parse_hhmmss("1:2:3:4").is_err());
+/// ```
+pub fn parse_hhmmss(time: &str) -> Result<f32> {
+    let mkerr = || format_err!("cannot parse time {:?}", time);
+    let parts: Vec<&str> = time.trim().split(':').collect();
+    if parts.len() > 3 {
+        return Err(mkerr());
+    }
+    let mut seconds = 0.0;
+    for part in &parts {
+        let value = f32::from_str(&part.replace(",", "."))
+            .map_err(|_| mkerr())?;
+        if value < 0.0 {
+            return Err(mkerr());
+        }
+        seconds = seconds * 60.0 + value;
+    }
+    Ok(seconds)
+}
+
 /// A period of time, in seconds.  The beginning is guaranteed to be less
 /// than the end, and all times are positive.  This is lightweight
 /// structure which implements `Copy`, so it can be passed by value.
@@ -230,6 +259,97 @@ impl Period {
     pub fn overlap(&self, other: Period) -> f32 {
         (self.end.min(other.end) - self.begin.max(other.begin)).max(0.0)
     }
+
+    /// Apply `retiming` to this time period. Very short periods will be
+    /// stretched to last at least `MIN_SPACING`, and periods which would
+    /// become inverted or negative return an error.
+    ///
+    /// ```
+    /// use substudy::time::{Period, Retiming};
+    ///
+    /// let period = Period::new(1.0, 2.0).unwrap();
+    /// assert_eq!(Period::new(3.0, 5.0).unwrap(),
+    ///            period.retime(Retiming::new(2.0, 1.0)).unwrap());
+    /// assert!(period.retime(Retiming::offset(-1.5)).is_err());
+    /// ```
+    pub fn retime(&self, retiming: Retiming) -> Result<Period> {
+        let begin = retiming.apply(self.begin);
+        let mut end = retiming.apply(self.end);
+        if end >= begin && end - begin < MIN_SPACING {
+            end = begin + MIN_SPACING;
+        }
+        Period::new(begin, end)
+    }
+}
+
+/// A linear correction to subtitle timing, which maps each time `t` to
+/// `t * scale + offset`. This can fix subtitles which are off by a fixed
+/// amount, or which drift because they were timed for a different frame
+/// rate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Retiming {
+    /// The amount to stretch each time by.
+    pub scale: f32,
+    /// The number of seconds to add after scaling.
+    pub offset: f32,
+}
+
+impl Retiming {
+    /// Create a new retiming.
+    pub fn new(scale: f32, offset: f32) -> Retiming {
+        Retiming {
+            scale: scale,
+            offset: offset,
+        }
+    }
+
+    /// Shift all times by `offset` seconds.
+    pub fn offset(offset: f32) -> Retiming {
+        Retiming::new(1.0, offset)
+    }
+
+    /// Multiply all times by `scale`.
+    pub fn scale(scale: f32) -> Retiming {
+        Retiming::new(scale, 0.0)
+    }
+
+    /// Compute the retiming which maps `from1` to `to1` and `from2` to
+    /// `to2`. The two points must be at least `MIN_SPACING` apart, and they
+    /// must not swap order.
+    ///
+    /// ```
+    /// use substudy::time::Retiming;
+    ///
+    /// let retiming = Retiming::fit((10.0, 12.0), (20.0, 32.0)).unwrap();
+    /// assert_eq!(Retiming::new(2.0, -8.0), retiming);
+    /// assert!(Retiming::fit((10.0, 12.0), (20.0, 11.0)).is_err());
+    /// ```
+    pub fn fit(
+        (from1, to1): (f32, f32),
+        (from2, to2): (f32, f32),
+    ) -> Result<Retiming> {
+        if (from2 - from1).abs() < MIN_SPACING || (to2 - to1).abs() < MIN_SPACING {
+            return Err(format_err!(
+                "cannot retime using two points which are too close together"
+            ));
+        }
+        let scale = (to2 - to1) / (from2 - from1);
+        if scale <= 0.0 {
+            return Err(format_err!(
+                "cannot retime {} to {} and {} to {} without reversing time",
+                from1,
+                to1,
+                from2,
+                to2
+            ));
+        }
+        Ok(Retiming::new(scale, to1 - from1 * scale))
+    }
+
+    /// Apply this retiming to a single point in time.
+    pub fn apply(&self, time: f32) -> f32 {
+        time * self.scale + self.offset
+    }
 }
 
 impl Serialize for Period {
@@ -0,0 +1,268 @@
+//! Timed Text Markup Language (TTML, also known as DFXP) subtitle support.
+//!
+//! TTML is a large XML-based format, but subtitle files in the wild only use
+//! a small part of it: a list of `<p begin="..." end="...">` elements,
+//! possibly containing `<br/>` and `<span>` elements. That's all we
+//! support.
+
+use common_failures::prelude::*;
+use regex::{Captures, Regex};
+use std::str::FromStr;
+
+use clean::strip_formatting;
+use srt::{Subtitle, SubtitleFile};
+use time::Period;
+
+lazy_static! {
+    /// A `<p>` element containing a single subtitle.
+    static ref PARAGRAPH: Regex = Regex::new(r"(?s)<p\b([^>]*)>(.*?)</p>").unwrap();
+
+    /// An attribute of an XML element.
+    static ref ATTRIBUTE: Regex =
+        Regex::new(r#"([-A-Za-z:]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap();
+
+    /// Whitespace, which isn't significant in XML.
+    static ref WHITESPACE: Regex = Regex::new(r"\s+").unwrap();
+
+    /// A line break.
+    static ref BREAK: Regex = Regex::new(r"<br\s*/?>").unwrap();
+
+    /// Any other tag.
+    static ref TAG: Regex = Regex::new(r"<[^>]*>").unwrap();
+
+    /// An XML entity.
+    static ref ENTITY: Regex = Regex::new(r"&(#x[0-9A-Fa-f]+|#[0-9]+|[a-z]+);").unwrap();
+
+    /// A clock time like `00:01:02.500` or `00:01:02:12` (with frames).
+    static ref CLOCK_TIME: Regex =
+        Regex::new(r"^(\d+):(\d{2}):(\d{2}(?:\.\d+)?)(?::(\d+))?$").unwrap();
+
+    /// An offset time like `62.5s`, `1500ms` or `625000000t`.
+    static ref OFFSET_TIME: Regex = Regex::new(r"^(\d+(?:\.\d+)?)(h|m|s|ms|f|t)$").unwrap();
+}
+
+/// Timing parameters from the root `<tt>` element.
+struct TimeBase {
+    frame_rate: f32,
+    tick_rate: f32,
+}
+
+/// Get the value of the attribute `name` from an attribute string, ignoring
+/// any namespace prefix.
+fn attribute<'a>(attrs: &'a str, name: &str) -> Option<&'a str> {
+    ATTRIBUTE.captures_iter(attrs).find(|c| {
+        let key = c.get(1).unwrap().as_str();
+        key == name || key.ends_with(&format!(":{}", name))
+    }).map(|c| c.get(2).or_else(|| c.get(3)).unwrap().as_str())
+}
+
+/// Parse a TTML time expression.
+fn parse_time(time: &str, base: &TimeBase) -> Result<f32> {
+    let time = time.trim();
+    if let Some(caps) = CLOCK_TIME.captures(time) {
+        let h = f32::from_str(&caps[1])?;
+        let m = f32::from_str(&caps[2])?;
+        let s = f32::from_str(&caps[3])?;
+        let frames = match caps.get(4) {
+            Some(f) => f32::from_str(f.as_str())? / base.frame_rate,
+            None => 0.0,
+        };
+        Ok(h * 3600.0 + m * 60.0 + s + frames)
+    } else if let Some(caps) = OFFSET_TIME.captures(time) {
+        let value = f32::from_str(&caps[1])?;
+        Ok(match &caps[2] {
+            "h" => value * 3600.0,
+            "m" => value * 60.0,
+            "s" => value,
+            "ms" => value / 1000.0,
+            "f" => value / base.frame_rate,
+            "t" => value / base.tick_rate,
+            _ => unreachable!("unknown time unit"),
+        })
+    } else {
+        Err(format_err!("unsupported TTML time expression {:?}", time))
+    }
+}
+
+/// Decode XML entities.
+fn decode_entities(text: &str) -> String {
+    ENTITY
+        .replace_all(text, |caps: &Captures| {
+            let entity = caps.get(1).unwrap().as_str();
+            let decoded = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16)
+                    .ok()
+                    .and_then(::std::char::from_u32),
+                _ if entity.starts_with('#') => u32::from_str(&entity[1..])
+                    .ok()
+                    .and_then(::std::char::from_u32),
+                _ => None,
+            };
+            decoded
+                .map(|c| c.to_string())
+                .unwrap_or_else(|| caps.get(0).unwrap().as_str().to_owned())
+        })
+        .into_owned()
+}
+
+/// Does `data` look like a TTML document?
+pub(crate) fn is_ttml(data: &str) -> bool {
+    let start = data.trim_left();
+    (start.starts_with("<?xml") || start.starts_with("<tt")) && data.contains("<tt")
+}
+
+/// Parse TTML subtitle text.
+pub fn parse(data: &str) -> Result<SubtitleFile> {
+    if !is_ttml(data.trim_left_matches("\u{FEFF}")) {
+        return Err(format_err!("TTML file does not contain a <tt> element"));
+    }
+
+    // Look up our frame and tick rates on the root element.
+    let root_attrs = data.find("<tt")
+        .and_then(|i| data[i..].find('>').map(|j| &data[i..i + j]))
+        .unwrap_or("");
+    let rate = |name: &str| {
+        attribute(root_attrs, name).and_then(|r| match f32::from_str(r) {
+            Ok(r) if r.is_finite() && r > 0.0 => Some(r),
+            _ => None,
+        })
+    };
+    let frame_rate = rate("frameRate").unwrap_or(30.0);
+    let base = TimeBase {
+        frame_rate: frame_rate,
+        tick_rate: rate("tickRate").unwrap_or(frame_rate),
+    };
+
+    let mut subtitles = vec![];
+    for caps in PARAGRAPH.captures_iter(data) {
+        let attrs = caps.get(1).unwrap().as_str();
+        let mkerr = || format_err!("could not parse TTML paragraph {:?}", attrs);
+        let begin_attr = attribute(attrs, "begin").ok_or_else(|| mkerr())?;
+        let begin = parse_time(begin_attr, &base).with_context(|_| mkerr())?;
+        let mut end = match (attribute(attrs, "end"), attribute(attrs, "dur")) {
+            (Some(end), _) => parse_time(end, &base).with_context(|_| mkerr())?,
+            (None, Some(dur)) => begin + parse_time(dur, &base).with_context(|_| mkerr())?,
+            (None, None) => return Err(mkerr()),
+        };
+        if begin == end {
+            end += 0.001;
+        }
+
+        // Only `<br/>` breaks lines.
+        let body = WHITESPACE.replace_all(caps.get(2).unwrap().as_str(), " ");
+        let lines: Vec<String> = BREAK
+            .split(&body)
+            .map(|l| decode_entities(&TAG.replace_all(l, "")).trim().to_owned())
+            .filter(|l| !l.is_empty())
+            .collect();
+        if lines.is_empty() {
+            continue;
+        }
+        subtitles.push(Subtitle {
+            index: subtitles.len() + 1,
+            period: Period::new(begin, end).with_context(|_| mkerr())?,
+            lines: lines,
+        });
+    }
+    Ok(SubtitleFile { subtitles: subtitles })
+}
+
+/// Format seconds as a TTML clock time.
+fn format_time(time: f32) -> String {
+    let (h, rem) = ((time / 3600.0).trunc(), time % 3600.0);
+    let (m, s) = ((rem / 60.0).trunc(), rem % 60.0);
+    format!("{:02}:{:02}:{:0>6.3}", h, m, s)
+}
+
+/// Return a TTML representation of a subtitle file. Formatting is removed.
+pub fn to_string(file: &SubtitleFile) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <tt xmlns=\"http://www.w3.org/ns/ttml\">\n  <body>\n    <div>\n",
+    );
+    for sub in &file.subtitles {
+        let lines: Vec<String> = sub.lines
+            .iter()
+            .map(|l| {
+                strip_formatting(l)
+                    .replace("&", "&amp;")
+                    .replace("<", "&lt;")
+                    .replace(">", "&gt;")
+            })
+            .collect();
+        out.push_str(&format!(
+            "      <p begin=\"{}\" end=\"{}\">{}</p>\n",
+            format_time(sub.period.begin()),
+            format_time(sub.period.end()),
+            lines.join("<br/>")
+        ));
+    }
+    out.push_str("    </div>\n  </body>\n</tt>\n");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use ttml::{parse, to_string};
+
+    #[test]
+    fn parse_ttml() {
+        let data = r#"<?xml version="1.0" encoding="UTF-8"?>
+<tt xmlns="http://www.w3.org/ns/ttml" xmlns:ttp="http://www.w3.org/ns/ttml#parameter"
+    ttp:tickRate="10000000">
+  <body>
+    <div>
+      <p begin="00:01:02.328" end="00:01:04.664">¡Si! ¡Aang
+        ha vuelto!</p>
+      <p xml:id="s2" begin="731000000t" dur="10000000t">
+        <span tts:fontStyle="italic">Rock &amp; roll</span><br/>Line&#32;2
+      </p>
+    </div>
+  </body>
+</tt>
+"#;
+        let ttml = parse(data).unwrap();
+        assert_eq!(2, ttml.subtitles.len());
+        assert_eq!(62.328, ttml.subtitles[0].period.begin());
+        assert_eq!(vec!["¡Si! ¡Aang ha vuelto!".to_owned()], ttml.subtitles[0].lines);
+        assert_eq!(73.1, ttml.subtitles[1].period.begin());
+        assert_eq!(74.1, ttml.subtitles[1].period.end());
+        assert_eq!(
+            vec!["Rock & roll".to_owned(), "Line 2".to_owned()],
+            ttml.subtitles[1].lines
+        );
+
+        let reparsed = parse(&to_string(&ttml)).unwrap();
+        assert_eq!(ttml.subtitles[1].lines, reparsed.subtitles[1].lines);
+    }
+
+    #[test]
+    fn parse_malformed_ttml() {
+        let ttml = |root: &str, p: &str| {
+            parse(&format!("<tt {}><body><div>{}</div></body></tt>", root, p))
+        };
+
+        // Unsupported or incomplete time expressions.
+        for time in &["1.5x", "00:01", "00:01:02.", "-1s", "1:02:03:04:05", ""] {
+            let p = format!(r#"<p begin="{}" end="00:00:10.000">Text</p>"#, time);
+            assert!(ttml("", &p).is_err(), "accepted {:?}", time);
+        }
+        assert!(ttml("", r#"<p end="00:00:10.000">No begin</p>"#).is_err());
+        assert!(ttml("", r#"<p begin="00:00:10.000">No end</p>"#).is_err());
+        assert!(ttml("", r#"<p begin="10s" end="5s">Backwards</p>"#).is_err());
+        assert!(parse("<p begin=\"1s\" end=\"2s\">No root</p>").is_err());
+
+        // Unusable frame and tick rates fall back to the defaults.
+        let p = r#"<p begin="00:00:01:15" end="60t">Text</p>"#;
+        for root in &[r#"frameRate="0" tickRate="0""#, r#"frameRate="NaN" tickRate="-1""#] {
+            let sub = ttml(root, p).unwrap();
+            assert_eq!(1.5, sub.subtitles[0].period.begin());
+            assert_eq!(2.0, sub.subtitles[0].period.end());
+        }
+    }
+}
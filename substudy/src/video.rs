@@ -8,15 +8,24 @@ use regex::Regex;
 use serde::{Deserialize, Deserializer};
 use serde::de;
 use serde_json;
+use std::cmp::{self, Reverse};
 use std::collections::BTreeMap;
 use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::result;
 use std::str::{FromStr, from_utf8};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use vobsub;
 
 use errors::RunCommandError;
 use lang::Lang;
+use srt::SubtitleFile;
 use time::Period;
 
 /// Information about an MP3 track (optional).
@@ -111,13 +120,143 @@ impl<'de> Deserialize<'de> for Fraction {
     }
 }
 
+/// Deserialize one of the `0` or `1` flags used by `ffprobe`.
+fn deserialize_flag<'de, D: Deserializer<'de>>(d: D) -> result::Result<bool, D::Error> {
+    Ok(u8::deserialize(d)? != 0)
+}
+
+/// Convert a hex dump in the format used by `ffprobe -show_data` back into
+/// bytes. Each line looks like `00000000: 7369 7a65 3a20  size: `.
+fn parse_hexdump(dump: &str) -> Vec<u8> {
+    let mut bytes = vec![];
+    for line in dump.lines() {
+        let hex = match line.find(": ") {
+            Some(pos) => &line[pos + 2..],
+            None => continue,
+        };
+        // The hex digits are separated from the ASCII by two spaces.
+        let hex = hex.split("  ").next().unwrap_or("");
+        for group in hex.split_whitespace() {
+            let mut i = 0;
+            while i + 2 <= group.len() {
+                if let Ok(b) = u8::from_str_radix(&group[i..i + 2], 16) {
+                    bytes.push(b);
+                }
+                i += 2;
+            }
+        }
+    }
+    bytes
+}
+
+/// Make sure the `vobsub` crate can read the `*.idx` and `*.sub` files we
+/// extracted, so we don't hand broken files to an OCR tool.
+fn check_vobsub(idx_path: &Path) -> Result<()> {
+    let index = vobsub::Index::open(idx_path)?;
+    for subtitle in index.subtitles() {
+        subtitle.io_read_context(&idx_path.with_extension("sub"))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn parse_ffprobe_hexdump() {
+    let dump = "\n00000000: 7369 7a65 3a20 3732 3078 3438 300a 7061  size: 720x480.pa\n\
+                00000010: 6c65 7474 653a 0a                        lette:.\n";
+    assert_eq!(
+        String::from_utf8(parse_hexdump(dump)).unwrap(),
+        "size: 720x480\npalette:\n"
+    );
+}
+
+#[test]
+fn extracted_vobsub_can_be_read() {
+    use cli_test_dir::TestDir;
+    use std::io::Read;
+
+    // Matroska stores everything in the `*.idx` file before the first
+    // stream as codec data, which `ffprobe` shows as a hex dump.
+    let mut idx = String::new();
+    fs::File::open("../fixtures/example.idx")
+        .unwrap()
+        .read_to_string(&mut idx)
+        .unwrap();
+    let header = idx.lines()
+        .take_while(|line| !line.starts_with("id:"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut dump = String::new();
+    for (i, chunk) in header.as_bytes().chunks(16).enumerate() {
+        let groups = chunk
+            .chunks(2)
+            .map(|pair| pair.iter().map(|b| format!("{:02x}", b)).collect())
+            .collect::<Vec<String>>();
+        dump.push_str(&format!("{:08x}: {}\n", i * 16, groups.join(" ")));
+    }
+    let video = Video {
+        path: Path::new("test.mkv").to_owned(),
+        metadata: serde_json::from_value(json!({ "streams": [
+            { "index": 0, "codec_type": "subtitle", "codec_name": "dvd_subtitle",
+              "extradata": dump }
+        ] })).unwrap(),
+        audio_stream: None,
+    };
+
+    // Write our `*.idx` file next to a copy of the original `*.sub` file,
+    // and make sure that the `vobsub` crate agrees with us.
+    let testdir = TestDir::new("substudy", "extracted_vobsub_can_be_read");
+    let idx_path = testdir.path("test.es.idx");
+    video.write_vobsub_idx(0, &idx_path).unwrap();
+    fs::copy("../fixtures/example.sub", testdir.path("test.es.sub")).unwrap();
+    check_vobsub(&idx_path).unwrap();
+
+    let original = vobsub::Index::open("../fixtures/example.idx").unwrap();
+    let extracted = vobsub::Index::open(&idx_path).unwrap();
+    assert_eq!(extracted.palette(), original.palette());
+    assert_eq!(extracted.subtitles().count(), original.subtitles().count());
+
+    // A stream without a palette can't be extracted.
+    let video = Video {
+        path: Path::new("test.mkv").to_owned(),
+        metadata: serde_json::from_value(json!({ "streams": [
+            { "index": 0, "codec_type": "subtitle", "codec_name": "dvd_subtitle" }
+        ] })).unwrap(),
+        audio_stream: None,
+    };
+    assert!(video.write_vobsub_idx(0, &testdir.path("bad.idx")).is_err());
+}
+
+/// Flags describing the purpose of a stream, as reported by `ffprobe`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+#[allow(missing_docs)]
+pub struct Disposition {
+    #[serde(deserialize_with = "deserialize_flag")]
+    pub default: bool,
+    #[serde(deserialize_with = "deserialize_flag")]
+    pub comment: bool,
+    #[serde(deserialize_with = "deserialize_flag")]
+    pub forced: bool,
+    #[serde(deserialize_with = "deserialize_flag")]
+    pub hearing_impaired: bool,
+    #[serde(deserialize_with = "deserialize_flag")]
+    pub visual_impaired: bool,
+}
+
 /// An individual content stream within a video.
 #[derive(Debug, Deserialize)]
 #[allow(missing_docs)]
 pub struct Stream {
     pub index: usize,
     pub codec_type: CodecType,
+    pub codec_name: Option<String>,
+    pub channels: Option<u32>,
+    pub channel_layout: Option<String>,
+    #[serde(default)]
+    pub disposition: Disposition,
     tags: Option<BTreeMap<String, String>>,
+    /// Codec-specific data, as an `ffprobe` hex dump.
+    extradata: Option<String>,
 }
 
 impl Stream {
@@ -127,7 +266,74 @@ impl Stream {
         self.tags
             .as_ref()
             .and_then(|tags| tags.get("language"))
-            .and_then(|lang| Lang::iso639(lang).ok())
+            .and_then(|lang| Lang::bcp47(lang).ok())
+    }
+
+    /// The title of this stream, if it has one.
+    pub fn title(&self) -> Option<&str> {
+        self.tags
+            .as_ref()
+            .and_then(|tags| tags.get("title"))
+            .map(|title| &title[..])
+    }
+
+    /// Does our title contain any of `words`?
+    fn title_mentions(&self, words: &[&str]) -> bool {
+        let title = self.title().unwrap_or("").to_lowercase();
+        words.iter().any(|w| title.contains(w))
+    }
+
+    /// Is this a commentary track?
+    pub fn is_commentary(&self) -> bool {
+        self.disposition.comment || self.title_mentions(&["commentary"])
+    }
+
+    /// Is this an audio description track for the visually impaired?
+    pub fn is_audio_description(&self) -> bool {
+        self.disposition.visual_impaired
+            || self.title_mentions(&["audio description", "descriptive"])
+    }
+
+    /// Is this a "forced" subtitle track, which only translates signs and
+    /// occasional foreign dialog?
+    pub fn is_forced(&self) -> bool {
+        self.disposition.forced || self.title_mentions(&["forced"])
+    }
+
+    /// Is this a subtitle track for the hearing impaired, which describes
+    /// sounds as well as dialog?
+    pub fn is_sdh(&self) -> bool {
+        self.disposition.hearing_impaired || self.title_mentions(&["sdh"])
+    }
+
+    /// Is this a text subtitle track (as opposed to bitmap subtitles, which
+    /// would need OCR)?
+    pub fn is_text_subtitle(&self) -> bool {
+        match self.codec_name.as_ref().map(|n| &n[..]) {
+            Some("dvd_subtitle") | Some("hdmv_pgs_subtitle") | Some("dvb_subtitle")
+            | Some("xsub") => false,
+            _ => self.codec_type == CodecType::Subtitle,
+        }
+    }
+
+    /// A short description of our channel layout, such as "stereo" or
+    /// "5.1(side)".
+    pub fn channel_description(&self) -> Option<String> {
+        self.channel_layout.clone().or_else(|| {
+            self.channels.map(|n| format!("{} channels", n))
+        })
+    }
+
+    /// How well does our language match `lang`? Higher is better, and
+    /// `None` means we're definitely in another language.
+    fn language_rank(&self, lang: Option<Lang>) -> Option<u8> {
+        match (self.language(), lang) {
+            (_, None) => Some(0),
+            (Some(l), Some(lang)) if l == lang => Some(3),
+            (Some(l), Some(lang)) if l.matches(lang) => Some(2),
+            (Some(_), Some(_)) => None,
+            (None, Some(_)) => Some(1),
+        }
     }
 }
 
@@ -156,24 +362,307 @@ fn test_stream_decode() {
 ";
     let stream: Stream = serde_json::from_str(json).unwrap();
     assert_eq!(CodecType::Audio, stream.codec_type);
-    assert_eq!(Some(Lang::iso639("en").unwrap()), stream.language())
+    assert_eq!(Some(Lang::iso639("en").unwrap()), stream.language());
+    assert_eq!(Some(2), stream.channels);
+    assert!(!stream.disposition.default);
+
+    let json = json.replace("\"eng\"", "\"pt-BR\"");
+    let stream: Stream = serde_json::from_str(&json).unwrap();
+    assert_eq!(Some(Lang::bcp47("pt-BR").unwrap()), stream.language());
+}
+
+#[test]
+fn choose_streams() {
+    let json = r#"{ "streams": [
+      { "index": 0, "codec_type": "video" },
+      { "index": 1, "codec_type": "audio", "channels": 6,
+        "disposition": { "default": 1 }, "tags": { "language": "spa" } },
+      { "index": 2, "codec_type": "audio", "channels": 2,
+        "tags": { "language": "spa", "title": "Director's Commentary" } },
+      { "index": 3, "codec_type": "audio", "channels": 2,
+        "tags": { "language": "spa" } },
+      { "index": 4, "codec_type": "audio", "channels": 2,
+        "disposition": { "visual_impaired": 1 }, "tags": { "language": "eng" } },
+      { "index": 5, "codec_type": "audio", "channels": 2,
+        "tags": { "language": "pt-PT" } },
+      { "index": 6, "codec_type": "audio", "channels": 2 },
+      { "index": 7, "codec_type": "subtitle", "codec_name": "dvd_subtitle",
+        "tags": { "language": "spa" } },
+      { "index": 8, "codec_type": "subtitle", "codec_name": "subrip",
+        "disposition": { "forced": 1 }, "tags": { "language": "spa" } },
+      { "index": 9, "codec_type": "subtitle", "codec_name": "subrip",
+        "tags": { "language": "spa" } }
+    ] }"#;
+    let mut video = Video {
+        path: Path::new("test.mkv").to_owned(),
+        metadata: serde_json::from_str(json).unwrap(),
+        audio_stream: None,
+    };
+    let lang = |tag| Some(Lang::bcp47(tag).unwrap());
+
+    // Prefer the stereo mix to the 5.1 mix, and avoid commentary.
+    assert_eq!(video.audio_for(lang("es")), Some(3));
+    // Audio description is better than nothing.
+    assert_eq!(video.audio_for(lang("en")), Some(4));
+    // Fall back to untagged streams when no language matches.
+    assert_eq!(video.audio_for(lang("fr")), Some(6));
+    assert_eq!(video.audio_for(lang("pt-BR")), Some(6));
+    assert_eq!(video.audio_for(lang("pt")), Some(5));
+    assert_eq!(video.subtitles_for(lang("es")), Some(9));
+    assert_eq!(video.subtitles_for(lang("en")), None);
+
+    assert!(video.set_audio_stream(0).is_err());
+    assert!(video.set_audio_stream(10).is_err());
+    video.set_audio_stream(2).unwrap();
+    assert_eq!(video.audio_for(lang("es")), Some(2));
+}
+
+/// Audio formats which we can extract.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioFormat {
+    /// MP3 audio, which is supported almost everywhere.
+    Mp3,
+    /// AAC audio in an MPEG-4 container.
+    Aac,
+    /// Opus audio in an Ogg container. Good quality at low bitrates.
+    Opus,
+    /// Vorbis audio in an Ogg container.
+    Ogg,
+}
+
+impl AudioFormat {
+    /// The standard file extension for this format, without a leading ".".
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Aac => "m4a",
+            AudioFormat::Opus => "opus",
+            AudioFormat::Ogg => "ogg",
+        }
+    }
+
+    /// The ffmpeg encoder to use for this format.
+    fn codec(&self) -> &'static str {
+        match *self {
+            AudioFormat::Mp3 => "libmp3lame",
+            AudioFormat::Aac => "aac",
+            AudioFormat::Opus => "libopus",
+            AudioFormat::Ogg => "libvorbis",
+        }
+    }
+}
+
+impl fmt::Display for AudioFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match *self {
+            AudioFormat::Aac => write!(f, "aac"),
+            _ => write!(f, "{}", self.extension()),
+        }
+    }
+}
+
+impl FromStr for AudioFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<AudioFormat> {
+        match s {
+            "mp3" => Ok(AudioFormat::Mp3),
+            "aac" | "m4a" => Ok(AudioFormat::Aac),
+            "opus" => Ok(AudioFormat::Opus),
+            "ogg" | "vorbis" => Ok(AudioFormat::Ogg),
+            _ => Err(format_err!("Unknown audio format: {}", s)),
+        }
+    }
+}
+
+/// Image formats which we can extract. Some of these are actually short
+/// animated snippets, which cover an entire period instead of a single
+/// frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// A still JPEG image.
+    Jpeg,
+    /// A still PNG image.
+    Png,
+    /// A still WebP image.
+    Webp,
+    /// An animated GIF.
+    Gif,
+    /// A silent WebM video clip.
+    Webm,
+}
+
+impl ImageFormat {
+    /// The standard file extension for this format, without a leading ".".
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+            ImageFormat::Webp => "webp",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Webm => "webm",
+        }
+    }
+
+    /// Does this format contain an animated snippet, instead of a single
+    /// frame?
+    pub fn is_animated(&self) -> bool {
+        match *self {
+            ImageFormat::Gif | ImageFormat::Webm => true,
+            _ => false,
+        }
+    }
+
+    /// Is this format actually a video, which needs a `<video>` tag
+    /// instead of an `<img>` tag to display it?
+    pub fn is_video(&self) -> bool {
+        *self == ImageFormat::Webm
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        write!(f, "{}", self.extension())
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ImageFormat> {
+        match s {
+            "jpg" | "jpeg" => Ok(ImageFormat::Jpeg),
+            "png" => Ok(ImageFormat::Png),
+            "webp" => Ok(ImageFormat::Webp),
+            "gif" => Ok(ImageFormat::Gif),
+            "webm" => Ok(ImageFormat::Webm),
+            _ => Err(format_err!("Unknown image format: {}", s)),
+        }
+    }
+}
+
+/// The maximum size of an extracted image. Images will be scaled down to
+/// fit, preserving their aspect ratio, but never scaled up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageSize {
+    /// The maximum width, in pixels.
+    pub width: u32,
+    /// The maximum height, in pixels.
+    pub height: u32,
+}
+
+impl ImageSize {
+    /// An ffmpeg filter which scales video to fit within this size.
+    fn scale_filter(&self) -> String {
+        format!(
+            "scale=iw*min(1\\,min({}/iw\\,{}/ih)):-1",
+            self.width,
+            self.height
+        )
+    }
+}
+
+impl fmt::Display for ImageSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+impl FromStr for ImageSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ImageSize> {
+        let mut parts = s.splitn(2, 'x');
+        let width = parts.next().and_then(|w| w.parse::<u32>().ok());
+        let height = parts.next().and_then(|h| h.parse::<u32>().ok());
+        match (width, height) {
+            (Some(w), Some(h)) if w > 0 && h > 0 => Ok(ImageSize {
+                width: w,
+                height: h,
+            }),
+            _ => Err(format_err!("Image size must look like 240x160: {}", s)),
+        }
+    }
+}
+
+/// How should we encode extracted audio?
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AudioOptions {
+    /// The audio format to use.
+    pub format: AudioFormat,
+    /// The bitrate to use, in kbit/s. If this is `None`, we use ffmpeg's
+    /// default for the format.
+    pub bitrate: Option<u32>,
+    /// Should we normalize the loudness of each clip?
+    pub normalize: bool,
+}
+
+impl Default for AudioOptions {
+    fn default() -> AudioOptions {
+        AudioOptions {
+            format: AudioFormat::Mp3,
+            bitrate: None,
+            normalize: false,
+        }
+    }
+}
+
+impl AudioOptions {
+    fn add_args(&self, cmd: &mut Command) {
+        cmd.arg("-vn").arg("-c:a").arg(self.format.codec());
+        if let Some(bitrate) = self.bitrate {
+            cmd.arg("-b:a").arg(format!("{}k", bitrate));
+        }
+        if self.normalize {
+            // `loudnorm` resamples to 192kHz internally, which most of our
+            // encoders can't handle, so resample back down afterwards.
+            cmd.arg("-af").arg("loudnorm").arg("-ar").arg("48000");
+        }
+    }
+}
+
+/// How should we encode extracted images?
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageOptions {
+    /// The image format to use.
+    pub format: ImageFormat,
+    /// The maximum size of the image.
+    pub size: ImageSize,
 }
 
+impl Default for ImageOptions {
+    fn default() -> ImageOptions {
+        ImageOptions {
+            format: ImageFormat::Jpeg,
+            size: ImageSize {
+                width: 240,
+                height: 160,
+            },
+        }
+    }
+}
+
+/// The frame rate to use for animated snippets.
+const SNIPPET_FPS: u32 = 10;
+
 /// What kind of data do we want to extract, and from what position in the
 /// video clip?
 pub enum ExtractionSpec {
     /// Extract an image at the specified time.
-    Image(f32),
+    Image(f32, ImageOptions),
+    /// Extract an animated snippet covering the specified period.
+    Snippet(Period, ImageOptions),
     /// Extract an audio clip covering the specified stream and period.
-    Audio(Option<usize>, Period, Id3Metadata),
+    Audio(Option<usize>, Period, Id3Metadata, AudioOptions),
 }
 
 impl ExtractionSpec {
     /// The earliest time at which we might need to extract data.
     fn earliest_time(&self) -> f32 {
         match self {
-            &ExtractionSpec::Image(time) => time,
-            &ExtractionSpec::Audio(_, period, _) => period.begin(),
+            &ExtractionSpec::Image(time, _) => time,
+            &ExtractionSpec::Snippet(period, _) => period.begin(),
+            &ExtractionSpec::Audio(_, period, _, _) => period.begin(),
         }
     }
 
@@ -183,8 +672,8 @@ impl ExtractionSpec {
         match self {
             // Batch processing of images requires decoding the whole
             // video, but we can do a "fast seek" and extract one image
-            // extremely quickly.
-            &ExtractionSpec::Image(_) => false,
+            // extremely quickly. The same goes for short snippets.
+            &ExtractionSpec::Image(..) | &ExtractionSpec::Snippet(..) => false,
             _ => true,
         }
     }
@@ -194,22 +683,55 @@ impl ExtractionSpec {
     /// decoding at `time_base`.
     fn add_args(&self, cmd: &mut Command, time_base: f32) {
         match self {
-            &ExtractionSpec::Image(time) => {
-                let scale_filter =
-                    format!("scale=iw*min(1\\,min({}/iw\\,{}/ih)):-1", 240, 160);
+            &ExtractionSpec::Image(time, ref options) => {
                 cmd.arg("-ss")
                     .arg(format!("{}", time - time_base))
                     .arg("-vframes")
                     .arg("1")
                     .arg("-filter_complex")
-                    .arg(&scale_filter)
+                    .arg(&options.size.scale_filter())
                     .arg("-f")
                     .arg("image2");
             }
-            &ExtractionSpec::Audio(stream, period, ref metadata) => {
+            &ExtractionSpec::Snippet(period, ref options) => {
+                let scale_filter = format!(
+                    "fps={},{}",
+                    SNIPPET_FPS,
+                    options.size.scale_filter()
+                );
+                cmd.arg("-ss")
+                    .arg(format!("{}", period.begin() - time_base))
+                    .arg("-t")
+                    .arg(format!("{}", period.duration()))
+                    .arg("-an")
+                    .arg("-sn");
+                match options.format {
+                    ImageFormat::Gif => {
+                        // Build a custom palette for each snippet, which
+                        // looks much better than the default one.
+                        cmd.arg("-vf").arg(format!(
+                            "{},split[a][b];[a]palettegen[p];[b][p]paletteuse",
+                            scale_filter
+                        ));
+                    }
+                    ImageFormat::Webm => {
+                        cmd.arg("-vf")
+                            .arg(&scale_filter)
+                            .arg("-c:v")
+                            .arg("libvpx-vp9")
+                            .arg("-b:v")
+                            .arg("0")
+                            .arg("-crf")
+                            .arg("40");
+                    }
+                    other => panic!("{} is not an animated format", other),
+                }
+            }
+            &ExtractionSpec::Audio(stream, period, ref metadata, ref options) => {
                 if let Some(sid) = stream {
                     cmd.arg("-map").arg(format!("0:{}", sid));
                 }
+                options.add_args(cmd);
                 metadata.add_args(cmd);
                 cmd.arg("-ss")
                     .arg(format!("{}", period.begin() - time_base))
@@ -220,6 +742,62 @@ impl ExtractionSpec {
     }
 }
 
+#[test]
+fn parse_export_formats() {
+    assert_eq!("opus".parse::<AudioFormat>().unwrap(), AudioFormat::Opus);
+    assert_eq!("m4a".parse::<AudioFormat>().unwrap(), AudioFormat::Aac);
+    assert!("wav".parse::<AudioFormat>().is_err());
+    assert_eq!("jpeg".parse::<ImageFormat>().unwrap(), ImageFormat::Jpeg);
+    assert!("webm".parse::<ImageFormat>().unwrap().is_animated());
+    assert!(!"webp".parse::<ImageFormat>().unwrap().is_animated());
+    assert_eq!(
+        "320x200".parse::<ImageSize>().unwrap(),
+        ImageSize {
+            width: 320,
+            height: 200,
+        }
+    );
+    assert!("320".parse::<ImageSize>().is_err());
+    assert!("0x200".parse::<ImageSize>().is_err());
+}
+
+#[test]
+fn extraction_args() {
+    let period = Period::new(10.0, 12.5).unwrap();
+    let args = |spec: ExtractionSpec| {
+        let mut cmd = Command::new("ffmpeg");
+        spec.add_args(&mut cmd, 9.0);
+        format!("{:?}", cmd)
+    };
+
+    let audio = AudioOptions {
+        format: AudioFormat::Opus,
+        bitrate: Some(48),
+        normalize: true,
+    };
+    let audio_args =
+        args(ExtractionSpec::Audio(Some(2), period, Id3Metadata::default(), audio));
+    assert!(audio_args.contains(r#""-map" "0:2""#));
+    assert!(audio_args.contains(r#""-c:a" "libopus" "-b:a" "48k""#));
+    assert!(audio_args.contains(r#""-af" "loudnorm""#));
+    assert!(audio_args.contains(r#""-ss" "1" "-t" "2.5""#));
+
+    let image = ImageOptions {
+        format: ImageFormat::Png,
+        size: "320x200".parse().unwrap(),
+    };
+    let image_args = args(ExtractionSpec::Image(11.0, image));
+    assert!(image_args.contains("min(320/iw\\\\,200/ih)"));
+
+    let snippet = ImageOptions {
+        format: ImageFormat::Webm,
+        ..ImageOptions::default()
+    };
+    let snippet_args = args(ExtractionSpec::Snippet(period, snippet));
+    assert!(snippet_args.contains(r#""-c:v" "libvpx-vp9""#));
+    assert!(snippet_args.contains("fps=10,scale="));
+}
+
 /// Information about what kind of data we want to extract.
 pub struct Extraction {
     /// The path to extract to.
@@ -229,10 +807,11 @@ pub struct Extraction {
 }
 
 impl Extraction {
-    /// Add the necessary args to `cmd` to perform this extraction.
-    fn add_args(&self, cmd: &mut Command, time_base: f32) {
+    /// Add the necessary args to `cmd` to perform this extraction, writing
+    /// the result to `output`.
+    fn add_args(&self, cmd: &mut Command, time_base: f32, output: &Path) {
         self.spec.add_args(cmd, time_base);
-        cmd.arg(self.path.clone());
+        cmd.arg(output);
     }
 }
 
@@ -247,6 +826,8 @@ struct Metadata {
 pub struct Video {
     path: PathBuf,
     metadata: Metadata,
+    /// The audio stream chosen by the user, if any.
+    audio_stream: Option<usize>,
 }
 
 impl Video {
@@ -263,11 +844,16 @@ impl Video {
             .arg("-v")
             .arg("quiet")
             .arg("-show_streams")
+            .arg("-show_data")
             .arg("-of")
             .arg("json")
             .arg(path)
             .output();
         let output = cmd.with_context(|_| mkerr())?;
+        if !output.status.success() {
+            let err: Error = RunCommandError::from_output("ffprobe", &output).into();
+            Err(err).io_read_context(path)?;
+        }
         let stdout = from_utf8(&output.stdout).with_context(|_| mkerr())?;
         debug!("Video metadata: {}", stdout);
         let metadata = serde_json::from_str(stdout).with_context(|_| mkerr())?;
@@ -275,9 +861,15 @@ impl Video {
         Ok(Video {
             path: path.to_owned(),
             metadata: metadata,
+            audio_stream: None,
         })
     }
 
+    /// The path to this video file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     /// Get just the file name of this video file.
     pub fn file_name(&self) -> &OsStr {
         self.path.file_name().unwrap()
@@ -294,71 +886,404 @@ impl Video {
         &self.metadata.streams
     }
 
-    /// Choose the best audio for the specified language.
-    pub fn audio_for(&self, lang: Lang) -> Option<usize> {
-        self.streams().iter().position(|s| {
-            s.codec_type == CodecType::Audio && s.language() == Some(lang)
+    /// Always use the audio stream with the specified index, instead of
+    /// choosing one automatically.
+    pub fn set_audio_stream(&mut self, index: usize) -> Result<()> {
+        match self.streams().iter().find(|s| s.index == index) {
+            Some(s) if s.codec_type == CodecType::Audio => {
+                self.audio_stream = Some(index);
+                Ok(())
+            }
+            Some(_) => Err(format_err!("stream #{} is not an audio stream", index)),
+            None => Err(format_err!("no stream #{} in {}", index, self.path.display())),
+        }
+    }
+
+    /// Find the index of the best stream of type `codec_type` for `lang`.
+    /// Among streams with an equally good language match, we prefer the
+    /// one with the highest `preference`, then the default stream, then
+    /// the first stream.
+    fn best_stream<K, F>(&self, codec_type: CodecType, lang: Option<Lang>, preference: F)
+                         -> Option<usize>
+    where
+        K: Ord,
+        F: Fn(&Stream) -> K,
+    {
+        self.streams()
+            .iter()
+            .filter(|s| s.codec_type == codec_type)
+            .filter_map(|s| s.language_rank(lang).map(|rank| (s, rank)))
+            .max_by_key(|&(s, rank)| {
+                (rank, preference(s), s.disposition.default, Reverse(s.index))
+            })
+            .map(|(s, _)| s.index)
+    }
+
+    /// Choose the best audio stream for the specified language, returning
+    /// its index. We avoid commentary and audio description tracks, and
+    /// prefer stereo mixes. If the user has chosen a stream using
+    /// `set_audio_stream`, we always return that.
+    pub fn audio_for(&self, lang: Option<Lang>) -> Option<usize> {
+        if self.audio_stream.is_some() {
+            return self.audio_stream;
+        }
+        self.best_stream(CodecType::Audio, lang, |s| {
+            let main = !s.is_commentary() && !s.is_audio_description();
+            let channels = match s.channels {
+                Some(2) => 2,
+                Some(n) if n > 2 => 1,
+                _ => 0,
+            };
+            (main, channels)
+        })
+    }
+
+    /// Choose the best subtitle stream for the specified language,
+    /// returning its index. We avoid forced subtitles, and prefer text
+    /// subtitles to bitmaps, and regular subtitles to SDH.
+    pub fn subtitles_for(&self, lang: Option<Lang>) -> Option<usize> {
+        self.best_stream(CodecType::Subtitle, lang, |s| {
+            (!s.is_forced(), s.is_text_subtitle(), !s.is_sdh())
         })
     }
 
+    /// Look up the stream with the specified index.
+    fn stream(&self, index: usize) -> Result<&Stream> {
+        self.streams()
+            .iter()
+            .find(|s| s.index == index)
+            .ok_or_else(|| format_err!("no stream #{} in {}", index, self.path.display()))
+    }
+
+    /// Extract the embedded text subtitle stream with the specified index,
+    /// converting it to SRT format. Image-based subtitles can't be read
+    /// this way, but see `extract_vobsub`.
+    pub fn subtitles(&self, index: usize) -> Result<SubtitleFile> {
+        let stream = self.stream(index)?;
+        if stream.codec_type != CodecType::Subtitle {
+            return Err(format_err!("stream #{} is not a subtitle stream", index));
+        } else if !stream.is_text_subtitle() {
+            return Err(format_err!(
+                "stream #{} contains images, not text",
+                index
+            ));
+        }
+
+        let mkerr = || RunCommandError::new("ffmpeg");
+        let output = Command::new("ffmpeg")
+            .arg("-nostdin")
+            .arg("-v")
+            .arg("error")
+            .arg("-i")
+            .arg(&self.path)
+            .arg("-map")
+            .arg(format!("0:{}", index))
+            .arg("-f")
+            .arg("srt")
+            .arg("-")
+            .output()
+            .with_context(|_| mkerr())?;
+        if !output.status.success() {
+            let err: Error = RunCommandError::from_output("ffmpeg", &output).into();
+            Err(err).io_read_context(&self.path)?;
+        }
+        let data = String::from_utf8_lossy(&output.stdout);
+        Ok(SubtitleFile::from_str(&data).io_read_context(&self.path)?)
+    }
+
+    /// Extract the embedded DVD subtitle stream with the specified index as
+    /// a VobSub `*.idx` file and matching `*.sub` file, which can be read
+    /// by the `vobsub` crate (or converted to text by an OCR tool like
+    /// `subtitles2srt`).
+    pub fn extract_vobsub(&self, index: usize, idx_path: &Path) -> Result<()> {
+        self.write_vobsub_idx(index, idx_path)?;
+
+        // Copy the subtitle packets into an MPEG-2 Program Stream.
+        let sub_path = idx_path.with_extension("sub");
+        let mkerr = || RunCommandError::new("ffmpeg");
+        let output = Command::new("ffmpeg")
+            .arg("-nostdin")
+            .arg("-y")
+            .arg("-v")
+            .arg("error")
+            .arg("-i")
+            .arg(&self.path)
+            .arg("-map")
+            .arg(format!("0:{}", index))
+            .arg("-c:s")
+            .arg("copy")
+            .arg("-f")
+            .arg("vob")
+            .arg(&sub_path)
+            .output()
+            .with_context(|_| mkerr())?;
+        if !output.status.success() {
+            let err: Error = RunCommandError::from_output("ffmpeg", &output).into();
+            Err(err).io_write_context(&sub_path)?;
+        }
+        check_vobsub(idx_path)
+    }
+
+    /// Write the `*.idx` file for the DVD subtitle stream with the
+    /// specified index.
+    fn write_vobsub_idx(&self, index: usize, idx_path: &Path) -> Result<()> {
+        let stream = self.stream(index)?;
+        if stream.codec_name.as_ref().map(|n| &n[..]) != Some("dvd_subtitle") {
+            return Err(format_err!("stream #{} is not a DVD subtitle stream", index));
+        }
+
+        // Matroska files store the header of the original `*.idx` file
+        // (including the all-important palette) as codec data. Subtitles
+        // read directly from DVDs keep their palette elsewhere.
+        let header = stream
+            .extradata
+            .as_ref()
+            .map(|dump| String::from_utf8_lossy(&parse_hexdump(dump)).into_owned())
+            .unwrap_or_else(String::new);
+        if !header.contains("palette:") {
+            let err: Error =
+                format_err!("could not find a palette for DVD subtitle stream #{}", index);
+            Err(err).io_read_context(&self.path)?;
+        }
+        let mut idx = fs::File::create(idx_path).io_write_context(idx_path)?;
+        writeln!(idx, "{}", header.trim_right()).io_write_context(idx_path)?;
+        Ok(())
+    }
+
+    /// Decode the specified audio stream (or the default audio stream) into
+    /// signed 16-bit mono samples at `sample_rate`. This holds the entire
+    /// soundtrack in memory, so it works best with low sample rates.
+    pub fn audio_samples(&self, stream: Option<usize>, sample_rate: u32) -> Result<Vec<i16>> {
+        let mkerr = || RunCommandError::new("ffmpeg");
+        let mut cmd = Command::new("ffmpeg");
+        cmd.arg("-v").arg("quiet").arg("-i").arg(&self.path);
+        if let Some(sid) = stream {
+            cmd.arg("-map").arg(format!("0:{}", sid));
+        }
+        let output = cmd.arg("-vn")
+            .arg("-ac")
+            .arg("1")
+            .arg("-ar")
+            .arg(format!("{}", sample_rate))
+            .arg("-f")
+            .arg("s16le")
+            .arg("-acodec")
+            .arg("pcm_s16le")
+            .arg("-")
+            .output()
+            .with_context(|_| mkerr())?;
+        if !output.status.success() {
+            return Err(RunCommandError::from_output("ffmpeg", &output).into());
+        }
+        Ok(output
+            .stdout
+            .chunks(2)
+            .filter(|pair| pair.len() == 2)
+            .map(|pair| (pair[0] as u16 | (pair[1] as u16) << 8) as i16)
+            .collect())
+    }
+
     /// Create an extraction command using the specified `time_base`.  This
     /// allows us to start extractions at any arbitrary point in the video
     /// rapidly.
     fn extract_command(&self, time_base: f32) -> Command {
         let mut cmd = Command::new("ffmpeg");
+        // Don't read from the terminal, overwrite partial output from any
+        // earlier run, and only print actual errors.
+        cmd.arg("-nostdin").arg("-y").arg("-v").arg("error");
         cmd.arg("-ss").arg(format!("{}", time_base));
         cmd.arg("-i").arg(&self.path);
         cmd
     }
 
-    /// Perform a single extraction.
-    fn extract_one(&self, extraction: &Extraction) -> Result<()> {
-        let time_base = extraction.spec.earliest_time();
-        let mut cmd = self.extract_command(time_base);
-        extraction.add_args(&mut cmd, time_base);
-        cmd.output().with_context(|_| RunCommandError::new("ffmpg"))?;
-        Ok(())
-    }
-
-    /// Perform a batch extraction.  We assume that the extractions are
-    /// sorted in temporal order.
-    fn extract_batch(&self, extractions: &[&Extraction]) -> Result<()> {
-        // Bail early if we have nothing to extract
-        if extractions.is_empty() {
-            return Ok(());
-        }
-        let time_base = extractions[0].spec.earliest_time();
-
-        // Build and run our batch extraction command.
+    /// Build a job which performs `extractions` using a single ffmpeg
+    /// process. If there's more than one extraction, they must all support
+    /// batching.
+    fn extract_job(&self, extractions: &[&Extraction]) -> ExtractionJob {
+        let time_base = extractions
+            .iter()
+            .map(|e| e.spec.earliest_time())
+            .fold(::std::f32::INFINITY, |a, b| a.min(b));
         let mut cmd = self.extract_command(time_base);
+        let mut outputs = vec![];
         for e in extractions {
-            assert!(e.spec.can_be_batched());
-            e.add_args(&mut cmd, time_base);
+            assert!(extractions.len() == 1 || e.spec.can_be_batched());
+            let partial_path = partial_path(&e.path);
+            e.add_args(&mut cmd, time_base, &partial_path);
+            outputs.push((partial_path, e.path.clone()));
         }
-        cmd.output().with_context(|_| RunCommandError::new("ffmpg"))?;
-        Ok(())
+        ExtractionJob {
+            cmd: cmd,
+            outputs: outputs,
+        }
+    }
+
+    /// Group extractions which support batching into jobs of up to 20
+    /// extractions each. We sort them by time first, because extractions
+    /// like context audio clips may start before the clips scheduled just
+    /// before them, and each job can only seek forward from its start.
+    fn batch_jobs(&self, mut batch: Vec<&Extraction>) -> Vec<ExtractionJob> {
+        batch.sort_by(|a, b| {
+            let (a, b) = (a.spec.earliest_time(), b.spec.earliest_time());
+            a.partial_cmp(&b).unwrap_or(cmp::Ordering::Equal)
+        });
+        batch.chunks(20).map(|chunk| self.extract_job(chunk)).collect()
     }
 
-    /// Perform a list of extractions as efficiently as possible.  We use a
-    /// batch interface to avoid making too many passes through the file.
-    /// We assume that the extractions are sorted in temporal order.
-    pub fn extract(&self, extractions: &[Extraction]) -> Result<()> {
+    /// Perform a list of extractions as efficiently as possible, running up
+    /// to `jobs` copies of ffmpeg at once. We use a batch interface to
+    /// avoid making too many passes through the file.
+    ///
+    /// Each file is written under a temporary name, and renamed once ffmpeg
+    /// succeeds, so any output which already exists must be complete. We
+    /// skip these, which allows an interrupted export to resume.
+    pub fn extract(&self, extractions: &[Extraction], jobs: usize) -> Result<()> {
         let mut pb = ProgressBar::new(cast::u64(extractions.len()));
         pb.format("[== ]");
+
+        let mut pending = vec![];
         let mut batch: Vec<&Extraction> = vec![];
         for e in extractions {
-            if e.spec.can_be_batched() {
+            if is_complete(&e.path) {
+                pb.inc();
+            } else if e.spec.can_be_batched() {
                 batch.push(e);
             } else {
-                self.extract_one(e)?;
-                pb.inc();
+                pending.push(self.extract_job(&[e]));
             }
         }
+        pending.extend(self.batch_jobs(batch));
+        if pending.is_empty() {
+            return Ok(());
+        }
 
-        for chunk in batch.chunks(20) {
-            self.extract_batch(chunk)?;
-            pb.add(cast::u64(chunk.len()));
+        // Run our jobs on a pool of worker threads, and stop handing out
+        // new jobs after the first failure.
+        let workers = jobs.max(1).min(pending.len());
+        let queue = Arc::new(Mutex::new(pending.into_iter()));
+        let failed = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = mpsc::channel();
+        let mut handles = vec![];
+        for _ in 0..workers {
+            let queue = queue.clone();
+            let failed = failed.clone();
+            let tx = tx.clone();
+            handles.push(thread::spawn(move || {
+                while !failed.load(Ordering::SeqCst) {
+                    let job = queue.lock().expect("extraction queue poisoned").next();
+                    let result = match job {
+                        Some(job) => job.run(),
+                        None => break,
+                    };
+                    if result.is_err() {
+                        failed.store(true, Ordering::SeqCst);
+                    }
+                    if tx.send(result).is_err() {
+                        break;
+                    }
+                }
+            }));
+        }
+        drop(tx);
+
+        let mut first_err = None;
+        for result in rx {
+            match result {
+                Ok(count) => {
+                    pb.add(cast::u64(count));
+                }
+                Err(err) => {
+                    if first_err.is_none() {
+                        first_err = Some(err);
+                    }
+                }
+            }
+        }
+        for handle in handles {
+            handle.join().expect("extraction thread panicked");
+        }
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(()),
         }
-        Ok(())
     }
 }
+
+/// A single ffmpeg process which performs one or more extractions.
+struct ExtractionJob {
+    /// The ffmpeg command to run.
+    cmd: Command,
+    /// Pairs of `(partial_path, path)`, where `partial_path` is the file
+    /// which ffmpeg will write, and `path` is where we want it to end up.
+    outputs: Vec<(PathBuf, PathBuf)>,
+}
+
+impl ExtractionJob {
+    /// Run this job, returning the number of files extracted.
+    fn run(mut self) -> Result<usize> {
+        let output = self.cmd
+            .output()
+            .with_context(|_| RunCommandError::new("ffmpeg"))?;
+        if !output.status.success() {
+            let err = Error::from(RunCommandError::from_output("ffmpeg", &output));
+            let msg = format!("could not extract {}", self.outputs[0].1.display());
+            return Err(err.context(msg).into());
+        }
+        for &(ref partial_path, ref path) in &self.outputs {
+            fs::rename(partial_path, path).io_write_context(path)?;
+        }
+        Ok(self.outputs.len())
+    }
+}
+
+/// The temporary path to use while extracting `path`. We keep the original
+/// extension, because ffmpeg uses it to choose an output format.
+fn partial_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.partial.{}", stem, ext.to_string_lossy()),
+        None => format!("{}.partial", stem),
+    };
+    path.with_file_name(name)
+}
+
+/// Has `path` already been extracted?
+fn is_complete(path: &Path) -> bool {
+    fs::metadata(path).map(|m| m.is_file() && m.len() > 0).unwrap_or(false)
+}
+
+#[test]
+fn batch_jobs_never_seek_backwards() {
+    let video = Video {
+        path: PathBuf::from("episode.mkv"),
+        metadata: Metadata { streams: vec![] },
+        audio_stream: None,
+    };
+    let audio = |path: &str, begin: f32, end: f32| Extraction {
+        path: PathBuf::from(path),
+        spec: ExtractionSpec::Audio(
+            None,
+            Period::new(begin, end).unwrap(),
+            Id3Metadata::default(),
+            AudioOptions::default(),
+        ),
+    };
+    // A regular clip, followed by a context clip which starts earlier.
+    let clip = audio("clip.mp3", 10.0, 12.0);
+    let context = audio("context.mp3", 7.5, 12.0);
+    let jobs = video.batch_jobs(vec![&clip, &context]);
+    assert_eq!(jobs.len(), 1);
+    let cmd = format!("{:?}", jobs[0].cmd);
+    assert!(cmd.contains(r#""-ss" "7.5" "-i""#));
+    assert!(!cmd.contains(r#""-ss" "-"#));
+    let outputs: Vec<&PathBuf> = jobs[0].outputs.iter().map(|o| &o.1).collect();
+    assert_eq!(outputs, vec![&context.path, &clip.path]);
+}
+
+#[test]
+fn partial_paths_keep_extension() {
+    assert_eq!(
+        partial_path(Path::new("out/ep1_00001_000.es.mp3")),
+        Path::new("out/ep1_00001_000.es.partial.mp3"),
+    );
+}
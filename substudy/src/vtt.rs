@@ -0,0 +1,364 @@
+//! WebVTT-format subtitle support.
+//!
+//! We read WebVTT files into the same `Subtitle` model that we use for SRT
+//! files. Cue settings, `NOTE`, `STYLE` and `REGION` blocks are skipped,
+//! and WebVTT-only markup like `<c.class>` and `<v Speaker>` spans is
+//! removed, leaving only the `<i>`, `<b>` and `<u>` tags which SRT also
+//! understands.
+
+use common_failures::prelude::*;
+use regex::{Captures, Regex};
+use std::str::FromStr;
+
+use srt::{Subtitle, SubtitleFile};
+use time::Period;
+
+lazy_static! {
+    /// A cue timing line, with optional cue settings.
+    static ref TIMING: Regex = Regex::new(
+        r"^((?:\d+:)?\d{2}:\d{2}\.\d{3})[ \t]+-->[ \t]+((?:\d+:)?\d{2}:\d{2}\.\d{3})(?:[ \t]+(.*))?$"
+    ).unwrap();
+
+    /// Cue text tags which have no SRT equivalent, including inline
+    /// timestamps like `<00:01:02.000>`.
+    static ref VTT_ONLY_TAG: Regex =
+        Regex::new(r"</?(?:c|v|lang|ruby|rt)(?:[ .][^>]*)?>|<[0-9][^>]*>").unwrap();
+
+    /// Character references used in cue text.
+    static ref ENTITY: Regex =
+        Regex::new(r"&(amp|lt|gt|nbsp|lrm|rlm);").unwrap();
+
+    /// SRT-style `<font color="...">` tags.
+    static ref FONT_COLOR: Regex =
+        Regex::new(r#"<font color="([a-z]+)">"#).unwrap();
+
+    /// SRT-style tags which we know how to write as WebVTT.
+    static ref KNOWN_TAG: Regex =
+        Regex::new(r"<(/?)(b|i|u|c|font)((?:[ .][^<>]*)?)>").unwrap();
+}
+
+/// Parse a WebVTT timestamp, which may or may not include hours.
+fn parse_time(time: &str) -> f32 {
+    let mut seconds = 0.0;
+    for component in time.split(':') {
+        seconds = seconds * 60.0 + f32::from_str(component)
+            .expect("timestamp should have been validated by regex");
+    }
+    seconds
+}
+
+/// Format seconds using the standard WebVTT time format.
+pub fn format_time(time: f32) -> String {
+    let (h, rem) = ((time / 3600.0).trunc(), time % 3600.0);
+    let (m, s) = ((rem / 60.0).trunc(), rem % 60.0);
+    format!("{:02}:{:02}:{:0>6.3}", h, m, s)
+}
+
+/// Convert a line of WebVTT cue text to the SRT-compatible markup we use
+/// internally.
+fn cue_text_to_line(text: &str) -> String {
+    let stripped = VTT_ONLY_TAG.replace_all(text, "");
+    ENTITY
+        .replace_all(&stripped, |caps: &Captures| {
+            match caps.get(1).unwrap().as_str() {
+                "amp" => "&",
+                "lt" => "<",
+                "gt" => ">",
+                "nbsp" => "\u{a0}",
+                "lrm" => "\u{200e}",
+                "rlm" => "\u{200f}",
+                _ => unreachable!("unknown entity"),
+            }.to_owned()
+        })
+        .into_owned()
+}
+
+/// Escape text so that it won't be mistaken for WebVTT markup.
+fn escape(text: &str) -> String {
+    text.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;")
+}
+
+/// Convert the lines of a subtitle to WebVTT cue text, mapping SRT colors
+/// onto WebVTT's default color classes and dropping other `<font>` tags.
+/// Tags only count as markup if they are opened and closed within the
+/// subtitle, so that text like `1 <b> 2` is escaped instead.
+fn lines_to_cue_text(lines: &[String]) -> String {
+    let text = lines.join("\n");
+
+    // Pair each closing tag with the nearest open tag of the same name.
+    let tags: Vec<Captures> = KNOWN_TAG.captures_iter(&text).collect();
+    let mut pairs: Vec<Option<usize>> = vec![None; tags.len()];
+    let mut open: Vec<usize> = vec![];
+    for (i, caps) in tags.iter().enumerate() {
+        if caps[1].is_empty() {
+            open.push(i);
+        } else if let Some(pos) = open.iter().rposition(|&j| tags[j][2] == caps[2]) {
+            let j = open.remove(pos);
+            pairs[i] = Some(j);
+            pairs[j] = Some(i);
+        }
+    }
+
+    let mut result = String::with_capacity(text.len());
+    let mut last = 0;
+    for (i, caps) in tags.iter().enumerate() {
+        let tag = caps.get(0).unwrap();
+        result.push_str(&escape(&text[last..tag.start()]));
+        last = tag.end();
+        match (pairs[i], &caps[2]) {
+            (Some(j), "font") => {
+                let opening = &tags[i.min(j)][0];
+                if let Some(color) = FONT_COLOR.captures(opening) {
+                    if i < j {
+                        result.push_str(&format!("<c.{}>", &color[1]));
+                    } else {
+                        result.push_str("</c>");
+                    }
+                }
+            }
+            (None, "font") => {}
+            (Some(_), _) => result.push_str(tag.as_str()),
+            (None, _) => result.push_str(&escape(tag.as_str())),
+        }
+    }
+    result.push_str(&escape(&text[last..]));
+    result
+}
+
+/// Parse a single cue block, returning `None` if it doesn't contain any
+/// text.
+fn parse_cue(block: &[&str], default_index: usize) -> Result<Option<Subtitle>> {
+    // The timing line may be preceded by an optional cue identifier.
+    let timing_pos = block
+        .iter()
+        .position(|l| l.contains("-->"))
+        .ok_or_else(|| format_err!("expected cue timings in {:?}", block))?;
+    if timing_pos > 1 {
+        return Err(format_err!("unexpected text before cue timings: {:?}", block));
+    }
+    let index = if timing_pos == 1 {
+        usize::from_str(block[0].trim()).unwrap_or(default_index)
+    } else {
+        default_index
+    };
+
+    let caps = TIMING
+        .captures(block[timing_pos])
+        .ok_or_else(|| format_err!("invalid cue timings: {:?}", block[timing_pos]))?;
+    let begin = parse_time(caps.get(1).unwrap().as_str());
+    let mut end = parse_time(caps.get(2).unwrap().as_str());
+    if let Some(settings) = caps.get(3) {
+        trace!("ignoring cue settings {:?}", settings.as_str());
+    }
+    if begin == end {
+        // Fix zero-length cues the same way we do in our SRT parser.
+        end += 0.001;
+    }
+    let period = Period::new(begin, end)?;
+
+    let lines: Vec<String> = block[timing_pos + 1..]
+        .iter()
+        .map(|l| cue_text_to_line(l))
+        .filter(|l| !l.trim().is_empty())
+        .collect();
+    if lines.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Subtitle {
+        index: index,
+        period: period,
+        lines: lines,
+    }))
+}
+
+/// Does `line` begin with `keyword`, followed by whitespace or nothing?
+fn starts_with_keyword(line: &str, keyword: &str) -> bool {
+    line.starts_with(keyword)
+        && line[keyword.len()..]
+            .chars()
+            .next()
+            .map_or(true, |c| c == ' ' || c == '\t')
+}
+
+/// Parse WebVTT subtitle text.
+pub fn parse(data: &str) -> Result<SubtitleFile> {
+    let data = data.trim_left_matches("\u{FEFF}").replace("\r\n", "\n");
+    if !starts_with_keyword(data.lines().next().unwrap_or(""), "WEBVTT") {
+        return Err(format_err!("WebVTT file does not start with \"WEBVTT\""));
+    }
+
+    // Split our input into blocks separated by blank lines.
+    let mut blocks: Vec<Vec<&str>> = vec![];
+    let mut block: Vec<&str> = vec![];
+    for line in data.split('\n') {
+        if line.trim().is_empty() {
+            if !block.is_empty() {
+                blocks.push(block);
+                block = vec![];
+            }
+        } else {
+            block.push(line);
+        }
+    }
+    if !block.is_empty() {
+        blocks.push(block);
+    }
+
+    // Skip the header block, and any blocks which aren't cues.
+    let mut subtitles = vec![];
+    for block in blocks.iter().skip(1) {
+        let first = block[0];
+        if starts_with_keyword(first, "NOTE") || starts_with_keyword(first, "STYLE")
+            || starts_with_keyword(first, "REGION")
+        {
+            continue;
+        }
+        let default_index = subtitles.len() + 1;
+        if let Some(sub) = parse_cue(block, default_index)? {
+            subtitles.push(sub);
+        }
+    }
+    Ok(SubtitleFile { subtitles: subtitles })
+}
+
+/// Return a WebVTT representation of a single subtitle.
+pub fn subtitle_to_string(sub: &Subtitle) -> String {
+    format!(
+        "{}\n{} --> {}\n{}\n",
+        sub.index,
+        format_time(sub.period.begin()),
+        format_time(sub.period.end()),
+        lines_to_cue_text(&sub.lines)
+    )
+}
+
+/// Return a WebVTT representation of a subtitle file.
+pub fn to_string(file: &SubtitleFile) -> String {
+    let subs: Vec<String> = file.subtitles.iter().map(subtitle_to_string).collect();
+    format!("WEBVTT\n\n{}", subs.join("\n"))
+}
+
+#[cfg(test)]
+mod test {
+    use srt::SubtitleFile;
+    use vtt::{parse, subtitle_to_string, to_string};
+    use time::Period;
+
+    #[test]
+    fn parse_cues_and_skip_other_blocks() {
+        let data = "\u{FEFF}WEBVTT - Sample file
+
+STYLE
+::cue { color: yellow }
+
+NOTE This is a comment
+which spans two lines.
+
+intro
+00:01.000 --> 00:04.000 align:start position:10%
+<v Roger Bingham>We are in New York City</v>
+
+01:02:03.500 --> 01:02:05.000
+<c.yellow>Une idée</c> &amp; <i>une autre</i>
+<00:00:01.000>Second line
+";
+        let vtt = parse(data).unwrap();
+        assert_eq!(2, vtt.subtitles.len());
+
+        let sub = &vtt.subtitles[0];
+        assert_eq!(1, sub.index);
+        assert_eq!(Period::new(1.0, 4.0).unwrap(), sub.period);
+        assert_eq!(vec!["We are in New York City".to_string()], sub.lines);
+
+        let sub2 = &vtt.subtitles[1];
+        assert_eq!(2, sub2.index);
+        assert_eq!(3723.5, sub2.period.begin());
+        assert_eq!(
+            vec![
+                "Une idée & <i>une autre</i>".to_string(),
+                "Second line".to_string(),
+            ],
+            sub2.lines
+        );
+    }
+
+    #[test]
+    fn require_header() {
+        assert!(parse("1\n00:01.000 --> 00:04.000\nText\n").is_err());
+        assert!(parse("WEBVTTX\n").is_err());
+        assert_eq!(0, parse("WEBVTT\n").unwrap().subtitles.len());
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = "WEBVTT
+
+16
+00:01:02.328 --> 00:01:04.664
+Line 1.1
+
+17
+00:01:12.839 --> 00:01:13.839
+<i>Line 2.1</i> &amp; more
+";
+        let vtt = parse(data).unwrap();
+        assert_eq!(16, vtt.subtitles[0].index);
+        assert_eq!(data, &to_string(&vtt));
+    }
+
+    #[test]
+    fn srt_formatting_to_vtt() {
+        let srt = SubtitleFile::from_str(
+            "1
+00:00:01,000 --> 00:00:02,500
+<font color=\"yellow\">¡Hola!</font>
+<i>Hello!</i>
+",
+        ).unwrap();
+        let expected = "1
+00:00:01.000 --> 00:00:02.500
+<c.yellow>¡Hola!</c>
+<i>Hello!</i>
+";
+        assert_eq!(expected, &subtitle_to_string(&srt.subtitles[0]));
+    }
+
+    #[test]
+    fn round_trip_escaped_text() {
+        let data = "WEBVTT
+
+1
+00:00:01.000 --> 00:00:02.000
+a &lt; b &amp;&amp; c &gt; d
+
+2
+00:00:03.000 --> 00:00:04.000
+1 &lt;b&gt; 2
+&lt;x&gt; &lt;/i&gt;
+";
+        let vtt = parse(data).unwrap();
+        assert_eq!(vec!["a < b && c > d".to_string()], vtt.subtitles[0].lines);
+        assert_eq!(
+            vec!["1 <b> 2".to_string(), "<x> </i>".to_string()],
+            vtt.subtitles[1].lines
+        );
+        assert_eq!(data, &to_string(&vtt));
+    }
+
+    #[test]
+    fn srt_tags_spanning_lines_to_vtt() {
+        let srt = SubtitleFile::from_str(
+            "1
+00:00:01,000 --> 00:00:02,500
+<i><font size=\"3\">¡Hola!
+Hello!</font></i> <s>x</s>
+",
+        ).unwrap();
+        let expected = "1
+00:00:01.000 --> 00:00:02.500
+<i>¡Hola!
+Hello!</i> &lt;s&gt;x&lt;/s&gt;
+";
+        assert_eq!(expected, &subtitle_to_string(&srt.subtitles[0]));
+    }
+}
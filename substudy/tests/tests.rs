@@ -4,6 +4,7 @@
 extern crate cli_test_dir;
 
 use cli_test_dir::TestDir;
+use std::fs;
 use std::str::from_utf8;
 
 #[test]
@@ -53,6 +54,21 @@ fn cmd_clean() {
     assert!(from_utf8(&output.stdout).unwrap().find("Yay!").is_some());
 }
 
+#[test]
+fn cmd_clean_vtt() {
+    let testdir = TestDir::new("substudy", "cmd_clean_vtt");
+    let output = testdir
+        .cmd()
+        .args(&["clean", "--format", "vtt"])
+        .arg(testdir.src_path("fixtures/sample.es.vtt"))
+        .output()
+        .expect("could not run substudy");
+    assert!(output.status.success());
+    let stdout = from_utf8(&output.stdout).unwrap();
+    assert!(stdout.starts_with("WEBVTT"));
+    assert!(stdout.find("00:01:02.328 --> 00:01:04.664").is_some());
+}
+
 #[test]
 fn cmd_combine() {
     let testdir = TestDir::new("substudy", "cmd_combine");
@@ -68,6 +84,39 @@ fn cmd_combine() {
     assert!(from_utf8(&output.stdout).unwrap().find("¡Si!").is_some());
 }
 
+#[test]
+fn cmd_combine_bundle() {
+    let testdir = TestDir::new("substudy", "cmd_combine_bundle");
+    let output = testdir
+        .cmd()
+        .arg("combine")
+        .arg(testdir.src_path(
+            "../aligned_media/fixtures/examples/subtitle_example.aligned",
+        ))
+        .output()
+        .expect("could not run substudy");
+    assert!(output.status.success());
+    let stdout = from_utf8(&output.stdout).unwrap();
+    assert!(stdout.find("On y va !").is_some());
+    assert!(stdout.find("Let's go!").is_some());
+}
+
+#[test]
+fn cmd_combine_ass() {
+    let testdir = TestDir::new("substudy", "cmd_combine_ass");
+    let output = testdir
+        .cmd()
+        .args(&["combine", "--format", "ass"])
+        .arg(testdir.src_path("fixtures/sample.es.srt"))
+        .arg(testdir.src_path("fixtures/sample.en.srt"))
+        .output()
+        .expect("could not run substudy");
+    assert!(output.status.success());
+    let stdout = from_utf8(&output.stdout).unwrap();
+    assert!(stdout.find("Style: Foreign,").is_some());
+    assert!(stdout.find(",Native,,0,0,0,,Yay! Yay!").is_some());
+}
+
 #[test]
 fn cmd_export_csv() {
     let testdir = TestDir::new("substudy", "cmd_export_csv");
@@ -81,10 +130,44 @@ fn cmd_export_csv() {
         .expect("could not run substudy");
     assert!(output.status.success());
     testdir.expect_path("empty_csv/cards.csv");
+    testdir.expect_does_not_contain("empty_csv/cards.csv", "context_sound");
     testdir.expect_path("empty_csv/empty_00063_496.jpg");
     testdir.expect_path("empty_csv/empty_00060_828-00066_164.es.mp3");
 }
 
+#[test]
+fn cmd_export_apkg() {
+    let testdir = TestDir::new("substudy", "cmd_export_apkg");
+    let output = testdir
+        .cmd()
+        .args(&["export", "apkg"])
+        .arg(testdir.src_path("fixtures/empty.mp4"))
+        .arg(testdir.src_path("fixtures/sample.es.srt"))
+        .arg(testdir.src_path("fixtures/sample.en.srt"))
+        .output()
+        .expect("could not run substudy");
+    assert!(output.status.success());
+    testdir.expect_path("empty_apkg/empty.apkg");
+    testdir.expect_path("empty_apkg/empty_00063_496.jpg");
+}
+
+#[test]
+fn cmd_export_aligned() {
+    let testdir = TestDir::new("substudy", "cmd_export_aligned");
+    let output = testdir
+        .cmd()
+        .args(&["export", "aligned"])
+        .arg(testdir.src_path("fixtures/empty.mp4"))
+        .arg(testdir.src_path("fixtures/sample.es.srt"))
+        .arg(testdir.src_path("fixtures/sample.en.srt"))
+        .output()
+        .expect("could not run substudy");
+    assert!(output.status.success());
+    testdir.expect_path("empty_aligned/metadata.json");
+    testdir.expect_path("empty_aligned/empty.mp4");
+    testdir.expect_path("empty_aligned/empty_00063_496.jpg");
+}
+
 #[test]
 fn cmd_export_review() {
     let testdir = TestDir::new("substudy", "cmd_export_review");
@@ -117,6 +200,59 @@ fn cmd_export_tracks() {
     testdir.expect_path("empty_tracks/empty_00059_828-00067_164.es.mp3");
 }
 
+#[test]
+fn cmd_export_requires_foreign_subs() {
+    let testdir = TestDir::new("substudy", "cmd_export_requires_foreign_subs");
+    let output = testdir
+        .cmd()
+        .args(&["export", "csv"])
+        .arg(testdir.src_path("fixtures/empty.mp4"))
+        .output()
+        .expect("could not run substudy");
+    assert!(!output.status.success());
+    let stderr = from_utf8(&output.stderr).unwrap();
+    assert!(stderr.contains("--foreign-lang"));
+}
+
+#[test]
+fn cmd_export_dictionary_requires_text_only() {
+    let testdir = TestDir::new("substudy", "cmd_export_dictionary_requires_text_only");
+    let output = testdir
+        .cmd()
+        .args(&["export", "review", "--dictionary"])
+        .arg(testdir.src_path("fixtures/sample.es.srt"))
+        .arg(testdir.src_path("fixtures/empty.mp4"))
+        .arg(testdir.src_path("fixtures/sample.es.srt"))
+        .arg(testdir.src_path("fixtures/sample.en.srt"))
+        .output()
+        .expect("could not run substudy");
+    assert!(!output.status.success());
+    let stderr = from_utf8(&output.stderr).unwrap();
+    assert!(stderr.contains("--dictionary requires --text-only"));
+}
+
+#[test]
+fn cmd_export_csv_dir() {
+    let testdir = TestDir::new("substudy", "cmd_export_csv_dir");
+    fs::create_dir_all(testdir.path("season")).unwrap();
+    let files = &[
+        ("fixtures/empty.mp4", "season/Show.S01E02.mp4"),
+        ("fixtures/sample.es.srt", "season/Show.S01E02.es.srt"),
+        ("fixtures/sample.en.srt", "season/Show.S01E02.en.srt"),
+    ];
+    for &(src, dest) in files {
+        fs::copy(testdir.src_path(src), testdir.path(dest)).unwrap();
+    }
+    let output = testdir
+        .cmd()
+        .args(&["export", "csv", "season", "--foreign-lang", "es", "--native-lang", "en"])
+        .output()
+        .expect("could not run substudy");
+    assert!(output.status.success());
+    testdir.expect_contains("season_csv/cards.csv", "01.02 00:01:00.828");
+    testdir.expect_path("season_csv/Show.S01E02_00063_496.jpg");
+}
+
 #[test]
 fn cmd_list_tracks() {
     let testdir = TestDir::new("substudy", "cmd_export_tracks");
@@ -134,3 +270,115 @@ fn cmd_list_tracks() {
             .is_some()
     );
 }
+
+#[test]
+fn cmd_retime() {
+    let testdir = TestDir::new("substudy", "cmd_retime");
+    let output = testdir
+        .cmd()
+        .arg("retime")
+        .arg("--offset=-1.5")
+        .arg(testdir.src_path("fixtures/sample.es.srt"))
+        .output()
+        .expect("could not run substudy");
+    assert!(output.status.success());
+    assert!(from_utf8(&output.stdout).unwrap().find("00:01:00,828").is_some());
+}
+
+#[test]
+fn cmd_retime_rejects_bad_scale() {
+    let testdir = TestDir::new("substudy", "cmd_retime_rejects_bad_scale");
+    for scale in &["0", "-2", "1/0", "0/0", "inf", "NaN"] {
+        let output = testdir
+            .cmd()
+            .arg("retime")
+            .arg(format!("--scale={}", scale))
+            .arg(testdir.src_path("fixtures/sample.es.srt"))
+            .output()
+            .expect("could not run substudy");
+        assert!(!output.status.success());
+        let stderr = from_utf8(&output.stderr).unwrap();
+        assert!(stderr.contains("expected a positive number"));
+    }
+}
+
+#[test]
+fn cmd_align_report() {
+    let testdir = TestDir::new("substudy", "cmd_align_report");
+    let output = testdir
+        .cmd()
+        .arg("align")
+        .arg("--report")
+        .arg(testdir.src_path("fixtures/sample.es.srt"))
+        .arg(testdir.src_path("fixtures/sample.en.srt"))
+        .output()
+        .expect("could not run substudy");
+    assert!(output.status.success());
+    let stdout = from_utf8(&output.stdout).unwrap();
+    assert!(stdout.find("unmatched").is_some());
+    assert!(stdout.find("groups have confidence below 0.5").is_some());
+}
+
+#[test]
+fn cmd_bundle_create_and_split() {
+    let testdir = TestDir::new("substudy", "cmd_bundle_create_and_split");
+    let output = testdir
+        .cmd()
+        .args(&["bundle", "create"])
+        .arg(testdir.src_path("fixtures/empty.mp4"))
+        .arg(testdir.src_path("fixtures/sample.es.srt"))
+        .arg(testdir.src_path("fixtures/sample.en.srt"))
+        .output()
+        .expect("could not run substudy");
+    assert!(output.status.success());
+    testdir.expect_path("empty.aligned/metadata.json");
+    testdir.expect_path("empty.aligned/empty.mp4");
+
+    let output = testdir
+        .cmd()
+        .args(&["bundle", "split", "--format", "vtt", "--out-dir", "subs"])
+        .arg("empty.aligned")
+        .output()
+        .expect("could not run substudy");
+    assert!(output.status.success());
+    testdir.expect_contains("subs/empty.es.vtt", "¡Si! ¡Aang ha vuelto!");
+    testdir.expect_contains("subs/empty.en.vtt", "Aang's back!");
+}
+
+#[test]
+fn cmd_bundle_create_with_undetectable_languages() {
+    let testdir = TestDir::new("substudy", "cmd_bundle_create_with_undetectable_languages");
+    let numbers = "1\n00:00:01,000 --> 00:00:02,000\n1, 2, 3\n";
+    fs::write(testdir.path("numbers.srt"), numbers).unwrap();
+    fs::write(testdir.path("numbers.fr.srt"), numbers).unwrap();
+
+    let output = testdir
+        .cmd()
+        .args(&["bundle", "create"])
+        .arg(testdir.src_path("fixtures/empty.mp4"))
+        .arg("numbers.srt")
+        .output()
+        .expect("could not run substudy");
+    assert!(!output.status.success());
+    let stderr = from_utf8(&output.stderr).unwrap();
+    assert!(stderr.contains("try --lang"));
+
+    let output = testdir
+        .cmd()
+        .args(&["bundle", "create", "--lang", "de"])
+        .arg(testdir.src_path("fixtures/empty.mp4"))
+        .args(&["numbers.srt", "numbers.fr.srt"])
+        .output()
+        .expect("could not run substudy");
+    assert!(output.status.success());
+
+    let output = testdir
+        .cmd()
+        .args(&["bundle", "split", "--out-dir", "subs"])
+        .arg("empty.aligned")
+        .output()
+        .expect("could not run substudy");
+    assert!(output.status.success());
+    testdir.expect_contains("subs/empty.de.srt", "1, 2, 3");
+    testdir.expect_contains("subs/empty.fr.srt", "1, 2, 3");
+}
@@ -0,0 +1,84 @@
+extern crate aligned_media;
+#[macro_use]
+extern crate common_failures;
+extern crate docopt;
+extern crate env_logger;
+#[macro_use]
+extern crate failure;
+#[macro_use]
+extern crate log;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+
+use aligned_media::validate::{has_errors, validate_bundle, Severity};
+use common_failures::display::DisplayCausesAndBacktraceExt;
+use common_failures::prelude::*;
+use docopt::Docopt;
+use std::path::Path;
+
+const USAGE: &'static str = "
+Validate aligned media bundles or metadata.json files.
+
+Usage: validate_aligned_media [options] <path>...
+       validate_aligned_media --help
+
+Options:
+  --duration=<seconds>  The duration of the base track, used to check that
+                        alignments don't extend past the end of the media.
+  -q, --quiet           Don't print warnings.
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    arg_path: Vec<String>,
+    flag_duration: Option<f32>,
+    flag_quiet: bool,
+}
+
+quick_main!(run);
+
+fn run() -> Result<()> {
+    env_logger::init().unwrap();
+
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    debug!("args: {:?}", &args);
+
+    let mut failed = 0;
+    for path in &args.arg_path {
+        if !check(Path::new(path), &args) {
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        Err(format_err!(
+            "{} of {} files failed validation",
+            failed,
+            args.arg_path.len()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Validate a single bundle, printing any problems we find. Returns `true`
+/// if the bundle is valid.
+fn check(path: &Path, args: &Args) -> bool {
+    match validate_bundle(path, args.flag_duration) {
+        Ok(diagnostics) => {
+            for diagnostic in &diagnostics {
+                if diagnostic.severity == Severity::Error || !args.flag_quiet {
+                    eprintln!("{}: {}", path.display(), diagnostic);
+                }
+            }
+            !has_errors(&diagnostics)
+        }
+        Err(err) => {
+            eprint!("{}", err.display_causes_and_backtrace());
+            false
+        }
+    }
+}
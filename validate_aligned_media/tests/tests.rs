@@ -0,0 +1,39 @@
+//! # Integration tests.
+//!
+//! These tests are run on our executable to make sure that all the
+//! command-line options work correctly.
+
+extern crate cli_test_dir;
+
+use cli_test_dir::*;
+
+#[test]
+fn accepts_valid_bundles() {
+    let workdir = TestDir::new("validate_aligned_media", "accepts_valid_bundles");
+    workdir.cmd()
+        .arg(workdir.src_path("../aligned_media/fixtures/examples/book_example.aligned"))
+        .arg(workdir.src_path(
+            "../aligned_media/fixtures/examples/book_example.aligned/metadata.json",
+        ))
+        .expect_success();
+}
+
+#[test]
+fn rejects_invalid_bundles() {
+    let workdir = TestDir::new("validate_aligned_media", "rejects_invalid_bundles");
+    workdir.create_file("bad/metadata.json", r#"{
+  "alignments": [
+    { "timeSpan": [1, 2], "tracks": [{ "type": "html", "html": "Oui." }] }
+  ]
+}"#);
+    let output = workdir.cmd()
+        .arg(workdir.src_path("../aligned_media/fixtures/examples/book_example.aligned"))
+        .arg("bad")
+        .arg("missing")
+        .expect_failure();
+    let stderr = output.stderr_str();
+    assert!(stderr.contains("$.baseTrack"));
+    assert!(stderr.contains("$.alignments[0].tracks[0].lang"));
+    assert!(stderr.contains("could not read"));
+    assert!(stderr.contains("2 of 3 files failed validation"));
+}
@@ -6,11 +6,11 @@ use std::io::Read;
 use std::path::Path;
 
 /// Internal helper function which looks for "magic" bytes at the start of
-/// a file.
+/// a file. Files which are too short to contain `magic` don't match.
 fn has_magic(path: &Path, magic: &[u8]) -> Result<bool> {
-    let mut f = fs::File::open(path).io_read_context(path)?;
-    let mut bytes = vec![0; magic.len()];
-    f.read_exact(&mut bytes).io_read_context(path)?;
+    let f = fs::File::open(path).io_read_context(path)?;
+    let mut bytes = vec![];
+    f.take(magic.len() as u64).read_to_end(&mut bytes).io_read_context(path)?;
     Ok(magic == &bytes[..])
 }
 